@@ -0,0 +1,589 @@
+//! Client for the silly-db server: pooled connections, pipelines, timeouts
+use silly_db::core::kv_store::KvStore;
+use silly_db::server::DEFAULT_SCAN_COUNT;
+use silly_db::server::resp::{self, Value};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::{Bound, RangeBounds};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::vec;
+
+pub use silly_db::core::batch::WriteOp;
+pub use silly_db::core::kv_store::Pair;
+pub use silly_db::error::KVError;
+
+// "host:port", or "unix:/path/to/db.sock"
+#[derive(Debug, Clone, PartialEq)]
+pub enum Addr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl From<&str> for Addr {
+    fn from(addr: &str) -> Self {
+        match addr.strip_prefix("unix:") {
+            Some(path) => Addr::Unix(PathBuf::from(path)),
+            None => Addr::Tcp(addr.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub connect_timeout: Duration,
+    // per read or write on a connection, None = wait forever
+    pub io_timeout: Option<Duration>,
+    // connections kept open between calls; more may be open at once
+    pub max_idle: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Duration::from_secs(5),
+            io_timeout: Some(Duration::from_secs(30)),
+            max_idle: 8,
+        }
+    }
+}
+
+// Cheap to clone, clones share the pool: one Client per server is enough
+// for all threads. Errors are the server's KVError; a timeout is
+// KVError::Io with kind TimedOut.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    addr: Addr,
+    opts: ClientOptions,
+    idle: Mutex<Vec<Conn>>,
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(s) => Stream::Tcp(s.try_clone()?),
+            Stream::Unix(s) => Stream::Unix(s.try_clone()?),
+        })
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+struct Conn {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+}
+
+impl Conn {
+    fn open(addr: &Addr, opts: &ClientOptions) -> Result<Conn, KVError> {
+        let stream = match addr {
+            Addr::Tcp(addr) => {
+                let stream = connect_tcp(addr, opts.connect_timeout)?;
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(opts.io_timeout)?;
+                stream.set_write_timeout(opts.io_timeout)?;
+                Stream::Tcp(stream)
+            }
+            Addr::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(opts.io_timeout)?;
+                stream.set_write_timeout(opts.io_timeout)?;
+                Stream::Unix(stream)
+            }
+        };
+        Ok(Conn {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    // all commands go out in one write, then the replies are read in order
+    fn round_trip(&mut self, cmds: &[Vec<Vec<u8>>]) -> Result<Vec<Value>, KVError> {
+        for args in cmds {
+            let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+            resp::write_command(&mut self.writer, &args)?;
+        }
+        self.writer.flush()?;
+
+        let mut replies = Vec::with_capacity(cmds.len());
+        for _ in cmds {
+            let reply = resp::read_value(&mut self.reader)?;
+            let reply = reply.ok_or_else(|| KVError::Protocol("server closed the connection".into()))?;
+            replies.push(reply);
+        }
+        Ok(replies)
+    }
+}
+
+fn connect_tcp(addr: &str, timeout: Duration) -> Result<TcpStream, KVError> {
+    let mut last = io::Error::new(io::ErrorKind::InvalidInput, format!("no address for {addr}"));
+    for sock in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last.into())
+}
+
+// a read timeout shows up as WouldBlock on some platforms
+fn timed_out(e: KVError) -> KVError {
+    match e {
+        KVError::Io(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            KVError::Io(io::Error::new(io::ErrorKind::TimedOut, e))
+        }
+        e => e,
+    }
+}
+
+// an error reply is the server's KVError, anything else is a value
+fn into_result(reply: Value) -> Result<Value, KVError> {
+    match reply {
+        Value::Error(msg) => Err(resp::decode_error(&msg)),
+        value => Ok(value),
+    }
+}
+
+fn unexpected(reply: &Value) -> KVError {
+    KVError::Protocol(format!("unexpected reply {reply:?}"))
+}
+
+impl Client {
+    // opens the first connection right away, so a wrong address fails here
+    pub fn connect(addr: impl Into<Addr>) -> Result<Client, KVError> {
+        Self::connect_with(addr, ClientOptions::default())
+    }
+
+    pub fn connect_with(addr: impl Into<Addr>, opts: ClientOptions) -> Result<Client, KVError> {
+        let addr = addr.into();
+        let conn = Conn::open(&addr, &opts)?;
+        let client = Client {
+            inner: Arc::new(Inner { addr, opts, idle: Mutex::new(Vec::new()) }),
+        };
+        client.checkin(conn);
+        Ok(client)
+    }
+
+    fn checkout(&self) -> Result<Conn, KVError> {
+        let idle = self.inner.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        match idle {
+            Some(conn) => Ok(conn),
+            None => Conn::open(&self.inner.addr, &self.inner.opts),
+        }
+    }
+
+    fn checkin(&self, conn: Conn) {
+        let mut idle = self.inner.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.inner.opts.max_idle {
+            idle.push(conn);
+        }
+    }
+
+    // A connection that failed mid-way is out of step with the server
+    // and is dropped; error replies leave it usable.
+    fn send(&self, cmds: &[Vec<Vec<u8>>]) -> Result<Vec<Value>, KVError> {
+        let mut conn = self.checkout()?;
+        let replies = conn.round_trip(cmds).map_err(timed_out)?;
+        self.checkin(conn);
+        Ok(replies)
+    }
+
+    fn call(&self, args: &[&[u8]]) -> Result<Value, KVError> {
+        let cmd = args.iter().map(|arg| arg.to_vec()).collect();
+        let reply = self.send(&[cmd])?.pop().expect("one reply per command");
+        into_result(reply)
+    }
+
+    pub fn ping(&self) -> Result<(), KVError> {
+        match self.call(&[b"PING"])? {
+            Value::Simple(_) => Ok(()),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        match self.call(&[b"GET", key])? {
+            Value::Bulk(val) => Ok(Some(val)),
+            Value::Nil => Ok(None),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    // true if the key existed
+    pub fn set(&self, key: &[u8], val: &[u8]) -> Result<bool, KVError> {
+        match self.call(&[b"SET", key, val])? {
+            Value::Int(n) => Ok(n > 0),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    // true if the key existed
+    pub fn del(&self, key: &[u8]) -> Result<bool, KVError> {
+        match self.call(&[b"DEL", key])? {
+            Value::Int(n) => Ok(n > 0),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    // up to `count` pairs with start <= key < end, in key order
+    pub fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        count: usize,
+    ) -> Result<Vec<Pair>, KVError> {
+        let count = count.to_string();
+        let reply = self.call(&[b"SCAN", start, end.unwrap_or_default(), count.as_bytes()])?;
+        let Value::Array(items) = reply else { return Err(unexpected(&reply)) };
+
+        let mut pairs = Vec::with_capacity(items.len() / 2);
+        let mut items = items.into_iter();
+        while let Some(key) = items.next() {
+            match (key, items.next()) {
+                (Value::Bulk(key), Some(Value::Bulk(val))) => pairs.push((key, val)),
+                (key, _) => return Err(unexpected(&key)),
+            }
+        }
+        Ok(pairs)
+    }
+
+    // MULTI, the ops and EXEC in one round trip: all of them or none
+    pub fn apply(&self, ops: &[WriteOp]) -> Result<(), KVError> {
+        let mut cmds = vec![vec![b"MULTI".to_vec()]];
+        for op in ops {
+            cmds.push(match op {
+                WriteOp::Set(key, val) => vec![b"SET".to_vec(), key.clone(), val.clone()],
+                WriteOp::Del(key) => vec![b"DEL".to_vec(), key.clone()],
+            });
+        }
+        cmds.push(vec![b"EXEC".to_vec()]);
+
+        let reply = self.send(&cmds)?.pop().expect("one reply per command");
+        match into_result(reply)? {
+            Value::Array(_) => Ok(()),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline { client: self, cmds: Vec::new() }
+    }
+}
+
+// Commands sent in one write on one connection, replies read after. Not a
+// transaction: other clients' commands may run in between.
+pub struct Pipeline<'a> {
+    client: &'a Client,
+    cmds: Vec<Vec<Vec<u8>>>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: &[u8]) -> &mut Self {
+        self.cmds.push(vec![b"GET".to_vec(), key.to_vec()]);
+        self
+    }
+
+    pub fn set(&mut self, key: &[u8], val: &[u8]) -> &mut Self {
+        self.cmds.push(vec![b"SET".to_vec(), key.to_vec(), val.to_vec()]);
+        self
+    }
+
+    pub fn del(&mut self, key: &[u8]) -> &mut Self {
+        self.cmds.push(vec![b"DEL".to_vec(), key.to_vec()]);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    // One result per command, in order. The outer error is the connection
+    // failing; then it is unknown which commands ran.
+    pub fn exec(&mut self) -> Result<Vec<Result<Value, KVError>>, KVError> {
+        if self.cmds.is_empty() {
+            return Ok(Vec::new());
+        }
+        let cmds = std::mem::take(&mut self.cmds);
+        let replies = self.client.send(&cmds)?;
+        Ok(replies.into_iter().map(into_result).collect())
+    }
+}
+
+impl KvStore for Client {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        Client::get(self, key)
+    }
+
+    fn set(&mut self, key: &[u8], val: &[u8]) -> Result<bool, KVError> {
+        Client::set(self, key, val)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<bool, KVError> {
+        Client::del(self, key)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> impl Iterator<Item = Result<Pair, KVError>> + '_ {
+        ClientScan::new(self, range)
+    }
+
+    fn apply(&mut self, ops: &[WriteOp]) -> Result<(), KVError> {
+        Client::apply(self, ops)
+    }
+}
+
+// SCAN a page at a time. Pages are separate commands: writes from other
+// clients in between may or may not show up.
+struct ClientScan<'a> {
+    client: &'a Client,
+    // next page starts here
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    page: vec::IntoIter<Pair>,
+    done: bool,
+}
+
+impl<'a> ClientScan<'a> {
+    fn new(client: &'a Client, range: impl RangeBounds<Vec<u8>>) -> Self {
+        // SCAN takes start <= key < end: the next key up after an excluded
+        // start, or an included end, is the key plus a zero byte
+        let after = |key: &Vec<u8>| [key.as_slice(), &[0]].concat();
+        let start = match range.start_bound() {
+            Bound::Included(key) => key.clone(),
+            Bound::Excluded(key) => after(key),
+            Bound::Unbounded => Vec::new(),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Some(after(key)),
+            Bound::Excluded(key) => Some(key.clone()),
+            Bound::Unbounded => None,
+        };
+        // an empty end means no end to the server
+        let done = end.as_ref().is_some_and(|end| end.is_empty() || *end <= start);
+        ClientScan { client, start, end, page: Vec::new().into_iter(), done }
+    }
+}
+
+impl Iterator for ClientScan<'_> {
+    type Item = Result<Pair, KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pair) = self.page.next() {
+            return Some(Ok(pair));
+        }
+        if self.done {
+            return None;
+        }
+
+        let page = match self.client.scan(&self.start, self.end.as_deref(), DEFAULT_SCAN_COUNT) {
+            Ok(page) => page,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        match page.last() {
+            Some((key, _)) if page.len() == DEFAULT_SCAN_COUNT => self.start = [key.as_slice(), &[0]].concat(),
+            _ => self.done = true,
+        }
+        self.page = page.into_iter();
+        self.page.next().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use silly_db::core::key_value::KV;
+    use silly_db::server::Server;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    fn start() -> String {
+        let server = Server::new(KV::open_in_memory().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || server.serve_tcp(listener));
+        addr
+    }
+
+    fn idle(client: &Client) -> usize {
+        client.inner.idle.lock().unwrap().len()
+    }
+
+    #[test]
+    fn get_set_del_scan() {
+        let client = Client::connect(start().as_str()).unwrap();
+
+        client.ping().unwrap();
+        assert!(!client.set(b"a", b"1").unwrap());
+        assert!(client.set(b"a", b"2").unwrap());
+        client.set(b"b", b"3").unwrap();
+        assert_eq!(client.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert!(client.del(b"a").unwrap());
+        assert!(!client.del(b"a").unwrap());
+        assert_eq!(client.get(b"a").unwrap(), None);
+        assert_eq!(client.scan(b"", None, 10).unwrap(), [(b"b".to_vec(), b"3".to_vec())]);
+    }
+
+    #[test]
+    fn server_errors_are_typed() {
+        let client = Client::connect(start().as_str()).unwrap();
+
+        let err = client.set(&vec![0; 100 << 10], b"v").unwrap_err();
+        assert!(matches!(err, KVError::KeyTooLarge { len, .. } if len == 100 << 10));
+        // the connection is still fine after an error reply
+        assert_eq!(idle(&client), 1);
+        client.ping().unwrap();
+    }
+
+    #[test]
+    fn pipeline_in_one_round_trip() {
+        let client = Client::connect(start().as_str()).unwrap();
+
+        let mut pipe = client.pipeline();
+        for i in 0..100u8 {
+            pipe.set(&[i], &[i]);
+        }
+        pipe.get(&[7]).del(&[7]).get(&[7]).set(&vec![0; 100 << 10], b"v");
+        let replies = pipe.exec().unwrap();
+
+        assert_eq!(replies.len(), 104);
+        assert_eq!(replies[100].as_ref().unwrap(), &Value::Bulk(vec![7]));
+        assert_eq!(replies[101].as_ref().unwrap(), &Value::Int(1));
+        assert_eq!(replies[102].as_ref().unwrap(), &Value::Nil);
+        assert!(matches!(replies[103], Err(KVError::KeyTooLarge { .. })));
+        assert!(pipe.is_empty());
+    }
+
+    #[test]
+    fn pool_is_shared_by_threads() {
+        let opts = ClientOptions { max_idle: 2, ..ClientOptions::default() };
+        let client = Client::connect_with(start().as_str(), opts).unwrap();
+
+        let threads: Vec<_> = (0..8u8)
+            .map(|i| {
+                let client = client.clone();
+                std::thread::spawn(move || {
+                    for j in 0..20u8 {
+                        client.set(&[i, j], &[j]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(client.scan(b"", None, 1000).unwrap().len(), 160);
+        assert!(idle(&client) <= 2);
+    }
+
+    #[test]
+    fn slow_server_times_out() {
+        // accepts, never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_secs(2));
+        });
+
+        let opts = ClientOptions { io_timeout: Some(Duration::from_millis(50)), ..ClientOptions::default() };
+        let client = Client::connect_with(addr.as_str(), opts).unwrap();
+        let err = client.get(b"k").unwrap_err();
+        assert!(matches!(err, KVError::Io(e) if e.kind() == io::ErrorKind::TimedOut));
+        // the half-used connection is not put back
+        assert_eq!(idle(&client), 0);
+    }
+
+    #[test]
+    fn apply_and_scan_through_the_trait() {
+        let mut client = Client::connect(start().as_str()).unwrap();
+
+        let ops: Vec<WriteOp> = (0..250u8).map(|i| WriteOp::Set(vec![i], vec![i])).collect();
+        KvStore::apply(&mut client, &ops).unwrap();
+        let mut txn = client.begin();
+        txn.del(&[0]).unwrap();
+        txn.set(&[250], &[250]).unwrap();
+        txn.commit().unwrap();
+
+        // more than two pages
+        let all: Vec<Pair> = KvStore::scan(&client, ..).map(Result::unwrap).collect();
+        assert_eq!(all.len(), 250);
+        assert_eq!(all[0], (vec![1], vec![1]));
+        assert_eq!(all[249], (vec![250], vec![250]));
+        let keys = |range: (Bound<Vec<u8>>, Bound<Vec<u8>>)| -> Vec<Vec<u8>> {
+            KvStore::scan(&client, range).map(|pair| pair.unwrap().0).collect()
+        };
+        assert_eq!(keys((Bound::Excluded(vec![5]), Bound::Included(vec![7]))), [vec![6], vec![7]]);
+        assert!(keys((Bound::Included(vec![7]), Bound::Excluded(vec![7]))).is_empty());
+
+        let too_big = [WriteOp::Set(vec![1], vec![2]), WriteOp::Set(vec![0; 100 << 10], vec![])];
+        assert!(matches!(client.apply(&too_big), Err(KVError::KeyTooLarge { .. })));
+        assert_eq!(client.get(&[1]).unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn same_code_embedded_and_remote() {
+        fn bump(store: &mut impl KvStore, key: &[u8]) -> Result<u64, KVError> {
+            let n = match store.get(key)? {
+                Some(val) => u64::from_le_bytes(val.try_into().unwrap()) + 1,
+                None => 1,
+            };
+            store.set(key, &n.to_le_bytes())?;
+            Ok(n)
+        }
+
+        let mut kv = KV::open_in_memory().unwrap();
+        assert_eq!(bump(&mut kv, b"n").unwrap(), 1);
+        assert_eq!(bump(&mut kv, b"n").unwrap(), 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::new(KV::open_in_memory().unwrap());
+        let sock = dir.path().join("db.sock");
+        let listener = UnixListener::bind(&sock).unwrap();
+        std::thread::spawn(move || server.serve_unix(listener));
+
+        let mut client = Client::connect(format!("unix:{}", sock.display()).as_str()).unwrap();
+        assert_eq!(bump(&mut client, b"n").unwrap(), 1);
+        assert_eq!(bump(&mut client, b"n").unwrap(), 2);
+    }
+}
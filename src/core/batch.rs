@@ -0,0 +1,132 @@
+//! Batches: several writes in one log record, applied all or nothing
+use crate::error::KVError;
+
+const OP_SET: u8 = 0;
+const OP_DEL: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    Set(Vec<u8>, Vec<u8>),
+    Del(Vec<u8>),
+}
+
+impl WriteOp {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WriteOp::Set(key, _) | WriteOp::Del(key) => key,
+        }
+    }
+}
+
+// Value of a Batch record: one CRC for all of it, so a torn batch
+// is dropped whole.
+// | count | (op | key_len | val_len | key | val)* |
+// | 4     | 1    4         4         ...          |
+pub fn encode_batch(ops: &[WriteOp]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+    for op in ops {
+        let (tag, key, val) = match op {
+            WriteOp::Set(key, val) => (OP_SET, key, val.as_slice()),
+            WriteOp::Del(key) => (OP_DEL, key, &[][..]),
+        };
+        out.push(tag);
+        out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(val.len() as u32).to_le_bytes());
+        out.extend_from_slice(key);
+        out.extend_from_slice(val);
+    }
+    out
+}
+
+pub fn decode_batch(data: &[u8]) -> Result<Vec<WriteOp>, KVError> {
+    BatchOps::new(data)?
+        .map(|op| {
+            op.map(|(tag, key, val)| match tag {
+                OP_SET => WriteOp::Set(key.to_vec(), val.to_vec()),
+                _ => WriteOp::Del(key.to_vec()),
+            })
+        })
+        .collect()
+}
+
+// value of the op at `index`, copying only that one
+pub fn batch_value(data: &[u8], index: u32) -> Result<Vec<u8>, KVError> {
+    match BatchOps::new(data)?.nth(index as usize) {
+        Some(Ok((OP_SET, _, val))) => Ok(val.to_vec()),
+        Some(Err(e)) => Err(e),
+        _ => Err(KVError::corruption("no value at batch index")),
+    }
+}
+
+// (tag, key, val) borrowed from the record
+struct BatchOps<'a> {
+    data: &'a [u8],
+    left: u32,
+}
+
+impl<'a> BatchOps<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, KVError> {
+        if data.len() < 4 {
+            return Err(KVError::corruption("bad batch"));
+        }
+        let left = u32::from_le_bytes(data[..4].try_into().unwrap());
+        Ok(BatchOps { data: &data[4..], left })
+    }
+}
+
+impl<'a> Iterator for BatchOps<'a> {
+    type Item = Result<(u8, &'a [u8], &'a [u8]), KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+
+        let bad = || Some(Err(KVError::corruption("bad batch")));
+        let Some(head) = self.data.get(..9) else { return bad() };
+        let tag = head[0];
+        let key_len = u32::from_le_bytes(head[1..5].try_into().unwrap()) as usize;
+        let val_len = u32::from_le_bytes(head[5..9].try_into().unwrap()) as usize;
+        if tag > OP_DEL || (tag == OP_DEL && val_len != 0) {
+            return bad();
+        }
+        let Some(body) = self.data.get(9..9 + key_len + val_len) else { return bad() };
+        self.data = &self.data[9 + key_len + val_len..];
+        Some(Ok((tag, &body[..key_len], &body[key_len..])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_round_trip() {
+        let ops = vec![
+            WriteOp::Set(b"a".to_vec(), b"1".to_vec()),
+            WriteOp::Del(b"b".to_vec()),
+            WriteOp::Set(b"c".to_vec(), Vec::new()),
+        ];
+        let data = encode_batch(&ops);
+
+        assert_eq!(decode_batch(&data).unwrap(), ops);
+        assert_eq!(batch_value(&data, 0).unwrap(), b"1");
+        assert_eq!(batch_value(&data, 2).unwrap(), b"");
+        assert!(batch_value(&data, 1).is_err());
+        assert!(batch_value(&data, 3).is_err());
+    }
+
+    #[test]
+    fn damaged_batch_is_corruption() {
+        let data = encode_batch(&[WriteOp::Set(b"key".to_vec(), b"value".to_vec())]);
+
+        for bad in [&data[..2], &data[..data.len() - 1]] {
+            assert!(matches!(decode_batch(bad), Err(KVError::Corruption(_))));
+        }
+        let mut bad_tag = data.clone();
+        bad_tag[4] = 9;
+        assert!(decode_batch(&bad_tag).is_err());
+    }
+}
@@ -1,32 +1,172 @@
 //! Binary Serialization
-use std::io::{self, Read, Write};
+use crate::core::crypto::{Cipher, SEAL_OVERHEAD};
+use crate::core::file_header::VERSION;
+use crate::error::KVError;
+use std::io::{Read, Write};
 use crc32fast::Hasher;
+
+pub const DEFAULT_MAX_KEY_SIZE: usize = 64 << 10;
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 64 << 20;
+
+// Checked on write, and on decode before anything is allocated from a
+// length in the header. Lowering them below what is already in the log
+// makes those records look corrupt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check(&self, key: &[u8], val: &[u8]) -> Result<(), KVError> {
+        if key.len() > self.max_key_size {
+            return Err(KVError::KeyTooLarge { len: key.len(), max: self.max_key_size });
+        }
+        if val.len() > self.max_value_size {
+            return Err(KVError::ValueTooLarge { len: val.len(), max: self.max_value_size });
+        }
+        Ok(())
+    }
+}
+
+// the byte after the lengths; 0 and 1 are the old `deleted` flag,
+// the two high bits are COMPRESSED_FLAG and ENCRYPTED_FLAG
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    Value = 0,
+    Tombstone = 1,
+    // one piece of a blob, only reachable through its BlobRef
+    BlobChunk = 2,
+    // value is a blob manifest, written after all chunks
+    BlobRef = 3,
+    // value is several writes applied together, see core::batch
+    Batch = 4,
+}
+
+// value on disk is lz4: | raw_len(4) | lz4 block |
+const COMPRESSED_FLAG: u8 = 0x80;
+// key_len is 0 and the value is seal(key_len(4) | key | value),
+// with the kind byte and the record offset as associated data
+const ENCRYPTED_FLAG: u8 = 0x40;
+const FLAGS: u8 = COMPRESSED_FLAG | ENCRYPTED_FLAG;
+
+// encryption of one record; the offset goes into the tag, so a record
+// copied to another place in the log does not verify
+#[derive(Clone, Copy)]
+pub struct Seal<'a> {
+    pub cipher: &'a Cipher,
+    pub offset: u64,
+}
+
+impl Seal<'_> {
+    // | kind | offset | lsn | ts |, the stamp only from version 3 on
+    fn aad(&self, kind: u8, stamp: Option<Stamp>) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(25);
+        aad.push(kind);
+        aad.extend_from_slice(&self.offset.to_le_bytes());
+        if let Some(stamp) = stamp {
+            aad.extend_from_slice(&stamp.lsn.to_le_bytes());
+            aad.extend_from_slice(&stamp.ts.to_le_bytes());
+        }
+        aad
+    }
+}
+
+// order and time of a record, given by Log::append:
+// lsn grows by one per record, ts is microseconds since the epoch
+// and never goes back even if the clock does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Stamp {
+    pub lsn: u64,
+    pub ts: u64,
+}
+
+impl EntryKind {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(EntryKind::Value),
+            1 => Some(EntryKind::Tombstone),
+            2 => Some(EntryKind::BlobChunk),
+            3 => Some(EntryKind::BlobRef),
+            4 => Some(EntryKind::Batch),
+            _ => None,
+        }
+    }
+}
+
+// what the u32 length fields hold; a sealed value holds the key too
+const MAX_RECORD_DATA: usize = u32::MAX as usize - 4 - SEAL_OVERHEAD;
+
+fn check_lengths(key_len: usize, val_len: usize) -> Result<(), KVError> {
+    if key_len > MAX_RECORD_DATA {
+        return Err(KVError::KeyTooLarge { len: key_len, max: MAX_RECORD_DATA });
+    }
+    if key_len + val_len > MAX_RECORD_DATA {
+        return Err(KVError::ValueTooLarge { len: val_len, max: MAX_RECORD_DATA - key_len });
+    }
+    Ok(())
+}
+
 pub struct Entry {
     key: Vec<u8>,
     val: Vec<u8>,
-    deleted: bool,
+    kind: EntryKind,
+    stamp: Stamp,
 }
 
-
 impl Entry {
     pub fn new(key: Vec<u8>, val: Vec<u8>) -> Self {
-        Entry {
-            key,
-            val,
-            deleted: false,
-        }
+        Self::with_kind(key, val, EntryKind::Value)
     }
 
     pub fn tombstone(key: Vec<u8>) -> Self {
+        Self::with_kind(key, Vec::new(), EntryKind::Tombstone)
+    }
+
+    pub fn with_kind(key: Vec<u8>, val: Vec<u8>, kind: EntryKind) -> Self {
         Entry {
             key,
-            val: Vec::new(),
-            deleted: true,
+            val,
+            kind,
+            stamp: Stamp::default(),
         }
     }
 
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn stamp(&self) -> Stamp {
+        self.stamp
+    }
+
+    pub fn lsn(&self) -> u64 {
+        self.stamp.lsn
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.stamp.ts
+    }
+
+    pub fn set_stamp(&mut self, stamp: Stamp) {
+        self.stamp = stamp;
+    }
+
+    pub fn into_parts(self) -> (Vec<u8>, Vec<u8>) {
+        (self.key, self.val)
+    }
+
     pub fn is_deleted(&self) -> bool {
-        self.deleted
+        self.kind == EntryKind::Tombstone
     }
 
     pub fn key(&self) -> &[u8] {
@@ -64,14 +204,60 @@ impl Entry {
     }
 
     // writer data like file, WAL ...
-    pub fn encode_into<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    pub fn encode_into<W: Write>(&self, w: &mut W) -> Result<(), KVError> {
+        self.encode_into_with(w, None, None)
+    }
+
+    // values of at least `compress_above` bytes are stored compressed
+    // when that makes them smaller, then sealed if `seal` is given;
+    // the crc covers the bytes as they are on disk
+    pub fn encode_into_with<W: Write>(
+        &self,
+        w: &mut W,
+        compress_above: Option<usize>,
+        seal: Option<Seal>,
+    ) -> Result<(), KVError> {
+        check_lengths(self.key.len(), self.val.len())?;
+
+        let mut kind = self.kind as u8;
+        let mut val = self.val.as_slice();
+
+        let compressed;
+        if let Some(threshold) = compress_above
+            && self.val.len() >= threshold
+        {
+            compressed = lz4_flex::block::compress_prepend_size(&self.val);
+            if compressed.len() < self.val.len() {
+                kind |= COMPRESSED_FLAG;
+                val = &compressed;
+            }
+        }
+
+        let mut key = self.key.as_slice();
+
+        let sealed;
+        if let Some(seal) = seal {
+            kind |= ENCRYPTED_FLAG;
+
+            let mut plain = Vec::with_capacity(4 + key.len() + val.len());
+            plain.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            plain.extend_from_slice(key);
+            plain.extend_from_slice(val);
+
+            sealed = seal.cipher.seal(&seal.aad(kind, Some(self.stamp)), &plain)?;
+            key = &[];
+            val = &sealed;
+        }
+
         let mut payload = Vec::new();
 
-        payload.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
-        payload.extend_from_slice(&(self.val.len() as u32).to_le_bytes());
-        payload.push(self.deleted as u8);
-        payload.extend_from_slice(&self.key);
-        payload.extend_from_slice(&self.val);
+        payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&(val.len() as u32).to_le_bytes());
+        payload.push(kind);
+        payload.extend_from_slice(&self.stamp.lsn.to_le_bytes());
+        payload.extend_from_slice(&self.stamp.ts.to_le_bytes());
+        payload.extend_from_slice(key);
+        payload.extend_from_slice(val);
 
         let mut hasher = Hasher::new();
         hasher.update(&payload);
@@ -84,19 +270,59 @@ impl Entry {
     }
 
     // deserialization
-    pub fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
-        use std::io::{Error, ErrorKind};
+    pub fn decode<R: Read>(r: &mut R) -> Result<Self, KVError> {
+        Self::decode_with(r, &Limits::default())
+    }
+
+    // torn input is Io(UnexpectedEof), damaged input is Corruption
+    pub fn decode_with<R: Read>(r: &mut R, limits: &Limits) -> Result<Self, KVError> {
+        Self::decode_sealed(r, limits, None)
+    }
+
+    // with `seal`, every record must be encrypted: a plaintext record with
+    // a good crc in an encrypted log is Tampered, like a bad tag
+    pub fn decode_sealed<R: Read>(
+        r: &mut R,
+        limits: &Limits,
+        seal: Option<Seal>,
+    ) -> Result<Self, KVError> {
+        Self::decode_version(r, limits, seal, VERSION)
+    }
 
+    // record layout of an older log version, for upgrades:
+    // 1 and 2 have no lsn and ts, their records get a zero stamp
+    pub fn decode_version<R: Read>(
+        r: &mut R,
+        limits: &Limits,
+        seal: Option<Seal>,
+        version: u16,
+    ) -> Result<Self, KVError> {
         let mut crc_buf = [0u8; 4];
         r.read_exact(&mut crc_buf)?;
         let expected_crc = u32::from_le_bytes(crc_buf);
 
-        let mut header = [0u8; 9]; // key_len(4) + val_len(4) + deleted(1)
-        r.read_exact(&mut header)?;
+        // key_len(4) + val_len(4) + kind(1) + lsn(8) + ts(8)
+        let mut header = [0u8; 25];
+        let header = if version >= 3 { &mut header[..] } else { &mut header[..9] };
+        r.read_exact(header)?;
 
         let key_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
         let val_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
-        let deleted = header[8] != 0;
+        let kind = header[8];
+
+        // sealed values carry the key and the encryption overhead
+        let max_val_len = if kind & ENCRYPTED_FLAG != 0 {
+            limits.max_value_size + limits.max_key_size + 4 + SEAL_OVERHEAD
+        } else {
+            limits.max_value_size
+        };
+
+        // a damaged header must not turn into a huge allocation
+        if key_len > limits.max_key_size || val_len > max_val_len {
+            return Err(KVError::corruption(format!(
+                "record lengths {key_len}/{val_len} over limits"
+            )));
+        }
 
         let mut key = vec![0u8; key_len];
         let mut val = vec![0u8; val_len];
@@ -105,17 +331,89 @@ impl Entry {
         r.read_exact(&mut val)?;
 
         let mut hasher = Hasher::new();
-        hasher.update(&header);
+        hasher.update(header);
         hasher.update(&key);
         hasher.update(&val);
         let actual_crc = hasher.finalize();
 
         if actual_crc != expected_crc {
-            return Err(Error::new(ErrorKind::InvalidData, "bad checksum"));
+            return Err(KVError::corruption("bad checksum"));
         }
 
-        Ok(Entry { key, val, deleted })
+        let flags = kind & FLAGS;
+        let compressed = flags & COMPRESSED_FLAG != 0;
+        let encrypted = flags & ENCRYPTED_FLAG != 0;
+        let base = EntryKind::from_byte(kind & !FLAGS)
+            .ok_or_else(|| KVError::corruption(format!("unknown record kind {kind}")))?;
+
+        let stamp = (version >= 3).then(|| Stamp {
+            lsn: u64::from_le_bytes(header[9..17].try_into().unwrap()),
+            ts: u64::from_le_bytes(header[17..25].try_into().unwrap()),
+        });
+
+        match (seal, encrypted) {
+            (Some(seal), true) => {
+                (key, val) = unseal(&val, seal.cipher, &seal.aad(kind, stamp), limits)?
+            }
+            (Some(_), false) => return Err(KVError::Tampered("plaintext record".into())),
+            (None, true) => return Err(KVError::KeyMismatch("record is encrypted".into())),
+            (None, false) => {}
+        }
+        let kind = base;
+
+        if compressed {
+            val = decompress(&val, limits)?;
+        }
+
+        Ok(Entry {
+            key,
+            val,
+            kind,
+            stamp: stamp.unwrap_or_default(),
+        })
+    }
+}
+
+// -> (key, value as stored: maybe compressed)
+fn unseal(
+    sealed: &[u8],
+    cipher: &Cipher,
+    aad: &[u8],
+    limits: &Limits,
+) -> Result<(Vec<u8>, Vec<u8>), KVError> {
+    let plain = cipher.open(aad, sealed)?;
+
+    // authenticated, so a bad length here is our bug or a reused key
+    let Some((key_len, rest)) = plain.split_first_chunk::<4>() else {
+        return Err(KVError::corruption("sealed record too short"));
+    };
+    let key_len = u32::from_le_bytes(*key_len) as usize;
+    if key_len > limits.max_key_size || key_len > rest.len() {
+        return Err(KVError::corruption("bad key length in sealed record"));
     }
+
+    let (key, val) = rest.split_at(key_len);
+    Ok((key.to_vec(), val.to_vec()))
+}
+
+// the raw length is checked before the output buffer is allocated
+fn decompress(data: &[u8], limits: &Limits) -> Result<Vec<u8>, KVError> {
+    let Some((raw_len, block)) = data.split_first_chunk::<4>() else {
+        return Err(KVError::corruption("compressed value too short"));
+    };
+    let raw_len = u32::from_le_bytes(*raw_len) as usize;
+    if raw_len > limits.max_value_size {
+        return Err(KVError::corruption(format!(
+            "decompressed length {raw_len} over limit"
+        )));
+    }
+
+    let val = lz4_flex::block::decompress(block, raw_len)
+        .map_err(|e| KVError::corruption(format!("bad compressed value: {e}")))?;
+    if val.len() != raw_len {
+        return Err(KVError::corruption("decompressed length mismatch"));
+    }
+    Ok(val)
 }
 
 #[cfg(test)]
@@ -127,12 +425,46 @@ mod tests {
         let ent = Entry {
             key: b"a".to_vec(),
             val: b"bb".to_vec(),
-            deleted: false,
+            kind: EntryKind::Value,
+            stamp: Stamp::default(),
         };
 
         let encoded = ent.encode();
 
-        assert_eq!(encoded, vec![59, 37, 55, 31, 1, 0, 0, 0, 2, 0, 0, 0, 0, 97, 98, 98]);
+        assert_eq!(encoded, vec![
+            189, 238, 156, 229, 1, 0, 0, 0, 2, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            97, 98, 98,
+        ]);
+    }
+
+    #[test]
+    fn oversized_record_names_the_part() {
+        check_lengths(10, 10).unwrap();
+        assert!(matches!(check_lengths(MAX_RECORD_DATA + 1, 0), Err(KVError::KeyTooLarge { .. })));
+        let err = check_lengths(10, MAX_RECORD_DATA).unwrap_err();
+        assert!(matches!(err, KVError::ValueTooLarge { len, max } if len == MAX_RECORD_DATA && max == MAX_RECORD_DATA - 10));
+    }
+
+    #[test]
+    fn decode_version_2_record() {
+        // the can_encode bytes before lsn and ts
+        let data = [59, 37, 55, 31, 1, 0, 0, 0, 2, 0, 0, 0, 0, 97, 98, 98];
+
+        let entry = Entry::decode_version(&mut &data[..], &Limits::default(), None, 2).unwrap();
+        assert_eq!(entry.key(), b"a");
+        assert_eq!(entry.value(), b"bb");
+        assert_eq!(entry.stamp(), Stamp::default());
+    }
+
+    #[test]
+    fn stamp_round_trip() {
+        let mut entry = Entry::new(b"k".to_vec(), b"v".to_vec());
+        entry.set_stamp(Stamp { lsn: 7, ts: 1_700_000_000_000_000 });
+
+        let decoded = Entry::decode(&mut entry.encode().as_slice()).unwrap();
+        assert_eq!(decoded.lsn(), 7);
+        assert_eq!(decoded.timestamp(), 1_700_000_000_000_000);
     }
 
     #[test]
@@ -140,7 +472,8 @@ mod tests {
         let entry = Entry {
             key: b"barbambia".to_vec(),
             val: b"kergudu".to_vec(),
-            deleted: false,
+            kind: EntryKind::Value,
+            stamp: Stamp::default(),
         };
 
         let data = entry.encode();
@@ -150,7 +483,7 @@ mod tests {
 
         assert_eq!(decoded.key, b"barbambia");
         assert_eq!(decoded.val, b"kergudu");
-        assert!(!decoded.deleted);
+        assert!(!decoded.is_deleted());
     }
 
     #[test]
@@ -158,7 +491,8 @@ mod tests {
         let entry = Entry {
             key: b"barbambia".to_vec(),
             val: b"kergudu".to_vec(),
-            deleted: false,
+            kind: EntryKind::Value,
+            stamp: Stamp::default(),
         };
 
         let mut buf = std::io::Cursor::new(Vec::new());
@@ -170,7 +504,7 @@ mod tests {
 
         assert_eq!(decoded.key, b"barbambia");
         assert_eq!(decoded.val, b"kergudu");
-        assert!(!decoded.deleted);
+        assert!(!decoded.is_deleted());
     }
 
     #[test]
@@ -178,7 +512,8 @@ mod tests {
         let entry = Entry {
             key: b"to-delete".to_vec(),
             val: Vec::new(),
-            deleted: true,
+            kind: EntryKind::Tombstone,
+            stamp: Stamp::default(),
         };
 
         let data = entry.encode();
@@ -187,6 +522,152 @@ mod tests {
 
         assert_eq!(decoded.key, b"to-delete");
         assert!(decoded.val.is_empty());
-        assert!(decoded.deleted);
+        assert!(decoded.is_deleted());
+    }
+
+    #[test]
+    fn limits_check() {
+        let limits = Limits { max_key_size: 2, max_value_size: 3 };
+
+        assert!(limits.check(b"ab", b"abc").is_ok());
+        assert!(matches!(
+            limits.check(b"abc", b""),
+            Err(KVError::KeyTooLarge { len: 3, max: 2 })
+        ));
+        assert!(matches!(
+            limits.check(b"", b"abcd"),
+            Err(KVError::ValueTooLarge { len: 4, max: 3 })
+        ));
+    }
+
+    #[test]
+    fn oversize_header_rejected_before_read() {
+        let entry = Entry::new(b"key".to_vec(), vec![1u8; 100]);
+        let data = entry.encode();
+
+        let limits = Limits { max_key_size: 16, max_value_size: 10 };
+        let err = Entry::decode_with(&mut data.as_slice(), &limits).err().unwrap();
+        assert!(matches!(err, KVError::Corruption(_)));
+    }
+
+    #[test]
+    fn huge_length_in_damaged_header() {
+        // crc, key_len = u32::MAX, val_len = 0, kind = 0, lsn, ts, no payload
+        let mut data = vec![0u8; 4];
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.push(0);
+        data.extend_from_slice(&[0; 16]);
+
+        let err = Entry::decode(&mut data.as_slice()).err().unwrap();
+        assert!(matches!(err, KVError::Corruption(_)));
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let entry = Entry::new(b"k".to_vec(), vec![b'a'; 1000]);
+
+        let mut buf = Vec::new();
+        entry.encode_into_with(&mut buf, Some(100), None).unwrap();
+        assert!(buf.len() < 100);
+        assert_eq!(buf[12] & COMPRESSED_FLAG, COMPRESSED_FLAG);
+
+        let decoded = Entry::decode(&mut buf.as_slice()).unwrap();
+        assert_eq!(decoded.kind(), EntryKind::Value);
+        assert_eq!(decoded.value(), entry.value());
+    }
+
+    #[test]
+    fn compression_skipped_below_threshold_or_when_bigger() {
+        let small = Entry::new(b"k".to_vec(), vec![b'a'; 50]);
+        let mut buf = Vec::new();
+        small.encode_into_with(&mut buf, Some(100), None).unwrap();
+        assert_eq!(buf, small.encode());
+
+        // incompressible
+        let noise: Vec<u8> = (0..200u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let noisy = Entry::new(b"k".to_vec(), noise);
+        let mut buf = Vec::new();
+        noisy.encode_into_with(&mut buf, Some(100), None).unwrap();
+        assert_eq!(buf[12] & COMPRESSED_FLAG, 0);
+    }
+
+    #[test]
+    fn crc_covers_compressed_bytes() {
+        let entry = Entry::new(b"k".to_vec(), vec![b'z'; 500]);
+        let mut buf = Vec::new();
+        entry.encode_into_with(&mut buf, Some(1), None).unwrap();
+
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        let err = Entry::decode(&mut buf.as_slice()).err().unwrap();
+        assert!(matches!(err, KVError::Corruption(_)));
+    }
+
+    #[test]
+    fn decompressed_size_checked_against_limits() {
+        let entry = Entry::new(b"k".to_vec(), vec![0u8; 4096]);
+        let mut buf = Vec::new();
+        entry.encode_into_with(&mut buf, Some(1), None).unwrap();
+
+        // fits on disk, too big once decompressed
+        let limits = Limits { max_key_size: 16, max_value_size: 1024 };
+        let err = Entry::decode_with(&mut buf.as_slice(), &limits).err().unwrap();
+        assert!(matches!(err, KVError::Corruption(_)));
+    }
+
+    fn seal(cipher: &Cipher, offset: u64) -> Option<Seal<'_>> {
+        Some(Seal { cipher, offset })
+    }
+
+    #[test]
+    fn sealed_round_trip() {
+        let cipher = Cipher::new(1, &[7; 32]);
+        let entry = Entry::new(b"customer-42".to_vec(), vec![b'a'; 300]);
+
+        let mut buf = Vec::new();
+        entry.encode_into_with(&mut buf, Some(100), seal(&cipher, 64)).unwrap();
+        assert!(!buf.windows(11).any(|w| w == b"customer-42"));
+
+        let decoded = Entry::decode_sealed(&mut buf.as_slice(), &Limits::default(), seal(&cipher, 64)).unwrap();
+        assert_eq!(decoded.key(), b"customer-42");
+        assert_eq!(decoded.value(), entry.value());
+        assert_eq!(decoded.kind(), EntryKind::Value);
+
+        let err = Entry::decode(&mut buf.as_slice()).err().unwrap();
+        assert!(matches!(err, KVError::KeyMismatch(_)));
+    }
+
+    #[test]
+    fn tampering_differs_from_corruption() {
+        let cipher = Cipher::new(1, &[7; 32]);
+        let limits = Limits::default();
+        let mut buf = Vec::new();
+        Entry::new(b"k".to_vec(), b"v".to_vec())
+            .encode_into_with(&mut buf, None, seal(&cipher, 0))
+            .unwrap();
+
+        // flipped bit, crc left alone: disk damage
+        let mut damaged = buf.clone();
+        damaged[20] ^= 1;
+        let err = Entry::decode_sealed(&mut damaged.as_slice(), &limits, seal(&cipher, 0)).err().unwrap();
+        assert!(matches!(err, KVError::Corruption(_)));
+
+        // flipped bit with a fixed crc: somebody did it on purpose
+        let mut forged = damaged;
+        let mut hasher = Hasher::new();
+        hasher.update(&forged[4..]);
+        forged[..4].copy_from_slice(&hasher.finalize().to_le_bytes());
+        let err = Entry::decode_sealed(&mut forged.as_slice(), &limits, seal(&cipher, 0)).err().unwrap();
+        assert!(matches!(err, KVError::Tampered(_)));
+
+        // record moved to another offset
+        let err = Entry::decode_sealed(&mut buf.as_slice(), &limits, seal(&cipher, 99)).err().unwrap();
+        assert!(matches!(err, KVError::Tampered(_)));
+
+        // plaintext record slipped into an encrypted log
+        let plain = Entry::new(b"k".to_vec(), b"evil".to_vec()).encode();
+        let err = Entry::decode_sealed(&mut plain.as_slice(), &limits, seal(&cipher, 0)).err().unwrap();
+        assert!(matches!(err, KVError::Tampered(_)));
     }
 }
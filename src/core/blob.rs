@@ -0,0 +1,206 @@
+//! Blobs: large values stored as a chain of chunk records
+use crate::core::binary_serializer::EntryKind;
+use crate::core::log_storage::{Log, RecordPos};
+use crate::error::KVError;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+pub const DEFAULT_BLOB_CHUNK_SIZE: usize = 1 << 20;
+
+// Value of the BlobRef record: where the chunks are.
+// | len | count | (offset | rec_len)* |
+// | 8   | 4     | 8      | 4         |
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobManifest {
+    pub len: u64,
+    pub chunks: Vec<RecordPos>,
+}
+
+impl BlobManifest {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.len.to_le_bytes());
+        out.extend_from_slice(&(self.chunks.len() as u32).to_le_bytes());
+        for pos in &self.chunks {
+            out.extend_from_slice(&pos.offset.to_le_bytes());
+            out.extend_from_slice(&pos.len.to_le_bytes());
+        }
+    }
+
+    // advances `data` past the manifest
+    pub fn decode(data: &mut &[u8]) -> Result<Self, KVError> {
+        let bad = || KVError::corruption("bad blob manifest");
+
+        if data.len() < 12 {
+            return Err(bad());
+        }
+        let len = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        let count = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+        *data = &data[12..];
+
+        if data.len() < count * 12 {
+            return Err(bad());
+        }
+        let chunks = data[..count * 12]
+            .chunks_exact(12)
+            .map(|c| RecordPos {
+                offset: u64::from_le_bytes(c[0..8].try_into().unwrap()),
+                len: u32::from_le_bytes(c[8..12].try_into().unwrap()),
+            })
+            .collect();
+        *data = &data[count * 12..];
+
+        Ok(BlobManifest { len, chunks })
+    }
+}
+
+// The whole value. `len` comes from disk: it only sizes the buffer up to
+// what the chunks can hold, and must match what they do hold.
+pub fn read_blob(log: &Log, key: &[u8], manifest: &BlobManifest) -> Result<Vec<u8>, KVError> {
+    let most = manifest.chunks.len() as u64 * log.limits().max_value_size as u64;
+    let mut val = Vec::with_capacity(manifest.len.min(most) as usize);
+    BlobReader::new(log, key, manifest).read_to_end(&mut val)?;
+    if val.len() as u64 != manifest.len {
+        return Err(KVError::corruption(format!(
+            "blob is {} bytes, its manifest says {}",
+            val.len(),
+            manifest.len
+        )));
+    }
+    Ok(val)
+}
+
+// Streams a value one chunk at a time. Plain values are served from
+// memory, they are small anyway.
+pub struct BlobReader<'a> {
+    log: &'a Log,
+    key: Vec<u8>,
+    chunks: VecDeque<RecordPos>,
+    buf: Vec<u8>,
+    at: usize,
+}
+
+impl<'a> BlobReader<'a> {
+    pub fn new(log: &'a Log, key: &[u8], manifest: &BlobManifest) -> Self {
+        BlobReader {
+            log,
+            key: key.to_vec(),
+            chunks: manifest.chunks.iter().copied().collect(),
+            buf: Vec::new(),
+            at: 0,
+        }
+    }
+
+    pub fn from_value(log: &'a Log, val: Vec<u8>) -> Self {
+        BlobReader {
+            log,
+            key: Vec::new(),
+            chunks: VecDeque::new(),
+            buf: val,
+            at: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<bool, KVError> {
+        let Some(pos) = self.chunks.pop_front() else {
+            return Ok(false);
+        };
+
+        let entry = self.log.read_at(pos)?;
+        if entry.kind() != EntryKind::BlobChunk || entry.key() != self.key {
+            return Err(KVError::corruption("blob chunk does not belong to the blob"));
+        }
+
+        self.buf = entry.value().to_vec();
+        self.at = 0;
+        Ok(true)
+    }
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.at == self.buf.len() {
+            match self.next_chunk() {
+                Ok(true) => {}
+                Ok(false) => return Ok(0),
+                Err(KVError::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+
+        let n = out.len().min(self.buf.len() - self.at);
+        out[..n].copy_from_slice(&self.buf[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::binary_serializer::Entry;
+
+    #[test]
+    fn manifest_encode_decode() {
+        let m = BlobManifest {
+            len: 10,
+            chunks: vec![
+                RecordPos { offset: 0, len: 20 },
+                RecordPos { offset: 20, len: 18 },
+            ],
+        };
+
+        let mut buf = Vec::new();
+        m.encode(&mut buf);
+        buf.push(0xaa);
+
+        let mut data = buf.as_slice();
+        assert_eq!(BlobManifest::decode(&mut data).unwrap(), m);
+        assert_eq!(data, &[0xaa]);
+
+        assert!(BlobManifest::decode(&mut &buf[..15]).is_err());
+    }
+
+    #[test]
+    fn reads_chunks_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path().join("wal.log")).unwrap();
+
+        let mut chunks = Vec::new();
+        for part in [&b"hello "[..], b"blob ", b"world"] {
+            let mut entry = Entry::with_kind(b"k".to_vec(), part.to_vec(), EntryKind::BlobChunk);
+            chunks.push(log.append(&mut entry).unwrap());
+        }
+        let manifest = BlobManifest { len: 16, chunks };
+
+        let mut out = String::new();
+        BlobReader::new(&log, b"k", &manifest).read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello blob world");
+    }
+
+    #[test]
+    fn damaged_length_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path().join("wal.log")).unwrap();
+
+        let mut entry = Entry::with_kind(b"k".to_vec(), b"data".to_vec(), EntryKind::BlobChunk);
+        let chunks = vec![log.append(&mut entry).unwrap()];
+        assert_eq!(read_blob(&log, b"k", &BlobManifest { len: 4, chunks: chunks.clone() }).unwrap(), b"data");
+
+        // not a huge allocation first
+        let manifest = BlobManifest { len: u64::MAX, chunks };
+        assert!(matches!(read_blob(&log, b"k", &manifest), Err(KVError::Corruption(_))));
+    }
+
+    #[test]
+    fn foreign_chunk_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path().join("wal.log")).unwrap();
+
+        let pos = log.append(&mut Entry::new(b"k".to_vec(), b"plain".to_vec())).unwrap();
+        let manifest = BlobManifest { len: 5, chunks: vec![pos] };
+
+        let mut out = Vec::new();
+        let err = BlobReader::new(&log, b"k", &manifest).read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
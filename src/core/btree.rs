@@ -0,0 +1,753 @@
+//! Copy-on-write B+tree
+use crate::core::buffer_pool::{PageId, PAGE_SIZE};
+use crate::core::pager::{Pager, NO_PAGE};
+use crate::core::vfs::{StdVfs, Vfs};
+use crate::error::KVError;
+use std::collections::HashSet;
+use std::path::Path;
+
+pub const MAX_KEY_SIZE: usize = 1000;
+pub const MAX_VAL_SIZE: usize = 3000;
+
+pub type Pair = (Vec<u8>, Vec<u8>);
+
+const NODE_LEAF: u8 = 1;
+const NODE_INTERNAL: u8 = 2;
+
+// | type | nkeys | items... |
+// | 1    | 2     |          |
+const NODE_HEADER: usize = 3;
+
+// nodes smaller than this try to merge with a sibling
+const MERGE_THRESHOLD: usize = PAGE_SIZE / 4;
+
+// leaf item:     | key_len | val_len | key | val |
+//                | 2       | 2       | ... | ... |
+// internal item: | child | key_len | key |
+//                | 8     | 2       | ... |
+// internal keys are the first key of each child subtree
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Leaf(Vec<Pair>),
+    Internal(Vec<(Vec<u8>, PageId)>),
+}
+
+impl Node {
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf(items) => items.len(),
+            Node::Internal(items) => items.len(),
+        }
+    }
+
+    fn first_key(&self) -> Vec<u8> {
+        match self {
+            Node::Leaf(items) => items[0].0.clone(),
+            Node::Internal(items) => items[0].0.clone(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        NODE_HEADER
+            + match self {
+                Node::Leaf(items) => items.iter().map(|(k, v)| leaf_item_size(k, v)).sum::<usize>(),
+                Node::Internal(items) => items.iter().map(|(k, _)| internal_item_size(k)).sum(),
+            }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Node::Leaf(items) => {
+                buf.push(NODE_LEAF);
+                buf.extend_from_slice(&(items.len() as u16).to_le_bytes());
+                for (key, val) in items {
+                    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(&(val.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(val);
+                }
+            }
+            Node::Internal(items) => {
+                buf.push(NODE_INTERNAL);
+                buf.extend_from_slice(&(items.len() as u16).to_le_bytes());
+                for (key, child) in items {
+                    buf.extend_from_slice(&child.to_le_bytes());
+                    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(key);
+                }
+            }
+        }
+        buf
+    }
+
+    fn decode(page: &[u8]) -> Result<Self, KVError> {
+        let bad = || KVError::corruption("bad btree node");
+        let take = |data: &mut &[u8], n: usize| -> Result<Vec<u8>, KVError> {
+            if data.len() < n {
+                return Err(bad());
+            }
+            let (head, rest) = data.split_at(n);
+            *data = rest;
+            Ok(head.to_vec())
+        };
+        let u16_at = |b: &[u8]| u16::from_le_bytes(b.try_into().unwrap()) as usize;
+
+        let kind = page[0];
+        let nkeys = u16_at(&page[1..3]);
+        let mut data = &page[NODE_HEADER..];
+
+        match kind {
+            NODE_LEAF => {
+                let mut items = Vec::with_capacity(nkeys);
+                for _ in 0..nkeys {
+                    let head = take(&mut data, 4)?;
+                    let key = take(&mut data, u16_at(&head[0..2]))?;
+                    let val = take(&mut data, u16_at(&head[2..4]))?;
+                    items.push((key, val));
+                }
+                Ok(Node::Leaf(items))
+            }
+            NODE_INTERNAL => {
+                let mut items = Vec::with_capacity(nkeys);
+                for _ in 0..nkeys {
+                    let head = take(&mut data, 10)?;
+                    let child = u64::from_le_bytes(head[0..8].try_into().unwrap());
+                    let key = take(&mut data, u16_at(&head[8..10]))?;
+                    items.push((key, child));
+                }
+                Ok(Node::Internal(items))
+            }
+            _ => Err(bad()),
+        }
+    }
+
+    // cut into nodes that fit a page, filling each one greedily
+    fn split(self) -> Vec<Node> {
+        if self.size() <= PAGE_SIZE {
+            return vec![self];
+        }
+
+        fn chunks<T>(items: Vec<T>, size: impl Fn(&T) -> usize) -> Vec<Vec<T>> {
+            let mut out: Vec<Vec<T>> = vec![Vec::new()];
+            let mut used = NODE_HEADER;
+            for item in items {
+                let n = size(&item);
+                if used + n > PAGE_SIZE {
+                    out.push(Vec::new());
+                    used = NODE_HEADER;
+                }
+                used += n;
+                out.last_mut().unwrap().push(item);
+            }
+            out
+        }
+
+        match self {
+            Node::Leaf(items) => chunks(items, |(k, v)| leaf_item_size(k, v))
+                .into_iter()
+                .map(Node::Leaf)
+                .collect(),
+            Node::Internal(items) => chunks(items, |(k, _)| internal_item_size(k))
+                .into_iter()
+                .map(Node::Internal)
+                .collect(),
+        }
+    }
+}
+
+fn leaf_item_size(key: &[u8], val: &[u8]) -> usize {
+    4 + key.len() + val.len()
+}
+
+fn internal_item_size(key: &[u8]) -> usize {
+    10 + key.len()
+}
+
+// index of the child whose subtree may hold the key
+fn child_index(items: &[(Vec<u8>, PageId)], key: &[u8]) -> usize {
+    items
+        .iter()
+        .rposition(|(first, _)| first.as_slice() <= key)
+        .unwrap_or(0)
+}
+
+// A committed root that stays readable while the tree changes.
+#[derive(Debug)]
+pub struct ReadSnapshot {
+    root: PageId,
+    seq: u64,
+}
+
+// Updates never touch a live page: changed nodes are written to new pages
+// up to a new root, and commit() switches the root in the meta page.
+pub struct BTree {
+    pager: Pager,
+    root: PageId,
+}
+
+impl BTree {
+    // budget: buffer pool memory in bytes
+    pub fn open(path: &Path, budget: usize) -> Result<Self, KVError> {
+        Self::open_in(&StdVfs, path, budget)
+    }
+
+    pub fn open_in(vfs: &dyn Vfs, path: &Path, budget: usize) -> Result<Self, KVError> {
+        let pager = Pager::open_in(vfs, path, budget)?;
+        let root = pager.root();
+        Ok(BTree { pager, root })
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        self.get_from(self.root, key)
+    }
+
+    pub fn get_at(&mut self, snap: &ReadSnapshot, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        self.get_from(snap.root, key)
+    }
+
+    // true if the key existed
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<bool, KVError> {
+        if key.is_empty() {
+            return Err(KVError::ConstraintViolation("empty key".into()));
+        }
+        if key.len() > MAX_KEY_SIZE {
+            return Err(KVError::KeyTooLarge { len: key.len(), max: MAX_KEY_SIZE });
+        }
+        if val.len() > MAX_VAL_SIZE {
+            return Err(KVError::ValueTooLarge { len: val.len(), max: MAX_VAL_SIZE });
+        }
+
+        let mut existed = false;
+        let mut nodes = if self.root == NO_PAGE {
+            vec![(key.to_vec(), self.write_node(Node::Leaf(vec![(key.to_vec(), val.to_vec())]))?)]
+        } else {
+            self.insert_into(self.root, key, val, &mut existed)?
+        };
+
+        // root split: grow a level
+        while nodes.len() > 1 {
+            let mut parents = Vec::new();
+            for node in Node::Internal(nodes).split() {
+                let first = node.first_key();
+                parents.push((first, self.write_node(node)?));
+            }
+            nodes = parents;
+        }
+        self.root = nodes[0].1;
+
+        Ok(existed)
+    }
+
+    // true if the key existed
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, KVError> {
+        if self.root == NO_PAGE {
+            return Ok(false);
+        }
+
+        let Some(node) = self.delete_from(self.root, key)? else {
+            return Ok(false);
+        };
+
+        self.root = match node {
+            None => NO_PAGE,
+            Some(mut id) => {
+                // an internal root with one child is a wasted level
+                loop {
+                    match self.read_node(id)? {
+                        Node::Internal(items) if items.len() == 1 => {
+                            self.pager.free(id)?;
+                            id = items[0].1;
+                        }
+                        _ => break id,
+                    }
+                }
+            }
+        };
+
+        Ok(true)
+    }
+
+    // make the current tree durable
+    pub fn commit(&mut self) -> Result<(), KVError> {
+        self.pager.set_root(self.root);
+        self.pager.commit()
+    }
+
+    // drop uncommitted changes
+    pub fn rollback(&mut self) {
+        self.pager.rollback();
+        self.root = self.pager.root();
+    }
+
+    // read view of the last commit, pages stay valid until release
+    pub fn snapshot(&mut self) -> ReadSnapshot {
+        let seq = self.pager.hold_snapshot();
+        ReadSnapshot {
+            root: self.pager.root(),
+            seq,
+        }
+    }
+
+    pub fn release(&mut self, snap: ReadSnapshot) -> Result<(), KVError> {
+        self.pager.release_snapshot(snap.seq)
+    }
+
+    // all pairs in key order
+    pub fn entries(&mut self) -> Result<Vec<Pair>, KVError> {
+        let mut out = Vec::new();
+        if self.root != NO_PAGE {
+            self.collect(self.root, &mut out)?;
+        }
+        Ok(out)
+    }
+
+    // tree invariants plus the free list check against the pages in use
+    pub fn check(&mut self) -> Result<(), KVError> {
+        let mut used = HashSet::new();
+        let root = self.pager.root();
+        if root != NO_PAGE {
+            self.check_node(root, None, &mut used)?;
+        }
+        self.pager.check(&used)
+    }
+
+    fn get_from(&mut self, mut id: PageId, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        if id == NO_PAGE {
+            return Ok(None);
+        }
+        loop {
+            match self.read_node(id)? {
+                Node::Leaf(items) => {
+                    return Ok(items.into_iter().find(|(k, _)| k == key).map(|(_, v)| v));
+                }
+                Node::Internal(items) => id = items[child_index(&items, key)].1,
+            }
+        }
+    }
+
+    // -> replacement for the node: (first key, page) of each new node
+    fn insert_into(
+        &mut self,
+        id: PageId,
+        key: &[u8],
+        val: &[u8],
+        existed: &mut bool,
+    ) -> Result<Vec<(Vec<u8>, PageId)>, KVError> {
+        let node = match self.read_node(id)? {
+            Node::Leaf(mut items) => {
+                match items.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                    Ok(i) => {
+                        *existed = true;
+                        items[i].1 = val.to_vec();
+                    }
+                    Err(i) => items.insert(i, (key.to_vec(), val.to_vec())),
+                }
+                Node::Leaf(items)
+            }
+            Node::Internal(mut items) => {
+                let i = child_index(&items, key);
+                let replaced = self.insert_into(items[i].1, key, val, existed)?;
+                items.splice(i..=i, replaced);
+                Node::Internal(items)
+            }
+        };
+        self.pager.free(id)?;
+
+        let mut out = Vec::new();
+        for part in node.split() {
+            let first = part.first_key();
+            out.push((first, self.write_node(part)?));
+        }
+        Ok(out)
+    }
+
+    // None: key not found, nothing changed
+    // Some(None): node became empty
+    // Some(Some(page)): new copy of the node
+    fn delete_from(&mut self, id: PageId, key: &[u8]) -> Result<Option<Option<PageId>>, KVError> {
+        let node = match self.read_node(id)? {
+            Node::Leaf(mut items) => {
+                let Ok(i) = items.binary_search_by(|(k, _)| k.as_slice().cmp(key)) else {
+                    return Ok(None);
+                };
+                items.remove(i);
+                Node::Leaf(items)
+            }
+            Node::Internal(mut items) => {
+                let i = child_index(&items, key);
+                let Some(child) = self.delete_from(items[i].1, key)? else {
+                    return Ok(None);
+                };
+                match child {
+                    None => {
+                        items.remove(i);
+                    }
+                    Some(child) => {
+                        let child_node = self.read_node(child)?;
+                        items[i] = (child_node.first_key(), child);
+                        if child_node.size() < MERGE_THRESHOLD {
+                            self.merge_child(&mut items, i, child_node)?;
+                        }
+                    }
+                }
+                Node::Internal(items)
+            }
+        };
+        self.pager.free(id)?;
+
+        if node.len() == 0 {
+            return Ok(Some(None));
+        }
+        Ok(Some(Some(self.write_node(node)?)))
+    }
+
+    // merge a small child with its left or right sibling if both fit a page
+    fn merge_child(
+        &mut self,
+        items: &mut Vec<(Vec<u8>, PageId)>,
+        i: usize,
+        child: Node,
+    ) -> Result<(), KVError> {
+        let candidates = [i.checked_sub(1), Some(i + 1).filter(|&j| j < items.len())];
+        for sibling in candidates.into_iter().flatten() {
+            let sib_node = self.read_node(items[sibling].1)?;
+            if sib_node.size() + child.size() - NODE_HEADER > PAGE_SIZE {
+                continue;
+            }
+
+            let (left, right) = if sibling < i {
+                (sib_node, child)
+            } else {
+                (child, sib_node)
+            };
+            let merged = match (left, right) {
+                (Node::Leaf(mut a), Node::Leaf(b)) => {
+                    a.extend(b);
+                    Node::Leaf(a)
+                }
+                (Node::Internal(mut a), Node::Internal(b)) => {
+                    a.extend(b);
+                    Node::Internal(a)
+                }
+                // a leaf next to an internal node: the file is damaged
+                _ => return Err(KVError::corruption("bad btree node")),
+            };
+
+            let lo = sibling.min(i);
+            self.pager.free(items[lo].1)?;
+            self.pager.free(items[lo + 1].1)?;
+            let first = merged.first_key();
+            let page = self.write_node(merged)?;
+            items.splice(lo..=lo + 1, [(first, page)]);
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    fn collect(&mut self, id: PageId, out: &mut Vec<Pair>) -> Result<(), KVError> {
+        match self.read_node(id)? {
+            Node::Leaf(items) => out.extend(items),
+            Node::Internal(items) => {
+                for (_, child) in items {
+                    self.collect(child, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // keys sorted, internal keys match children, every page used once
+    fn check_node(
+        &mut self,
+        id: PageId,
+        first: Option<&[u8]>,
+        used: &mut HashSet<PageId>,
+    ) -> Result<(), KVError> {
+        let bad = KVError::Corruption;
+
+        if !used.insert(id) {
+            return Err(bad(format!("page {id} reachable twice")));
+        }
+        let node = self.read_node(id)?;
+        if node.len() == 0 {
+            return Err(bad(format!("empty node {id}")));
+        }
+        if let Some(first) = first
+            && node.first_key() != first
+        {
+            return Err(bad(format!("node {id} does not start with its parent key")));
+        }
+
+        let keys: Vec<&[u8]> = match &node {
+            Node::Leaf(items) => items.iter().map(|(k, _)| k.as_slice()).collect(),
+            Node::Internal(items) => items.iter().map(|(k, _)| k.as_slice()).collect(),
+        };
+        if keys.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(bad(format!("keys of node {id} are not sorted")));
+        }
+
+        if let Node::Internal(items) = node {
+            for (key, child) in items {
+                self.check_node(child, Some(&key), used)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_node(&mut self, id: PageId) -> Result<Node, KVError> {
+        Node::decode(&self.pager.read_page(id)?)
+    }
+
+    fn write_node(&mut self, node: Node) -> Result<PageId, KVError> {
+        let id = self.pager.alloc();
+        self.pager.write_page(id, &node.encode())?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUDGET: usize = 64 * PAGE_SIZE;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{i:06}").into_bytes()
+    }
+
+    // deterministic shuffle
+    fn shuffled(n: u32) -> Vec<u32> {
+        let mut v: Vec<u32> = (0..n).collect();
+        let mut x = 0x2545_f491_u32;
+        for i in (1..v.len()).rev() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            v.swap(i, x as usize % (i + 1));
+        }
+        v
+    }
+
+    #[test]
+    fn node_encode_decode() {
+        let leaf = Node::Leaf(vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), vec![])]);
+        let internal = Node::Internal(vec![(b"a".to_vec(), 7), (b"m".to_vec(), 9)]);
+
+        assert_eq!(Node::decode(&leaf.encode()).unwrap(), leaf);
+        assert_eq!(Node::decode(&internal.encode()).unwrap(), internal);
+        assert_eq!(leaf.encode().len(), leaf.size());
+    }
+
+    #[test]
+    fn insert_get_many() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = BTree::open(&dir.path().join("tree.db"), BUDGET).unwrap();
+
+        for i in shuffled(2000) {
+            assert!(!tree.insert(&key(i), &[i as u8; 100]).unwrap());
+        }
+        assert!(tree.insert(&key(5), b"new").unwrap());
+        tree.commit().unwrap();
+
+        assert_eq!(tree.get(&key(5)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(tree.get(&key(1999)).unwrap(), Some(vec![1999u32 as u8; 100]));
+        assert!(tree.get(b"nope").unwrap().is_none());
+
+        let all = tree.entries().unwrap();
+        assert_eq!(all.len(), 2000);
+        assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+
+        tree.check().unwrap();
+    }
+
+    #[test]
+    fn delete_shrinks_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = BTree::open(&dir.path().join("tree.db"), BUDGET).unwrap();
+
+        for i in 0..1000 {
+            tree.insert(&key(i), &[1u8; 50]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        for i in shuffled(1000) {
+            if i % 3 != 0 {
+                assert!(tree.delete(&key(i)).unwrap());
+            }
+        }
+        assert!(!tree.delete(&key(1)).unwrap());
+        tree.commit().unwrap();
+        tree.check().unwrap();
+
+        assert_eq!(tree.entries().unwrap().len(), 334);
+        assert!(tree.get(&key(1)).unwrap().is_none());
+        assert!(tree.get(&key(3)).unwrap().is_some());
+
+        for i in (0..1000).filter(|i| i % 3 == 0) {
+            tree.delete(&key(i)).unwrap();
+        }
+        tree.commit().unwrap();
+        assert_eq!(tree.root, NO_PAGE);
+        tree.check().unwrap();
+    }
+
+    #[test]
+    fn commit_persists_and_rollback_discards() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+
+        {
+            let mut tree = BTree::open(&path, BUDGET).unwrap();
+            tree.insert(b"a", b"1").unwrap();
+            tree.commit().unwrap();
+
+            tree.insert(b"b", b"2").unwrap();
+            tree.rollback();
+            assert!(tree.get(b"b").unwrap().is_none());
+
+            // never committed: lost on "crash"
+            tree.insert(b"c", b"3").unwrap();
+        }
+
+        let mut tree = BTree::open(&path, BUDGET).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(tree.get(b"c").unwrap().is_none());
+        tree.check().unwrap();
+    }
+
+    #[test]
+    fn siblings_on_different_levels_are_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = BTree::open(&dir.path().join("tree.db"), BUDGET).unwrap();
+
+        let leaf = tree.write_node(Node::Leaf(vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())])).unwrap();
+        let deep = tree.write_node(Node::Leaf(vec![(b"m".to_vec(), b"3".to_vec())])).unwrap();
+        let inner = tree.write_node(Node::Internal(vec![(b"m".to_vec(), deep)])).unwrap();
+        tree.root = tree.write_node(Node::Internal(vec![(b"a".to_vec(), leaf), (b"m".to_vec(), inner)])).unwrap();
+
+        assert!(matches!(tree.delete(b"a"), Err(KVError::Corruption(_))));
+    }
+
+    #[test]
+    fn commit_after_rollback_keeps_the_free_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = BTree::open(&dir.path().join("tree.db"), BUDGET).unwrap();
+
+        for i in 0..200u32 {
+            tree.insert(&i.to_be_bytes(), &[7; 100]).unwrap();
+        }
+        tree.commit().unwrap();
+        for i in 0..100u32 {
+            tree.insert(&i.to_be_bytes(), &[8; 100]).unwrap();
+        }
+        tree.rollback();
+        tree.commit().unwrap();
+
+        tree.check().unwrap();
+        assert_eq!(tree.get(&5u32.to_be_bytes()).unwrap(), Some(vec![7; 100]));
+    }
+
+    #[test]
+    fn torn_commit_keeps_previous_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+
+        {
+            let mut tree = BTree::open(&path, BUDGET).unwrap();
+            tree.insert(b"a", b"1").unwrap();
+            tree.commit().unwrap();
+            tree.insert(b"a", b"2").unwrap();
+            tree.commit().unwrap();
+        }
+
+        // tear the newest meta page (seq 3 -> page 1)
+        let mut data = std::fs::read(&path).unwrap();
+        data[PAGE_SIZE + 12] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let mut tree = BTree::open(&path, BUDGET).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+        tree.check().unwrap();
+    }
+
+    #[test]
+    fn snapshot_reads_old_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = BTree::open(&dir.path().join("tree.db"), BUDGET).unwrap();
+
+        for i in 0..300 {
+            tree.insert(&key(i), b"old").unwrap();
+        }
+        tree.commit().unwrap();
+
+        let snap = tree.snapshot();
+        for i in 0..300 {
+            tree.insert(&key(i), b"new").unwrap();
+        }
+        tree.delete(&key(7)).unwrap();
+        tree.commit().unwrap();
+
+        // more commits must not reuse the pages the snapshot sees
+        for i in 300..600 {
+            tree.insert(&key(i), b"more").unwrap();
+            tree.commit().unwrap();
+        }
+
+        assert_eq!(tree.get_at(&snap, &key(7)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(tree.get_at(&snap, &key(299)).unwrap(), Some(b"old".to_vec()));
+        assert!(tree.get_at(&snap, &key(300)).unwrap().is_none());
+        assert!(tree.get(&key(7)).unwrap().is_none());
+
+        tree.release(snap).unwrap();
+        tree.check().unwrap();
+    }
+
+    #[test]
+    fn pages_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = BTree::open(&dir.path().join("tree.db"), BUDGET).unwrap();
+
+        for i in 0..200 {
+            tree.insert(&key(i), &[0u8; 100]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        for round in 0..50u8 {
+            for i in 0..200 {
+                tree.insert(&key(i), &[round; 100]).unwrap();
+            }
+            tree.commit().unwrap();
+        }
+
+        // rewrites do not grow the file much
+        let npages = tree.pager.npages();
+        for _ in 0..10 {
+            for i in 0..200 {
+                tree.insert(&key(i), &[9u8; 100]).unwrap();
+            }
+            tree.commit().unwrap();
+        }
+        assert!(tree.pager.npages() <= npages + 2);
+        tree.check().unwrap();
+    }
+
+    #[test]
+    fn size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = BTree::open(&dir.path().join("tree.db"), BUDGET).unwrap();
+
+        assert!(tree.insert(&[1u8; MAX_KEY_SIZE + 1], b"v").is_err());
+        assert!(tree.insert(b"k", &[1u8; MAX_VAL_SIZE + 1]).is_err());
+        assert!(tree.insert(b"", b"v").is_err());
+
+        // biggest items still split into pages
+        for i in 0..10 {
+            let mut k = vec![i as u8; MAX_KEY_SIZE];
+            k[0] = b'k';
+            tree.insert(&k, &[i as u8; MAX_VAL_SIZE]).unwrap();
+        }
+        tree.commit().unwrap();
+        tree.check().unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+//! Buffer pool (page cache)
+use crate::core::fsync::create_file_sync;
+use crate::core::vfs::{StdVfs, Vfs, VfsFile};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub type PageId = u64;
+
+struct Frame {
+    page: Option<PageId>,
+    data: Box<[u8]>,
+    pins: u32,
+    dirty: bool,
+    // CLOCK reference bit: one more turn of the hand before eviction
+    referenced: bool,
+}
+
+pub struct BufferPool {
+    file: Box<dyn VfsFile>,
+    frames: Vec<Frame>,
+    table: HashMap<PageId, usize>,
+    hand: usize,
+}
+
+impl BufferPool {
+    // budget in bytes, rounded down to whole pages
+    pub fn open(path: &Path, budget: usize) -> io::Result<Self> {
+        Self::open_in(&StdVfs, path, budget)
+    }
+
+    pub fn open_in(vfs: &dyn Vfs, path: &Path, budget: usize) -> io::Result<Self> {
+        let nframes = budget / PAGE_SIZE;
+        if nframes == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffer pool budget is smaller than one page",
+            ));
+        }
+
+        let file = create_file_sync(vfs, path)?;
+        let frames = (0..nframes)
+            .map(|_| Frame {
+                page: None,
+                data: vec![0u8; PAGE_SIZE].into_boxed_slice(),
+                pins: 0,
+                dirty: false,
+                referenced: false,
+            })
+            .collect();
+
+        Ok(BufferPool {
+            file,
+            frames,
+            table: HashMap::new(),
+            hand: 0,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_cached(&self, id: PageId) -> bool {
+        self.table.contains_key(&id)
+    }
+
+    // load the page if needed and keep it in memory until unpin
+    pub fn pin(&mut self, id: PageId) -> io::Result<()> {
+        if let Some(&idx) = self.table.get(&id) {
+            let frame = &mut self.frames[idx];
+            frame.pins += 1;
+            frame.referenced = true;
+            return Ok(());
+        }
+
+        let idx = self.victim()?;
+        self.evict(idx)?;
+
+        let frame = &mut self.frames[idx];
+        read_page(&*self.file, id, &mut frame.data)?;
+        frame.page = Some(id);
+        frame.pins = 1;
+        frame.dirty = false;
+        frame.referenced = true;
+        self.table.insert(id, idx);
+
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, id: PageId) -> io::Result<()> {
+        let frame = self.frame_mut(id)?;
+        frame.pins -= 1;
+        Ok(())
+    }
+
+    pub fn page(&self, id: PageId) -> io::Result<&[u8]> {
+        let idx = self.pinned(id)?;
+        Ok(&self.frames[idx].data)
+    }
+
+    // marks the page dirty, it is written back on eviction or flush
+    pub fn page_mut(&mut self, id: PageId) -> io::Result<&mut [u8]> {
+        let frame = self.frame_mut(id)?;
+        frame.dirty = true;
+        Ok(&mut frame.data)
+    }
+
+    // write back all dirty pages and fsync the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        for frame in &mut self.frames {
+            if let (Some(id), true) = (frame.page, frame.dirty) {
+                self.file.write_all_at(&frame.data, id * PAGE_SIZE as u64)?;
+                frame.dirty = false;
+            }
+        }
+        self.file.sync()
+    }
+
+    // a bug in the caller, but not worth bringing the process down for
+    fn pinned(&self, id: PageId) -> io::Result<usize> {
+        match self.table.get(&id) {
+            Some(&idx) if self.frames[idx].pins > 0 => Ok(idx),
+            _ => Err(io::Error::other(format!("buffer pool: page {id} is not pinned"))),
+        }
+    }
+
+    fn frame_mut(&mut self, id: PageId) -> io::Result<&mut Frame> {
+        let idx = self.pinned(id)?;
+        Ok(&mut self.frames[idx])
+    }
+
+    // CLOCK: skip pinned frames, clear reference bits until one is found unset
+    fn victim(&mut self) -> io::Result<usize> {
+        // two full turns: the first may only clear reference bits
+        for _ in 0..self.frames.len() * 2 {
+            let idx = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+
+            let frame = &mut self.frames[idx];
+            if frame.pins > 0 {
+                continue;
+            }
+            if frame.referenced {
+                frame.referenced = false;
+                continue;
+            }
+            return Ok(idx);
+        }
+
+        Err(io::Error::other("buffer pool: all pages are pinned"))
+    }
+
+    // a failed write-back leaves the page cached and dirty
+    fn evict(&mut self, idx: usize) -> io::Result<()> {
+        let frame = &mut self.frames[idx];
+        let Some(old) = frame.page else {
+            return Ok(());
+        };
+
+        if frame.dirty {
+            // without fsync: flush() makes it durable
+            self.file.write_all_at(&frame.data, old * PAGE_SIZE as u64)?;
+            frame.dirty = false;
+        }
+        frame.page = None;
+        self.table.remove(&old);
+
+        Ok(())
+    }
+}
+
+// pages past the end of the file read as zeros
+fn read_page(file: &dyn VfsFile, id: PageId, buf: &mut [u8]) -> io::Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        let n = file.read_at(&mut buf[done..], id * PAGE_SIZE as u64 + done as u64)?;
+        if n == 0 {
+            break;
+        }
+        done += n;
+    }
+    buf[done..].fill(0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sim_vfs::{Faults, SimVfs};
+
+    fn pool(pages: usize) -> (tempfile::TempDir, BufferPool) {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = BufferPool::open(&dir.path().join("data.db"), pages * PAGE_SIZE).unwrap();
+        (dir, pool)
+    }
+
+    #[test]
+    fn budget_sets_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        let pool = BufferPool::open(&path, 3 * PAGE_SIZE + 100).unwrap();
+        assert_eq!(pool.capacity(), 3);
+
+        assert!(BufferPool::open(&path, PAGE_SIZE - 1).is_err());
+    }
+
+    #[test]
+    fn new_page_is_zeroed() {
+        let (_dir, mut pool) = pool(2);
+
+        pool.pin(7).unwrap();
+        assert!(pool.page(7).unwrap().iter().all(|&b| b == 0));
+        pool.unpin(7).unwrap();
+    }
+
+    #[test]
+    fn dirty_page_written_back_on_eviction() {
+        let (_dir, mut pool) = pool(1);
+
+        pool.pin(0).unwrap();
+        pool.page_mut(0).unwrap()[0] = 42;
+        pool.unpin(0).unwrap();
+
+        // only one frame: page 0 goes out
+        pool.pin(1).unwrap();
+        pool.unpin(1).unwrap();
+        assert!(!pool.is_cached(0));
+
+        pool.pin(0).unwrap();
+        assert_eq!(pool.page(0).unwrap()[0], 42);
+        pool.unpin(0).unwrap();
+    }
+
+    #[test]
+    fn pinned_pages_are_not_evicted() {
+        let (_dir, mut pool) = pool(2);
+
+        pool.pin(0).unwrap();
+        pool.pin(1).unwrap();
+        pool.unpin(1).unwrap();
+
+        pool.pin(2).unwrap();
+        assert!(pool.is_cached(0));
+        assert!(!pool.is_cached(1));
+    }
+
+    #[test]
+    fn all_pinned_is_an_error() {
+        let (_dir, mut pool) = pool(2);
+
+        pool.pin(0).unwrap();
+        pool.pin(1).unwrap();
+
+        assert!(pool.pin(2).is_err());
+
+        pool.unpin(0).unwrap();
+        assert!(pool.pin(2).is_ok());
+    }
+
+    #[test]
+    fn clock_gives_second_chance() {
+        let (_dir, mut pool) = pool(2);
+
+        for id in [0, 1] {
+            pool.pin(id).unwrap();
+            pool.unpin(id).unwrap();
+        }
+
+        // clears both bits, then evicts 0
+        pool.pin(2).unwrap();
+        pool.unpin(2).unwrap();
+        assert!(!pool.is_cached(0));
+
+        // 1 lost its bit on the first turn, 2 still has one
+        pool.pin(3).unwrap();
+        pool.unpin(3).unwrap();
+        assert!(!pool.is_cached(1));
+        assert!(pool.is_cached(2));
+    }
+
+    #[test]
+    fn flush_persists_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        {
+            let mut pool = BufferPool::open(&path, 4 * PAGE_SIZE).unwrap();
+            pool.pin(3).unwrap();
+            pool.page_mut(3).unwrap()[..5].copy_from_slice(b"hello");
+            pool.unpin(3).unwrap();
+            pool.flush().unwrap();
+        }
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * PAGE_SIZE as u64);
+
+        let mut pool = BufferPool::open(&path, 4 * PAGE_SIZE).unwrap();
+        pool.pin(3).unwrap();
+        assert_eq!(&pool.page(3).unwrap()[..5], b"hello");
+    }
+
+    #[test]
+    fn access_without_pin_is_an_error() {
+        let (_dir, mut pool) = pool(1);
+        assert!(pool.page(0).is_err());
+        assert!(pool.unpin(0).is_err());
+
+        pool.pin(0).unwrap();
+        pool.unpin(0).unwrap();
+        assert!(pool.page_mut(0).is_err());
+        assert!(pool.unpin(0).is_err());
+    }
+
+    #[test]
+    fn failed_write_back_keeps_the_page() {
+        let vfs = SimVfs::new(1);
+        let mut pool = BufferPool::open_in(&vfs, Path::new("/data.db"), PAGE_SIZE).unwrap();
+        pool.pin(0).unwrap();
+        pool.page_mut(0).unwrap()[0] = 42;
+        pool.unpin(0).unwrap();
+
+        vfs.set_faults(Faults { write_error: 1.0, sync_error: 0.0 });
+        assert!(pool.pin(1).is_err());
+        assert!(pool.is_cached(0));
+
+        vfs.set_faults(Faults::default());
+        pool.flush().unwrap();
+        pool.pin(1).unwrap();
+        pool.unpin(1).unwrap();
+        pool.pin(0).unwrap();
+        assert_eq!(pool.page(0).unwrap()[0], 42);
+    }
+}
@@ -0,0 +1,238 @@
+//! Change data capture: committed sets and deletes read back from the log
+use crate::core::batch::{self, WriteOp};
+use crate::core::binary_serializer::EntryKind;
+use crate::core::blob::{self, BlobManifest};
+use crate::core::db_lock::SyncMark;
+use crate::core::log_storage::{Log, LogOptions};
+use crate::error::KVError;
+use std::collections::VecDeque;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeOp {
+    Set(Vec<u8>),
+    Del,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub lsn: u64,
+    // micros since the epoch
+    pub ts: u64,
+    pub key: Vec<u8>,
+    pub op: ChangeOp,
+}
+
+// Tails the log through its own read-only handle, in lsn order. Catching
+// up ends with None; call again after more writes. A blob is delivered
+// once, as a Set with the whole value, when its BlobRef is read. The ops
+// of a batch come one by one with the batch's lsn.
+// Only records the writer has synced are delivered (its SyncMark): one
+// appended but not synced yet, or never if the sync failed, may still be
+// lost. A log no KV writer ever opened has no mark, all of it counts.
+pub struct Subscription {
+    log: Log,
+    mark: Option<SyncMark>,
+    // last mark read
+    synced: u64,
+    // next record to read
+    offset: u64,
+    // changes up to here are delivered (or skipped on resume)
+    after: u64,
+    // rest of a batch
+    pending: VecDeque<Change>,
+}
+
+impl Subscription {
+    // deliver changes with lsn > `after_lsn`; 0 = from the start.
+    // Resuming reads the log from the start up to there.
+    pub fn open(path: &Path, opts: LogOptions, after_lsn: u64) -> Result<Self, KVError> {
+        let mark = SyncMark::open(&*opts.vfs, path)?;
+        let log = Log::open_with(path, LogOptions { read_only: true, ..opts })?;
+        let offset = log.data_start();
+        Ok(Subscription {
+            log,
+            mark,
+            synced: 0,
+            offset,
+            after: after_lsn,
+            pending: VecDeque::new(),
+        })
+    }
+
+    // lsn of the last change delivered: store it to resume from there.
+    // A batch counts once all its changes are: resuming in the middle of
+    // one delivers all of it again.
+    pub fn last_lsn(&self) -> u64 {
+        self.after
+    }
+
+    // the changes of one record: a single one, or all ops of a batch
+    pub fn next_commit(&mut self) -> Result<Option<Vec<Change>>, KVError> {
+        let Some(first) = self.next_change()? else { return Ok(None) };
+        let mut changes = vec![first];
+        while !self.pending.is_empty() {
+            changes.extend(self.next_change()?);
+        }
+        Ok(Some(changes))
+    }
+
+    pub fn next_change(&mut self) -> Result<Option<Change>, KVError> {
+        if let Some(change) = self.pending.pop_front() {
+            if self.pending.is_empty() {
+                self.after = change.lsn;
+            }
+            return Ok(Some(change));
+        }
+
+        loop {
+            // a record being written is not read yet: start over at it next time
+            self.log.seek(self.offset)?;
+            let Some(entry) = self.log.read()? else { return Ok(None) };
+            let end = self.log.position()?;
+            if !self.is_synced(end)? {
+                return Ok(None);
+            }
+            self.offset = end;
+
+            if entry.lsn() <= self.after {
+                continue;
+            }
+            let op = match entry.kind() {
+                EntryKind::Value => ChangeOp::Set(entry.value().to_vec()),
+                EntryKind::Tombstone => ChangeOp::Del,
+                // part of a blob, or left over from an interrupted set_blob
+                EntryKind::BlobChunk => continue,
+                EntryKind::BlobRef => {
+                    let manifest = BlobManifest::decode(&mut entry.value())?;
+                    ChangeOp::Set(blob::read_blob(&self.log, entry.key(), &manifest)?)
+                }
+                EntryKind::Batch => {
+                    let (lsn, ts) = (entry.lsn(), entry.timestamp());
+                    self.pending = batch::decode_batch(entry.value())?
+                        .into_iter()
+                        .map(|op| match op {
+                            WriteOp::Set(key, val) => Change { lsn, ts, key, op: ChangeOp::Set(val) },
+                            WriteOp::Del(key) => Change { lsn, ts, key, op: ChangeOp::Del },
+                        })
+                        .collect();
+                    return self.next_change();
+                }
+            };
+
+            self.after = entry.lsn();
+            let (lsn, ts) = (entry.lsn(), entry.timestamp());
+            let (key, _) = entry.into_parts();
+            return Ok(Some(Change { lsn, ts, key, op }));
+        }
+    }
+
+    // the mark is read again only when a record would pass the last one
+    fn is_synced(&mut self, end: u64) -> Result<bool, KVError> {
+        let Some(mark) = &self.mark else { return Ok(true) };
+        if end > self.synced {
+            match mark.get()? {
+                Some(synced) => self.synced = synced,
+                None => return Ok(true),
+            }
+        }
+        Ok(end <= self.synced)
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Change, KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_change().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::binary_serializer::Entry;
+    use crate::core::db_lock::DbLock;
+    use crate::core::vfs::StdVfs;
+
+    #[test]
+    fn tails_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let mut log = Log::open(&path).unwrap();
+        log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+
+        let mut sub = Subscription::open(&path, LogOptions::default(), 0).unwrap();
+        let change = sub.next_change().unwrap().unwrap();
+        assert_eq!((change.lsn, change.key, change.op), (1, b"a".to_vec(), ChangeOp::Set(b"1".to_vec())));
+        assert!(sub.next_change().unwrap().is_none());
+
+        log.write(&mut Entry::tombstone(b"a".to_vec())).unwrap();
+        let change = sub.next_change().unwrap().unwrap();
+        assert_eq!((change.lsn, change.op), (2, ChangeOp::Del));
+        assert_eq!(sub.last_lsn(), 2);
+    }
+
+    #[test]
+    fn torn_record_is_read_once_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let mut log = Log::open(&path).unwrap();
+        let mut sub = Subscription::open(&path, LogOptions::default(), 0).unwrap();
+
+        let mut entry = Entry::new(b"k".to_vec(), b"value".to_vec());
+        let pos = log.write(&mut entry).unwrap();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(pos.offset + pos.len as u64 - 2).unwrap();
+        assert!(sub.next_change().unwrap().is_none());
+
+        // the writer finishes the record
+        file.set_len(pos.offset).unwrap();
+        Log::open(&path).unwrap().write(&mut entry).unwrap();
+        assert_eq!(sub.next_change().unwrap().unwrap().key, b"k");
+    }
+
+    #[test]
+    fn batch_ops_share_the_lsn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let mut log = Log::open(&path).unwrap();
+        let ops = [WriteOp::Set(b"a".to_vec(), b"1".to_vec()), WriteOp::Del(b"b".to_vec())];
+        log.write(&mut Entry::with_kind(Vec::new(), batch::encode_batch(&ops), EntryKind::Batch))
+            .unwrap();
+
+        let mut sub = Subscription::open(&path, LogOptions::default(), 0).unwrap();
+        let first = sub.next_change().unwrap().unwrap();
+        assert_eq!((first.lsn, first.op), (1, ChangeOp::Set(b"1".to_vec())));
+        // not all of the batch is out yet
+        assert_eq!(sub.last_lsn(), 0);
+        let second = sub.next_change().unwrap().unwrap();
+        assert_eq!((second.lsn, second.key, second.op), (1, b"b".to_vec(), ChangeOp::Del));
+        assert_eq!(sub.last_lsn(), 1);
+
+        let mut sub = Subscription::open(&path, LogOptions::default(), 0).unwrap();
+        let commit = sub.next_commit().unwrap().unwrap();
+        assert_eq!(commit.iter().map(|c| c.key.clone()).collect::<Vec<_>>(), [b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(sub.last_lsn(), 1);
+    }
+
+    #[test]
+    fn stops_at_the_sync_mark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+        let lock = DbLock::writer(&StdVfs, &path).unwrap();
+        let mut log = Log::open(&path).unwrap();
+        log.set_sync_mark(lock.sync_mark()).unwrap();
+        log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+
+        let mut sub = Subscription::open(&path, LogOptions::default(), 0).unwrap();
+        assert_eq!(sub.next_change().unwrap().unwrap().key, b"a");
+
+        // appended, not synced: a crash may still lose it
+        log.append(&mut Entry::new(b"b".to_vec(), b"2".to_vec())).unwrap();
+        assert!(sub.next_change().unwrap().is_none());
+
+        log.sync().unwrap();
+        assert_eq!(sub.next_change().unwrap().unwrap().key, b"b");
+    }
+}
@@ -0,0 +1,199 @@
+//! Randomized crash recovery: a db on a SimVfs checked against a map of
+//! what it should hold, through power cuts, torn writes and I/O errors
+use crate::core::batch::WriteOp;
+use crate::core::crypto::{Cipher, KEY_LEN};
+use crate::core::fsync::{Durability, SyncMode};
+use crate::core::key_dir::ValueMode;
+use crate::core::key_value::{KV, Options};
+use crate::core::sim_vfs::{Faults, Rng, SimVfs};
+use crate::core::vfs::SharedVfs;
+use crate::error::KVError;
+use std::collections::BTreeMap;
+
+const DB: &str = "/db/db.log";
+const SEEDS: u64 = 300;
+const STEPS: usize = 80;
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Debug)]
+enum Step {
+    Set(Vec<u8>, Vec<u8>),
+    Del(Vec<u8>),
+    Apply(Vec<WriteOp>),
+    Blob(Vec<u8>, Vec<u8>),
+    Snapshot,
+    Reopen,
+    // power loss with nothing in flight: all acknowledged writes survive
+    Crash,
+}
+
+fn options(vfs: &SimVfs, rng: &mut Rng) -> Options {
+    let value_mode = match rng.chance(0.5) {
+        true => ValueMode::Memory,
+        false => ValueMode::Disk,
+    };
+    Options {
+        value_mode,
+        snapshot_every: rng.chance(0.5).then(|| 1 + rng.below(8)),
+        blob_chunk_size: Some(8),
+        compress_above: rng.chance(0.3).then_some(16),
+        cipher: rng.chance(0.3).then(|| Cipher::new(1, &[7; KEY_LEN])),
+        vfs: SharedVfs::new(vfs.clone()),
+        durability: Durability {
+            sync: if rng.chance(0.5) { SyncMode::Full } else { SyncMode::Data },
+            preallocate: rng.chance(0.5).then(|| 1 + rng.below(256)),
+            direct_io: rng.chance(0.3),
+            ..Durability::default()
+        },
+        ..Options::default()
+    }
+}
+
+fn random_key(rng: &mut Rng) -> Vec<u8> {
+    format!("k{}", rng.below(12)).into_bytes()
+}
+
+fn random_val(rng: &mut Rng) -> Vec<u8> {
+    let byte = rng.below(256) as u8;
+    vec![byte; rng.below(40) as usize]
+}
+
+fn random_step(rng: &mut Rng) -> Step {
+    match rng.below(20) {
+        0..=7 => Step::Set(random_key(rng), random_val(rng)),
+        8..=10 => Step::Del(random_key(rng)),
+        11..=13 => {
+            let ops = (0..rng.below(5))
+                .map(|_| match rng.chance(0.7) {
+                    true => WriteOp::Set(random_key(rng), random_val(rng)),
+                    false => WriteOp::Del(random_key(rng)),
+                })
+                .collect();
+            Step::Apply(ops)
+        }
+        14..=15 => Step::Blob(random_key(rng), random_val(rng)),
+        16 => Step::Snapshot,
+        17..=18 => Step::Reopen,
+        _ => Step::Crash,
+    }
+}
+
+// the model once `step` is done
+fn expected(model: &Model, step: &Step) -> Model {
+    let mut model = model.clone();
+    let mut write = |op: &WriteOp| match op {
+        WriteOp::Set(key, val) => model.insert(key.clone(), val.clone()),
+        WriteOp::Del(key) => model.remove(key),
+    };
+    match step {
+        Step::Set(key, val) | Step::Blob(key, val) => drop(write(&WriteOp::Set(key.clone(), val.clone()))),
+        Step::Del(key) => drop(write(&WriteOp::Del(key.clone()))),
+        Step::Apply(ops) => ops.iter().for_each(|op| drop(write(op))),
+        Step::Snapshot | Step::Reopen | Step::Crash => {}
+    }
+    model
+}
+
+fn contents(kv: &KV) -> Model {
+    let keys: Vec<Vec<u8>> = kv.keys().map(|key| key.to_vec()).collect();
+    keys.into_iter()
+        .map(|key| {
+            let val = kv.get(&key).unwrap().expect("listed key has a value");
+            (key, val)
+        })
+        .collect()
+}
+
+struct Run {
+    vfs: SimVfs,
+    opts: Options,
+    kv: Option<KV>,
+}
+
+impl Run {
+    fn kv(&mut self) -> &mut KV {
+        self.kv.as_mut().expect("db is open")
+    }
+
+    fn step(&mut self, step: &Step) -> Result<(), KVError> {
+        match step {
+            Step::Set(key, val) => self.kv().set(key, val).map(drop),
+            Step::Del(key) => self.kv().del(key).map(drop),
+            Step::Apply(ops) => self.kv().apply(ops),
+            Step::Blob(key, val) => self.kv().set_blob(key, val.as_slice()).map(drop),
+            Step::Snapshot => self.kv().snapshot(),
+            Step::Reopen => {
+                let mut kv = self.kv.take().expect("db is open");
+                kv.close()?;
+                drop(kv);
+                self.kv = Some(KV::open_with(DB, self.opts.clone())?);
+                Ok(())
+            }
+            Step::Crash => {
+                self.crash();
+                Ok(())
+            }
+        }
+    }
+
+    // power back on, no more faults
+    fn crash(&mut self) {
+        self.kv = None;
+        self.vfs.set_faults(Faults::default());
+        self.vfs.crash();
+        self.kv = Some(KV::open_with(DB, self.opts.clone()).expect("recovery opens the db"));
+    }
+}
+
+fn run_seed(seed: u64) {
+    let vfs = SimVfs::new(seed);
+    let mut rng = Rng::new(seed ^ 0x5eed);
+    let opts = options(&vfs, &mut rng);
+    let kv = KV::open_with(DB, opts.clone()).unwrap();
+    let mut run = Run { vfs, opts, kv: Some(kv) };
+    let mut model = Model::new();
+
+    for i in 0..STEPS {
+        if rng.chance(0.05) {
+            run.vfs.cut_power_after(rng.below(12));
+        } else if rng.chance(0.05) {
+            run.vfs.set_faults(Faults { write_error: 0.1, sync_error: 0.1 });
+        }
+
+        let step = random_step(&mut rng);
+        let after = expected(&model, &step);
+        match run.step(&step) {
+            Ok(()) => {
+                model = after;
+                if let Step::Crash = step {
+                    assert_eq!(contents(run.kv()), model, "seed {seed}, step {i}: lost acknowledged writes");
+                }
+            }
+            Err(_) => {
+                if run.kv.as_ref().is_some_and(KV::is_poisoned) {
+                    let err = run.kv().set(b"k0", b"").unwrap_err();
+                    assert!(matches!(err, KVError::Poisoned(_)), "seed {seed}, step {i}: write after a failed fsync");
+                }
+                // in doubt: it either happened or did not, nothing else
+                run.crash();
+                let found = contents(run.kv());
+                assert!(
+                    found == model || found == after,
+                    "seed {seed}, step {i} {step:?}: recovered to neither the state before nor after",
+                );
+                model = found;
+            }
+        }
+    }
+
+    run.crash();
+    assert_eq!(contents(run.kv()), model, "seed {seed}: lost acknowledged writes");
+}
+
+#[test]
+fn recovers_from_random_crashes() {
+    for seed in 0..SEEDS {
+        run_seed(seed);
+    }
+}
@@ -0,0 +1,128 @@
+//! Encryption at rest
+use crate::error::KVError;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::io;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+// bytes seal() adds to the plaintext
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+// a fresh random XChaCha nonce per seal, stored in front of the ciphertext;
+// 24 bytes are enough that random nonces never repeat in practice
+pub const NONCE_RANDOM: u8 = 1;
+
+// XChaCha20-Poly1305 with an id, so a file can tell which key it needs
+#[derive(Clone)]
+pub struct Cipher {
+    key_id: u64,
+    aead: XChaCha20Poly1305,
+}
+
+// no key bytes in logs
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher").field("key_id", &self.key_id).finish()
+    }
+}
+
+impl Cipher {
+    pub fn new(key_id: u64, key: &[u8; KEY_LEN]) -> Self {
+        Cipher {
+            key_id,
+            aead: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    pub fn key_id(&self) -> u64 {
+        self.key_id
+    }
+
+    // | nonce | ciphertext | tag |
+    pub fn seal(&self, aad: &[u8], plain: &[u8]) -> Result<Vec<u8>, KVError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        random_bytes(&mut nonce)?;
+
+        let sealed = self
+            .aead
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plain, aad })
+            .map_err(|_| KVError::Io(io::Error::other("encryption failed")))?;
+
+        let mut out = Vec::with_capacity(NONCE_LEN + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    // a wrong tag means the bytes were changed on purpose or sealed with
+    // another key: accidental damage is caught by the crc before this
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, KVError> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(KVError::Tampered("sealed data too short".into()));
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: data, aad })
+            .map_err(|_| KVError::Tampered("authentication failed".into()))
+    }
+}
+
+// getrandom(2), blocks only until the kernel pool is seeded once
+fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let n = unsafe { libc::getrandom(rest.as_mut_ptr() as *mut libc::c_void, rest.len(), 0) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        filled += n as usize;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(id: u64) -> Cipher {
+        Cipher::new(id, &[id as u8; KEY_LEN])
+    }
+
+    #[test]
+    fn seal_then_open() {
+        let c = cipher(1);
+        let sealed = c.seal(b"aad", b"secret").unwrap();
+
+        assert_eq!(sealed.len(), 6 + SEAL_OVERHEAD);
+        assert_eq!(c.open(b"aad", &sealed).unwrap(), b"secret");
+
+        // nonces differ
+        assert_ne!(c.seal(b"aad", b"secret").unwrap(), sealed);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let c = cipher(1);
+        let mut sealed = c.seal(b"aad", b"secret").unwrap();
+
+        assert!(matches!(c.open(b"other", &sealed), Err(KVError::Tampered(_))));
+        assert!(matches!(cipher(2).open(b"aad", &sealed), Err(KVError::Tampered(_))));
+
+        sealed[NONCE_LEN] ^= 1;
+        assert!(matches!(c.open(b"aad", &sealed), Err(KVError::Tampered(_))));
+    }
+
+    #[test]
+    fn debug_hides_key() {
+        assert_eq!(format!("{:?}", cipher(3)), "Cipher { key_id: 3 }");
+    }
+}
@@ -0,0 +1,184 @@
+//! Lock file next to the log: one writer per db, readers alongside it
+use crate::core::vfs::{LockKind, OpenMode, Vfs, VfsFile};
+use crate::error::KVError;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// the byte writers lock: one writer at a time
+const WRITER: u64 = 0;
+// the byte readers share; the writer takes it only to rewrite the log
+const READERS: u64 = 1;
+
+// db.log -> db.log.LOCK: dbs sharing a directory do not lock each other
+pub fn lock_path(log_path: &Path) -> PathBuf {
+    let mut p = log_path.as_os_str().to_owned();
+    p.push(".LOCK");
+    PathBuf::from(p)
+}
+
+// Held for as long as the db is open; dropping it (and its SyncMark)
+// unlocks
+pub struct DbLock {
+    file: Arc<dyn VfsFile>,
+    path: PathBuf,
+}
+
+impl DbLock {
+    // the only writer, or Locked
+    pub fn writer(vfs: &dyn Vfs, log_path: &Path) -> Result<Self, KVError> {
+        let path = lock_path(log_path);
+        let file: Arc<dyn VfsFile> = vfs.open(&path, OpenMode::Write)?.into();
+        if !file.try_lock(WRITER, LockKind::Exclusive)? {
+            return Err(KVError::Locked(format!("{} is held by another writer", path.display())));
+        }
+        // marked now: readers of the raw log wait for the log to be opened
+        if file.len()? < 8 {
+            SyncMark { file: file.clone() }.set(0)?;
+        }
+        Ok(DbLock { file, path })
+    }
+
+    // Locked only while the writer rewrites the log. The first reader of a
+    // db creates the lock file, so a writer starting later sees it. None
+    // only where it cannot be created: nobody can replace the log there.
+    // A log that does not exist is NotFound.
+    pub fn reader(vfs: &dyn Vfs, log_path: &Path) -> Result<Option<Self>, KVError> {
+        if !vfs.exists(log_path) {
+            let msg = format!("{}: no such log", log_path.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+        }
+        let path = lock_path(log_path);
+        let file: Arc<dyn VfsFile> = match vfs.open(&path, OpenMode::Read) {
+            Ok(file) => file.into(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match vfs.open(&path, OpenMode::Write) {
+                Ok(file) => file.into(),
+                Err(e) if matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem) => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            },
+            Err(e) => return Err(e.into()),
+        };
+        if !file.try_lock(READERS, LockKind::Shared)? {
+            return Err(KVError::Locked(format!("{}: the log is being rewritten", path.display())));
+        }
+        Ok(Some(DbLock { file, path }))
+    }
+
+    // writer only: no reader may have the log open while it is replaced
+    pub fn exclude_readers(&self) -> Result<(), KVError> {
+        if !self.file.try_lock(READERS, LockKind::Exclusive)? {
+            return Err(KVError::Locked(format!("{} is held by readers", self.path.display())));
+        }
+        Ok(())
+    }
+
+    pub fn admit_readers(&self) -> Result<(), KVError> {
+        Ok(self.file.unlock(READERS)?)
+    }
+
+    // writer only: where its log is synced up to
+    pub fn sync_mark(&self) -> SyncMark {
+        SyncMark { file: self.file.clone() }
+    }
+}
+
+// The first 8 bytes of the lock file: the writer's log is durable up to
+// this offset. Readers of the raw log (cdc) stop there, past it a record
+// may still be lost. Locks are advisory, they do not keep anyone from
+// reading it.
+pub struct SyncMark {
+    file: Arc<dyn VfsFile>,
+}
+
+impl SyncMark {
+    // None without a lock file: no writer ever marked this log
+    pub fn open(vfs: &dyn Vfs, log_path: &Path) -> Result<Option<Self>, KVError> {
+        match vfs.open(&lock_path(log_path), OpenMode::Read) {
+            Ok(file) => Ok(Some(SyncMark { file: file.into() })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // None if no writer ever set it: a lock file only readers made
+    pub fn get(&self) -> Result<Option<u64>, KVError> {
+        let mut buf = [0u8; 8];
+        match self.file.read_exact_at(&mut buf, 0) {
+            Ok(()) => Ok(Some(u64::from_le_bytes(buf))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // not synced itself: a crash loses only what the next writer marks again
+    pub fn set(&self, offset: u64) -> Result<(), KVError> {
+        Ok(self.file.write_all_at(&offset.to_le_bytes(), 0)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::vfs::StdVfs;
+
+    #[test]
+    fn one_writer_many_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("db.log");
+
+        assert!(matches!(DbLock::reader(&StdVfs, &log), Err(KVError::Io(_))));
+        std::fs::write(&log, b"").unwrap();
+        let writer = DbLock::writer(&StdVfs, &log).unwrap();
+        assert!(matches!(DbLock::writer(&StdVfs, &log), Err(KVError::Locked(_))));
+        // another db in the same directory
+        DbLock::writer(&StdVfs, &dir.path().join("other.log")).unwrap();
+
+        let reader = DbLock::reader(&StdVfs, &log).unwrap().unwrap();
+        let second = DbLock::reader(&StdVfs, &log).unwrap().unwrap();
+        assert!(matches!(writer.exclude_readers(), Err(KVError::Locked(_))));
+        drop((reader, second));
+
+        writer.exclude_readers().unwrap();
+        assert!(matches!(DbLock::reader(&StdVfs, &log), Err(KVError::Locked(_))));
+        writer.admit_readers().unwrap();
+        DbLock::reader(&StdVfs, &log).unwrap().unwrap();
+
+        drop(writer);
+        DbLock::writer(&StdVfs, &log).unwrap();
+    }
+
+    #[test]
+    fn first_reader_creates_the_lock_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("db.log");
+        std::fs::write(&log, b"").unwrap();
+
+        let reader = DbLock::reader(&StdVfs, &log).unwrap().unwrap();
+        assert!(lock_path(&log).exists());
+        // a writer coming later may not rewrite the log under it
+        let writer = DbLock::writer(&StdVfs, &log).unwrap();
+        assert!(matches!(writer.exclude_readers(), Err(KVError::Locked(_))));
+        drop(reader);
+        writer.exclude_readers().unwrap();
+    }
+
+    #[test]
+    fn readers_see_the_sync_mark() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("db.log");
+        assert!(SyncMark::open(&StdVfs, &log).unwrap().is_none());
+
+        std::fs::write(&log, b"").unwrap();
+        let reader = DbLock::reader(&StdVfs, &log).unwrap().unwrap();
+        let mark = SyncMark::open(&StdVfs, &log).unwrap().unwrap();
+        assert_eq!(mark.get().unwrap(), None);
+        drop(reader);
+
+        let writer = DbLock::writer(&StdVfs, &log).unwrap();
+        assert_eq!(mark.get().unwrap(), Some(0));
+        writer.sync_mark().set(123).unwrap();
+        assert_eq!(mark.get().unwrap(), Some(123));
+    }
+}
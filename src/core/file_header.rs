@@ -0,0 +1,186 @@
+//! Log file header
+use crate::core::crypto::{Cipher, NONCE_RANDOM};
+use crate::error::KVError;
+use crc32fast::Hasher;
+
+const MAGIC: &[u8; 8] = b"SILLYLOG";
+
+// 1: no header, | crc | key_len | val_len | deleted | key | val |
+// 2: this header; the deleted byte became the record kind with flag bits
+// 3: | crc | key_len | val_len | kind | lsn | ts | key | val |
+pub const VERSION: u16 = 3;
+
+// | magic | version | features | key_id | nonce_scheme | crc32 |
+// | 8     | 2       | 4        | 8      | 1            | 4     |
+pub const HEADER_LEN: usize = 8 + 2 + 4 + 8 + 1 + 4;
+
+// features a reader must support to read the file; compression is not one:
+// it is flagged per record and every reader since version 2 understands it
+pub const FEATURE_ENCRYPTED: u32 = 1;
+const KNOWN_FEATURES: u32 = FEATURE_ENCRYPTED;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileHeader {
+    pub version: u16,
+    pub features: u32,
+    // only with FEATURE_ENCRYPTED, 0 otherwise
+    pub key_id: u64,
+    pub nonce_scheme: u8,
+}
+
+impl FileHeader {
+    // header for a new log
+    pub fn new(cipher: Option<&Cipher>) -> Self {
+        match cipher {
+            Some(cipher) => FileHeader {
+                version: VERSION,
+                features: FEATURE_ENCRYPTED,
+                key_id: cipher.key_id(),
+                nonce_scheme: NONCE_RANDOM,
+            },
+            None => FileHeader {
+                version: VERSION,
+                features: 0,
+                key_id: 0,
+                nonce_scheme: 0,
+            },
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&self.features.to_le_bytes());
+        buf.extend_from_slice(&self.key_id.to_le_bytes());
+        buf.push(self.nonce_scheme);
+
+        let mut hasher = Hasher::new();
+        hasher.update(&buf);
+        buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+        buf
+    }
+
+    // Unsupported for files from a newer version, Corruption for damage
+    pub fn decode(data: &[u8]) -> Result<Self, KVError> {
+        if data.len() < HEADER_LEN || !has_magic(data) {
+            return Err(KVError::corruption("no log header"));
+        }
+
+        let (body, crc) = data[..HEADER_LEN].split_at(HEADER_LEN - 4);
+        let mut hasher = Hasher::new();
+        hasher.update(body);
+        if hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(KVError::corruption("bad log header checksum"));
+        }
+
+        let header = FileHeader {
+            version: u16::from_le_bytes(body[8..10].try_into().unwrap()),
+            features: u32::from_le_bytes(body[10..14].try_into().unwrap()),
+            key_id: u64::from_le_bytes(body[14..22].try_into().unwrap()),
+            nonce_scheme: body[22],
+        };
+
+        // older versions are returned, Log::open asks for an upgrade
+        if header.version > VERSION || header.version < 2 {
+            return Err(KVError::Unsupported(format!("log version {}", header.version)));
+        }
+        let unknown = header.features & !KNOWN_FEATURES;
+        if unknown != 0 {
+            return Err(KVError::Unsupported(format!("log features {unknown:#x}")));
+        }
+        if header.encrypted() && header.nonce_scheme != NONCE_RANDOM {
+            return Err(KVError::Unsupported(format!("nonce scheme {}", header.nonce_scheme)));
+        }
+
+        Ok(header)
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.features & FEATURE_ENCRYPTED != 0
+    }
+
+    // the file and the caller must agree on encryption and the key
+    pub fn check_key(&self, cipher: Option<&Cipher>) -> Result<(), KVError> {
+        match (self.encrypted(), cipher) {
+            (false, None) => Ok(()),
+            (false, Some(_)) => Err(KVError::KeyMismatch("log is not encrypted".into())),
+            (true, None) => Err(KVError::KeyMismatch("log is encrypted, no key given".into())),
+            (true, Some(cipher)) if cipher.key_id() != self.key_id => {
+                Err(KVError::KeyMismatch(format!(
+                    "log needs key {}, got key {}",
+                    self.key_id,
+                    cipher.key_id()
+                )))
+            }
+            (true, Some(_)) => Ok(()),
+        }
+    }
+}
+
+pub fn has_magic(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// a header cut short while creating the file: nothing after it was written
+pub fn is_torn_header(data: &[u8]) -> bool {
+    data.len() < HEADER_LEN && (MAGIC.starts_with(data) || has_magic(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_then_decode() {
+        let cipher = Cipher::new(7, &[1; 32]);
+        for header in [FileHeader::new(None), FileHeader::new(Some(&cipher))] {
+            let data = header.encode();
+            assert_eq!(data.len(), HEADER_LEN);
+            assert_eq!(FileHeader::decode(&data).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn newer_files_are_unsupported() {
+        let mut header = FileHeader::new(None);
+        header.version = VERSION + 1;
+        let err = FileHeader::decode(&header.encode()).err().unwrap();
+        assert!(matches!(err, KVError::Unsupported(_)));
+
+        let mut header = FileHeader::new(None);
+        header.features = 1 << 5;
+        let err = FileHeader::decode(&header.encode()).err().unwrap();
+        assert!(matches!(err, KVError::Unsupported(_)));
+    }
+
+    #[test]
+    fn damaged_header_is_corruption() {
+        let mut data = FileHeader::new(None).encode();
+        data[9] ^= 1;
+        assert!(matches!(FileHeader::decode(&data), Err(KVError::Corruption(_))));
+    }
+
+    #[test]
+    fn key_checks() {
+        let plain = FileHeader::new(None);
+        let cipher = Cipher::new(7, &[1; 32]);
+        let encrypted = FileHeader::new(Some(&cipher));
+
+        assert!(plain.check_key(None).is_ok());
+        assert!(encrypted.check_key(Some(&cipher)).is_ok());
+        assert!(plain.check_key(Some(&cipher)).is_err());
+        assert!(encrypted.check_key(None).is_err());
+        assert!(encrypted.check_key(Some(&Cipher::new(8, &[1; 32]))).is_err());
+    }
+
+    #[test]
+    fn torn_header() {
+        let data = FileHeader::new(None).encode();
+        assert!(is_torn_header(&data[..0]));
+        assert!(is_torn_header(&data[..5]));
+        assert!(is_torn_header(&data[..20]));
+        assert!(!is_torn_header(&data));
+        assert!(!is_torn_header(b"legacy"));
+    }
+}
@@ -1,11 +1,11 @@
 //! fsync
-use std::fs::{File, OpenOptions};
+use crate::core::vfs::{OpenMode, Vfs, VfsFile};
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use libc::{open, fsync, close, O_DIRECTORY, O_RDONLY};
 
-fn sync_dir(path: &Path) -> io::Result<()> {
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
     let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
     let fd = unsafe { open(c_path.as_ptr(), O_RDONLY | O_DIRECTORY) };
     if fd < 0 {
@@ -19,16 +19,70 @@ fn sync_dir(path: &Path) -> io::Result<()> {
     Ok(())
 }
 
-pub fn create_file_sync(path: &Path) -> io::Result<File> {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)?;
+// "db.log".parent() is "", which open(2) rejects
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
 
-    if let Some(dir) = path.parent() {
-        sync_dir(dir)?;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    // fsync: data and all metadata
+    #[default]
+    Full,
+    // fdatasync: data and the size, not timestamps
+    Data,
+}
+
+// How a data file (the log) reaches the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Durability {
+    pub sync: SyncMode,
+    // grow the file this many zero bytes at a time with fallocate, so most
+    // appends change no metadata; the zeros read as the end of the log
+    pub preallocate: Option<u64>,
+    // O_DIRECT: skip the page cache; writes become whole aligned blocks
+    pub direct_io: bool,
+    // O_DSYNC: each write reaches the disk before it returns
+    pub write_through: bool,
+}
+
+impl Durability {
+    pub fn sync(&self, file: &dyn VfsFile) -> io::Result<()> {
+        match self.sync {
+            SyncMode::Full => file.sync(),
+            SyncMode::Data => file.sync_data(),
+        }
     }
+}
+
+pub fn create_file_sync(vfs: &dyn Vfs, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+    create_file_sync_with(vfs, path, &Durability::default())
+}
+
+pub fn create_file_sync_with(vfs: &dyn Vfs, path: &Path, durability: &Durability) -> io::Result<Box<dyn VfsFile>> {
+    let file = vfs.open_with(path, OpenMode::Write, durability)?;
+
+    vfs.sync_dir(parent_dir(path))?;
 
     Ok(file)
 }
+
+// write to tmp file, fsync, rename over target, fsync dir
+pub fn write_file_atomic(vfs: &dyn Vfs, path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+
+    let file = vfs.open(tmp, OpenMode::Truncate)?;
+    file.write_all_at(data, 0)?;
+    file.sync()?;
+
+    vfs.rename(tmp, path)?;
+
+    vfs.sync_dir(parent_dir(path))?;
+
+    Ok(())
+}
@@ -0,0 +1,403 @@
+//! Key directory (bitcask-style)
+use crate::core::binary_serializer::Stamp;
+use crate::core::blob::BlobManifest;
+use crate::core::crypto::Cipher;
+use crate::core::fsync::write_file_atomic;
+use crate::core::vfs::Vfs;
+use crate::core::log_storage::RecordPos;
+use crate::error::KVError;
+use crc32fast::Hasher;
+use std::collections::btree_map::{self, BTreeMap};
+use std::io;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+const HINT_MAGIC: &[u8; 8] = b"SDBHINT3";
+
+const SLOT_INLINE: u8 = 0;
+const SLOT_ON_DISK: u8 = 1;
+const SLOT_BLOB: u8 = 2;
+const SLOT_BATCHED: u8 = 3;
+
+// where values live while the db is open
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ValueMode {
+    // whole values in RAM
+    #[default]
+    Memory,
+    // only record positions in RAM, values are read from the log
+    Disk,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Slot {
+    Inline(Vec<u8>),
+    OnDisk(RecordPos),
+    // in both modes: blobs are never kept in RAM
+    Blob(BlobManifest),
+    // Disk mode: the value is op `index` of a Batch record
+    Batched { pos: RecordPos, index: u32 },
+}
+
+impl Slot {
+    // | kind | Inline: len(4) bytes | OnDisk: offset(8) len(4) | Blob: manifest |
+    // Batched: offset(8) len(4) index(4)
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Slot::Inline(val) => {
+                out.push(SLOT_INLINE);
+                out.extend_from_slice(&(val.len() as u32).to_le_bytes());
+                out.extend_from_slice(val);
+            }
+            Slot::OnDisk(pos) => {
+                out.push(SLOT_ON_DISK);
+                out.extend_from_slice(&pos.offset.to_le_bytes());
+                out.extend_from_slice(&pos.len.to_le_bytes());
+            }
+            Slot::Blob(manifest) => {
+                out.push(SLOT_BLOB);
+                manifest.encode(out);
+            }
+            Slot::Batched { pos, index } => {
+                out.push(SLOT_BATCHED);
+                out.extend_from_slice(&pos.offset.to_le_bytes());
+                out.extend_from_slice(&pos.len.to_le_bytes());
+                out.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+    }
+
+    // advances `data` past the slot, None if damaged
+    pub fn decode(data: &mut &[u8]) -> Option<Self> {
+        let (&kind, rest) = data.split_first()?;
+        *data = rest;
+
+        match kind {
+            SLOT_INLINE => {
+                let len = u32::from_le_bytes(data.get(0..4)?.try_into().unwrap()) as usize;
+                let val = data.get(4..4 + len)?.to_vec();
+                *data = &data[4 + len..];
+                Some(Slot::Inline(val))
+            }
+            SLOT_ON_DISK => {
+                let raw = data.get(0..12)?;
+                let pos = RecordPos {
+                    offset: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+                    len: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+                };
+                *data = &data[12..];
+                Some(Slot::OnDisk(pos))
+            }
+            SLOT_BLOB => BlobManifest::decode(data).ok().map(Slot::Blob),
+            SLOT_BATCHED => {
+                let raw = data.get(0..16)?;
+                let pos = RecordPos {
+                    offset: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+                    len: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+                };
+                let index = u32::from_le_bytes(raw[12..16].try_into().unwrap());
+                *data = &data[16..];
+                Some(Slot::Batched { pos, index })
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct KeyDir {
+    mode: ValueMode,
+    // ordered, so a scan walks only its range
+    map: BTreeMap<Vec<u8>, Slot>,
+}
+
+impl KeyDir {
+    pub fn new(mode: ValueMode) -> Self {
+        KeyDir {
+            mode,
+            map: BTreeMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> ValueMode {
+        self.mode
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Slot> {
+        self.map.get(key)
+    }
+
+    // keeps the value or only its position, depending on the mode
+    pub fn insert(&mut self, key: &[u8], val: &[u8], pos: RecordPos) {
+        let slot = match self.mode {
+            ValueMode::Memory => Slot::Inline(val.to_vec()),
+            ValueMode::Disk => Slot::OnDisk(pos),
+        };
+        self.map.insert(key.to_vec(), slot);
+    }
+
+    // one value of a Batch record at `pos`
+    pub fn insert_batched(&mut self, key: &[u8], val: &[u8], pos: RecordPos, index: u32) {
+        let slot = match self.mode {
+            ValueMode::Memory => Slot::Inline(val.to_vec()),
+            ValueMode::Disk => Slot::Batched { pos, index },
+        };
+        self.map.insert(key.to_vec(), slot);
+    }
+
+    pub fn insert_blob(&mut self, key: &[u8], manifest: BlobManifest) {
+        self.map.insert(key.to_vec(), Slot::Blob(manifest));
+    }
+
+    // slot loaded from a snapshot or hint
+    pub fn insert_slot(&mut self, key: Vec<u8>, slot: Slot) {
+        self.map.insert(key, slot);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.map.remove(key);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Slot)> {
+        self.map.iter()
+    }
+
+    pub fn range(&self, range: impl RangeBounds<Vec<u8>>) -> btree_map::Range<'_, Vec<u8>, Slot> {
+        self.map.range(range)
+    }
+}
+
+// hint file: the key directory of a Disk mode db, so open can skip the log
+// | magic | covered | lsn | ts | count | (key_len | key | slot)* | crc32 |
+// | 8     | 8       | 8   | 8  | 8     | 4        | ... | ...   | 4     |
+pub struct Hint {
+    pub dir: KeyDir,
+    // log offset up to which the hint is complete
+    pub covered: u64,
+    // stamp of the last record before `covered`
+    pub stamp: Stamp,
+}
+
+pub fn hint_path(log_path: &Path) -> PathBuf {
+    let mut p = log_path.as_os_str().to_owned();
+    p.push(".hint");
+    PathBuf::from(p)
+}
+
+// with a cipher the file is sealed like a snapshot
+pub fn write_hint(
+    vfs: &dyn Vfs,
+    path: &Path,
+    dir: &KeyDir,
+    covered: u64,
+    stamp: Stamp,
+    cipher: Option<&Cipher>,
+) -> Result<(), KVError> {
+    if dir.mode() != ValueMode::Disk {
+        return Err(KVError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "hint needs a Disk mode key directory",
+        )));
+    }
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(HINT_MAGIC);
+    buf.extend_from_slice(&covered.to_le_bytes());
+    buf.extend_from_slice(&stamp.lsn.to_le_bytes());
+    buf.extend_from_slice(&stamp.ts.to_le_bytes());
+    buf.extend_from_slice(&(dir.len() as u64).to_le_bytes());
+
+    for (key, slot) in dir.iter() {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        slot.encode(&mut buf);
+    }
+
+    let mut hasher = Hasher::new();
+    hasher.update(&buf);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+    if let Some(cipher) = cipher {
+        buf = cipher.seal(HINT_MAGIC, &buf)?;
+    }
+    Ok(write_file_atomic(vfs, path, &buf)?)
+}
+
+// None if the hint is missing or damaged: caller falls back to full replay
+pub fn read_hint(vfs: &dyn Vfs, path: &Path, cipher: Option<&Cipher>) -> Result<Option<Hint>, KVError> {
+    let mut data = match vfs.read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(cipher) = cipher {
+        data = cipher.open(HINT_MAGIC, &data)?;
+    }
+
+    Ok(parse_hint(&data))
+}
+
+fn parse_hint(data: &[u8]) -> Option<Hint> {
+    if data.len() < HINT_MAGIC.len() + 8 + 16 + 8 + 4 {
+        return None;
+    }
+
+    let (body, crc) = data.split_at(data.len() - 4);
+    let mut hasher = Hasher::new();
+    hasher.update(body);
+    if hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return None;
+    }
+
+    if &body[..8] != HINT_MAGIC {
+        return None;
+    }
+    let covered = u64::from_le_bytes(body[8..16].try_into().unwrap());
+    let stamp = Stamp {
+        lsn: u64::from_le_bytes(body[16..24].try_into().unwrap()),
+        ts: u64::from_le_bytes(body[24..32].try_into().unwrap()),
+    };
+    let count = u64::from_le_bytes(body[32..40].try_into().unwrap());
+
+    let mut dir = KeyDir::new(ValueMode::Disk);
+    let mut rest = &body[40..];
+
+    for _ in 0..count {
+        let key_len = u32::from_le_bytes(rest.get(0..4)?.try_into().unwrap()) as usize;
+        let key = rest.get(4..4 + key_len)?.to_vec();
+        rest = &rest[4 + key_len..];
+
+        match Slot::decode(&mut rest)? {
+            Slot::Inline(_) => return None,
+            slot => dir.map.insert(key, slot),
+        };
+    }
+
+    if !rest.is_empty() {
+        return None;
+    }
+
+    Some(Hint { dir, covered, stamp })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::vfs::StdVfs;
+
+    fn pos(offset: u64, len: u32) -> RecordPos {
+        RecordPos { offset, len }
+    }
+
+    #[test]
+    fn mode_decides_what_is_kept() {
+        let mut mem = KeyDir::new(ValueMode::Memory);
+        let mut disk = KeyDir::new(ValueMode::Disk);
+
+        mem.insert(b"k", b"v", pos(0, 20));
+        disk.insert(b"k", b"v", pos(0, 20));
+
+        assert_eq!(mem.get(b"k"), Some(&Slot::Inline(b"v".to_vec())));
+        assert_eq!(disk.get(b"k"), Some(&Slot::OnDisk(pos(0, 20))));
+    }
+
+    #[test]
+    fn hint_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log.hint");
+
+        let mut keys = KeyDir::new(ValueMode::Disk);
+        keys.insert(b"a", b"", pos(0, 15));
+        keys.insert(b"bb", b"", pos(15, 17));
+
+        write_hint(&StdVfs, &path, &keys, 32, Stamp::default(), None).unwrap();
+        let Hint { dir: loaded, covered, .. } = read_hint(&StdVfs, &path, None).unwrap().unwrap();
+
+        assert_eq!(covered, 32);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(b"a"), Some(&Slot::OnDisk(pos(0, 15))));
+        assert_eq!(loaded.get(b"bb"), Some(&Slot::OnDisk(pos(15, 17))));
+    }
+
+    #[test]
+    fn hint_keeps_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log.hint");
+
+        let manifest = BlobManifest { len: 3, chunks: vec![pos(0, 16)] };
+        let mut keys = KeyDir::new(ValueMode::Disk);
+        keys.insert_blob(b"big", manifest.clone());
+
+        write_hint(&StdVfs, &path, &keys, 40, Stamp::default(), None).unwrap();
+        let loaded = read_hint(&StdVfs, &path, None).unwrap().unwrap().dir;
+        assert_eq!(loaded.get(b"big"), Some(&Slot::Blob(manifest)));
+    }
+
+    #[test]
+    fn slot_encode_decode() {
+        let slots = [
+            Slot::Inline(b"abc".to_vec()),
+            Slot::OnDisk(pos(7, 30)),
+            Slot::Blob(BlobManifest { len: 9, chunks: vec![pos(1, 2), pos(3, 4)] }),
+            Slot::Batched { pos: pos(40, 60), index: 2 },
+        ];
+
+        let mut buf = Vec::new();
+        for slot in &slots {
+            slot.encode(&mut buf);
+        }
+
+        let mut data = buf.as_slice();
+        for slot in &slots {
+            assert_eq!(&Slot::decode(&mut data).unwrap(), slot);
+        }
+        assert!(data.is_empty());
+        assert!(Slot::decode(&mut &buf[..3]).is_none());
+    }
+
+    #[test]
+    fn missing_hint_is_none() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log.hint");
+
+        assert!(read_hint(&StdVfs, &path, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn damaged_hint_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log.hint");
+
+        let mut keys = KeyDir::new(ValueMode::Disk);
+        keys.insert(b"a", b"", pos(0, 15));
+        write_hint(&StdVfs, &path, &keys, 15, Stamp::default(), None).unwrap();
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[30] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        assert!(read_hint(&StdVfs, &path, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn memory_dir_has_no_hint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log.hint");
+
+        let mut keys = KeyDir::new(ValueMode::Memory);
+        keys.insert(b"a", b"1", pos(0, 15));
+
+        assert!(write_hint(&StdVfs, &path, &keys, 15, Stamp::default(), None).is_err());
+    }
+}
@@ -1,102 +1,593 @@
 //! key value interface
-use crate::core::binary_serializer::Entry;
-use crate::core::log_storage::Log;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use crate::core::binary_serializer::{Entry, EntryKind, Limits, Stamp};
+use crate::core::batch::{self, WriteOp};
+use crate::core::blob::{self, BlobManifest, BlobReader, DEFAULT_BLOB_CHUNK_SIZE};
+use crate::core::cdc::Subscription;
+use crate::core::crypto::Cipher;
+use crate::core::db_lock::DbLock;
+use crate::core::fsync::Durability;
+use crate::core::key_dir::{self, KeyDir, Slot, ValueMode};
+use crate::core::kv_store;
+use crate::core::log_storage::{self, Log, LogOptions, RecordPos};
+use crate::core::mmap::MmapReader;
+use crate::core::snapshot::{self, Snapshot};
+use crate::core::vfs::SharedVfs;
+pub use crate::error::KVError;
+use std::collections::btree_map;
+use std::io::Read;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub struct KV {
-    log: Log,
-    mem: HashMap<Vec<u8>, Vec<u8>>,
+// where KV::open_at stops replaying the log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PointInTime {
+    // up to and including this lsn
+    Lsn(u64),
+    // records written at or before this time
+    Time(SystemTime),
+}
+
+impl PointInTime {
+    fn covers(&self, stamp: Stamp) -> bool {
+        match self {
+            PointInTime::Lsn(lsn) => stamp.lsn <= *lsn,
+            PointInTime::Time(time) => {
+                let micros = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+                stamp.ts <= micros
+            }
+        }
+    }
+}
+
+pub struct Scan<'a> {
+    kv: &'a KV,
+    slots: Option<btree_map::Range<'a, Vec<u8>, Slot>>,
 }
 
-#[derive(Debug)]
-pub enum KVError {
-    Io(std::io::Error),
+impl Iterator for Scan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, slot) = self.slots.as_mut()?.next()?;
+        Some(self.kv.slot_value(key, slot).map(|val| (key.clone(), val)))
+    }
 }
 
-impl From<std::io::Error> for KVError {
-    fn from(e: std::io::Error) -> Self {
-        KVError::Io(e)
+// the ops of a Batch record at `pos`, in order
+fn apply_batched(mem: &mut KeyDir, ops: &[WriteOp], pos: RecordPos) {
+    for (index, op) in ops.iter().enumerate() {
+        match op {
+            WriteOp::Set(key, val) => mem.insert_batched(key, val, pos, index as u32),
+            WriteOp::Del(key) => mem.remove(key),
+        }
     }
 }
 
+// unmapped log tail that makes set() map the log again
+const MMAP_REMAP_STEP: u64 = 1 << 20;
+
+pub struct KV {
+    log: Log,
+    mem: KeyDir,
+    mmap: Option<MmapReader>,
+    read_only: bool,
+    // held until the KV is dropped; None in memory
+    _lock: Option<DbLock>,
+    snapshot_every: Option<u64>,
+    writes_since_snapshot: u64,
+    blob_chunk_size: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub value_mode: ValueMode,
+    // take a snapshot (Disk mode: a hint) after this many writes
+    pub snapshot_every: Option<u64>,
+    // Disk mode: read values through a mapping of the log instead of pread
+    pub mmap_reads: bool,
+    pub limits: Limits,
+    // set_blob: bytes per chunk record, DEFAULT_BLOB_CHUNK_SIZE if None
+    pub blob_chunk_size: Option<usize>,
+    // lz4 for values of at least this many bytes; reads always decompress
+    pub compress_above: Option<usize>,
+    // encrypt the log, snapshots and hints; a new db takes the key id,
+    // an existing one must be opened with the same key
+    pub cipher: Option<Cipher>,
+    // where the log, snapshots and hints live; mmap_reads needs the real
+    // file system and is ignored on another
+    pub vfs: SharedVfs,
+    // how the log is synced and written; snapshots and hints always fsync
+    pub durability: Durability,
+}
+
 impl KV {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self, KVError> {
-        let mut log = Log::open(path)?;
-        let mut mem = HashMap::new();
+        Self::open_with(path, Options::default())
+    }
+
+    // everything on disk encrypted with `cipher`
+    pub fn open_encrypted(path: impl Into<PathBuf>, cipher: Cipher) -> Result<Self, KVError> {
+        Self::open_with(path, Options { cipher: Some(cipher), ..Options::default() })
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, opts: Options) -> Result<Self, KVError> {
+        Self::open_until(path.into(), opts, None)
+    }
+
+    // An empty db with its log in memory: no file is read or written, no
+    // snapshots or hints, nothing left once it is dropped. For tests.
+    pub fn open_in_memory() -> Result<Self, KVError> {
+        Self::open_in_memory_with(Options::default())
+    }
+
+    // mmap_reads is ignored
+    pub fn open_in_memory_with(opts: Options) -> Result<Self, KVError> {
+        let log = Log::open_in_memory(LogOptions {
+            limits: opts.limits,
+            compress_above: opts.compress_above,
+            cipher: opts.cipher.clone(),
+            read_only: false,
+            vfs: SharedVfs::default(),
+            durability: Durability::default(),
+        })?;
+        let mem = KeyDir::new(opts.value_mode);
+        Ok(Self::from_parts(log, mem, None, false, None, &opts))
+    }
+
+    // The db as it is now, only to look at: no file is written or
+    // truncated, none created but the lock file if no one made it yet;
+    // set/del/snapshot are ReadOnly. Runs next to a writer and
+    // sees what was written before it opened; reopen to see more.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self, KVError> {
+        Self::open_read_only_with(path, Options::default())
+    }
+
+    pub fn open_read_only_with(path: impl Into<PathBuf>, opts: Options) -> Result<Self, KVError> {
+        // a view as of the newest record there is
+        Self::open_until(path.into(), opts, Some(PointInTime::Lsn(u64::MAX)))
+    }
+
+    // Read-only view of the db as it was at `at`: the log is replayed up to
+    // there, a snapshot or hint is used only if it is older than that
+    pub fn open_at(path: impl Into<PathBuf>, at: PointInTime) -> Result<Self, KVError> {
+        Self::open_at_with(path, at, Options::default())
+    }
+
+    pub fn open_at_with(
+        path: impl Into<PathBuf>,
+        at: PointInTime,
+        opts: Options,
+    ) -> Result<Self, KVError> {
+        Self::open_until(path.into(), opts, Some(at))
+    }
+
+    fn open_until(path: PathBuf, opts: Options, until: Option<PointInTime>) -> Result<Self, KVError> {
+        let read_only = until.is_some();
+        let log_opts = LogOptions {
+            limits: opts.limits,
+            compress_above: opts.compress_above,
+            cipher: opts.cipher.clone(),
+            read_only,
+            vfs: opts.vfs.clone(),
+            durability: opts.durability,
+        };
+        let vfs = &*opts.vfs;
+        let usable = |stamp: Stamp| until.is_none_or(|at| at.covers(stamp));
+
+        // one writer; read-only views share theirs with the writer
+        let lock = match read_only {
+            false => Some(DbLock::writer(vfs, &path)?),
+            true => DbLock::reader(vfs, &path)?,
+        };
+
+        // a log of an older version is rewritten, what pointed into it is
+        // dropped; no view may be reading it meanwhile
+        if let Some(lock) = &lock
+            && !read_only
+            && log_storage::needs_upgrade(&path, &log_opts)?
+        {
+            lock.exclude_readers()?;
+            log_storage::upgrade(&path, &log_opts)?;
+            snapshot::remove_snapshots(vfs, &path)?;
+            let hint = key_dir::hint_path(&path);
+            if vfs.exists(&hint) {
+                vfs.remove_file(&hint)?;
+            }
+            lock.admit_readers()?;
+        }
+        let mut log = Log::open_with(&path, log_opts)?;
+        let mut mem = KeyDir::new(opts.value_mode);
+
+        // start from the latest snapshot (Disk mode: hint)
+        // and replay only the log after it
+        let log_len = log.file_len()?;
+        if opts.value_mode == ValueMode::Memory {
+            if let Some(snap) = snapshot::latest_snapshot(vfs, &path, log_len, opts.cipher.as_ref())?
+                && usable(snap.stamp)
+            {
+                for (key, slot) in snap.pairs {
+                    mem.insert_slot(key, slot);
+                }
+                log.seek(snap.covered)?;
+                log.observe_stamp(snap.stamp);
+            }
+        } else {
+            if let Some(hint) = key_dir::read_hint(vfs, &key_dir::hint_path(&path), opts.cipher.as_ref())?
+                && hint.covered <= log_len
+                && usable(hint.stamp)
+            {
+                mem = hint.dir;
+                log.seek(hint.covered)?;
+                log.observe_stamp(hint.stamp);
+            }
+        }
 
         // read WAL for EOF
-        while let Some(entry) = log.read()? {
-            if entry.is_deleted() {
-                mem.remove(entry.key());
-            } else {
-                mem.insert(entry.key().to_vec(), entry.value().to_vec());
+        let mut seen = log.last_stamp();
+        loop {
+            let offset = log.position()?;
+            let Some(entry) = log.read()? else { break };
+            let len = (log.position()? - offset) as u32;
+            if !usable(entry.stamp()) {
+                // the view ends before the record log.read() just stamped
+                log.rewind_stamp(seen);
+                break;
+            }
+            seen = entry.stamp();
+
+            match entry.kind() {
+                EntryKind::Value => mem.insert(entry.key(), entry.value(), RecordPos { offset, len }),
+                EntryKind::Tombstone => mem.remove(entry.key()),
+                // chunks without a BlobRef after them are left over from
+                // an interrupted set_blob
+                EntryKind::BlobChunk => {}
+                EntryKind::BlobRef => {
+                    let manifest = BlobManifest::decode(&mut entry.value())?;
+                    mem.insert_blob(entry.key(), manifest);
+                }
+                EntryKind::Batch => {
+                    let ops = batch::decode_batch(entry.value())?;
+                    apply_batched(&mut mem, &ops, RecordPos { offset, len });
+                }
             }
         }
 
-        Ok(KV { log, mem })
+        // the log is read to its end: cdc subscribers may go up to there
+        if let Some(lock) = &lock
+            && !read_only
+        {
+            log.set_sync_mark(lock.sync_mark())?;
+        }
+
+        let mmap = if opts.mmap_reads && opts.value_mode == ValueMode::Disk && vfs.supports_mmap() {
+            Some(MmapReader::open_with(&path, opts.limits, opts.cipher.clone())?)
+        } else {
+            None
+        };
+
+        Ok(Self::from_parts(log, mem, mmap, read_only, lock, &opts))
+    }
+
+    fn from_parts(
+        log: Log,
+        mem: KeyDir,
+        mmap: Option<MmapReader>,
+        read_only: bool,
+        lock: Option<DbLock>,
+        opts: &Options,
+    ) -> Self {
+        KV {
+            log,
+            mem,
+            mmap,
+            read_only,
+            _lock: lock,
+            snapshot_every: opts.snapshot_every,
+            writes_since_snapshot: 0,
+            blob_chunk_size: opts
+                .blob_chunk_size
+                .unwrap_or(DEFAULT_BLOB_CHUNK_SIZE)
+                .clamp(1, opts.limits.max_value_size.max(1)),
+        }
+    }
+
+    // log_storage::IN_MEMORY_PATH for an in-memory db
+    pub fn path(&self) -> &Path {
+        self.log.path()
+    }
+
+    pub fn is_in_memory(&self) -> bool {
+        self.log.is_in_memory()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // a log fsync failed: writes are Poisoned until the db is reopened,
+    // reads still work
+    pub fn is_poisoned(&self) -> bool {
+        self.log.is_poisoned()
     }
 
+    fn check_writable(&self) -> Result<(), KVError> {
+        if self.read_only {
+            return Err(KVError::ReadOnly);
+        }
+        // no more appends, and no snapshot of a state the log disagrees with
+        self.log.check_poisoned()
+    }
+
+    // Disk mode leaves a hint for the next open
     pub fn close(&mut self) -> Result<(), KVError> {
+        if self.mem.mode() == ValueMode::Disk && !self.read_only {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    // dump the current state so open can skip the log up to here
+    pub fn snapshot(&mut self) -> Result<(), KVError> {
+        self.check_writable()?;
+        if self.log.is_in_memory() {
+            // there is no next open
+            return Ok(());
+        }
+        let covered = self.log.position()?;
+        let stamp = self.log.last_stamp();
+
+        match self.mem.mode() {
+            ValueMode::Memory => {
+                let pairs = self
+                    .mem
+                    .iter()
+                    .map(|(key, slot)| match slot {
+                        Slot::OnDisk(_) | Slot::Batched { .. } => {
+                            Err(KVError::corruption("value on disk in a Memory mode keydir"))
+                        }
+                        slot => Ok((key.clone(), slot.clone())),
+                    })
+                    .collect::<Result<_, _>>()?;
+                let snap = Snapshot { covered, stamp, pairs };
+                snapshot::write_snapshot(&**self.log.vfs(), self.log.path(), &snap, self.log.cipher())?;
+            }
+            ValueMode::Disk => {
+                let path = key_dir::hint_path(self.log.path());
+                key_dir::write_hint(&**self.log.vfs(), &path, &self.mem, covered, stamp, self.log.cipher())?;
+            }
+        }
+
+        self.writes_since_snapshot = 0;
+        Ok(())
+    }
+
+    // lsn and time of the newest record: every set/del/set_blob is one
+    // record (a blob: one per chunk and its BlobRef), lsns have no gaps
+    pub fn last_stamp(&self) -> Stamp {
+        self.log.last_stamp()
+    }
+
+    pub fn last_lsn(&self) -> u64 {
+        self.log.last_stamp().lsn
+    }
+
+    fn after_write(&mut self) -> Result<(), KVError> {
+        self.writes_since_snapshot += 1;
+        if let Some(every) = self.snapshot_every
+            && self.writes_since_snapshot >= every
+        {
+            self.snapshot()?;
+        }
         Ok(())
     }
 
+    // blobs are read whole, use get_blob to stream them
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
-        Ok(self.mem.get(key).cloned())
+        match self.mem.get(key) {
+            None => Ok(None),
+            Some(slot) => Ok(Some(self.slot_value(key, slot)?)),
+        }
+    }
+
+    fn slot_value(&self, key: &[u8], slot: &Slot) -> Result<Vec<u8>, KVError> {
+        match slot {
+            Slot::Inline(val) => Ok(val.clone()),
+            Slot::OnDisk(pos) => self.read_value(*pos),
+            Slot::Batched { pos, index } => self.read_batched(*pos, *index),
+            Slot::Blob(manifest) => blob::read_blob(&self.log, key, manifest),
+        }
+    }
+
+    // any value as a stream; only blobs are read chunk by chunk
+    pub fn get_blob(&self, key: &[u8]) -> Result<Option<BlobReader<'_>>, KVError> {
+        let val = match self.mem.get(key) {
+            None => return Ok(None),
+            Some(Slot::Blob(manifest)) => return Ok(Some(BlobReader::new(&self.log, key, manifest))),
+            Some(Slot::Inline(val)) => val.clone(),
+            Some(Slot::OnDisk(pos)) => self.read_value(*pos)?,
+            Some(Slot::Batched { pos, index }) => self.read_batched(*pos, *index)?,
+        };
+        Ok(Some(BlobReader::from_value(&self.log, val)))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.mem.contains(key)
+    }
+
+    // in key order
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.mem.iter().map(|(key, _)| key.as_slice())
+    }
+
+    // pairs in key order; values are read as the iterator gets to them
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan<'_> {
+        let slots = (!kv_store::is_empty_range(&range)).then(|| self.mem.range(range));
+        Scan { kv: self, slots }
+    }
+
+    // committed changes with lsn > `after_lsn`, see Subscription
+    pub fn subscribe(&self, after_lsn: u64) -> Result<Subscription, KVError> {
+        if self.log.is_in_memory() {
+            return Err(KVError::Unsupported("subscribe to an in-memory db".into()));
+        }
+        let opts = LogOptions {
+            limits: self.log.limits(),
+            cipher: self.log.cipher().cloned(),
+            vfs: self.log.vfs().clone(),
+            ..LogOptions::default()
+        };
+        Subscription::open(self.log.path(), opts, after_lsn)
+    }
+
+    fn read_record(&self, pos: RecordPos) -> Result<Entry, KVError> {
+        // records past the mapping are read with pread
+        match self.mmap.as_ref().and_then(|m| m.entry_at(pos)) {
+            Some(entry) => entry,
+            None => self.log.read_at(pos),
+        }
+    }
+
+    fn read_value(&self, pos: RecordPos) -> Result<Vec<u8>, KVError> {
+        Ok(self.read_record(pos)?.value().to_vec())
+    }
+
+    fn read_batched(&self, pos: RecordPos, index: u32) -> Result<Vec<u8>, KVError> {
+        batch::batch_value(self.read_record(pos)?.value(), index)
     }
 
     pub fn set(&mut self, key: &[u8], val: &[u8]) -> Result<bool, KVError> {
-        let existed = self.mem.contains_key(key);
+        self.check_writable()?;
+        let existed = self.mem.contains(key);
+
+        let mut entry = Entry::new(key.to_vec(), val.to_vec());
+        let pos = self.log.write(&mut entry)?;
 
-        let entry = Entry::new(key.to_vec(), val.to_vec());
-        self.log.write(&entry)?;
+        self.mem.insert(key, val, pos);
 
-        self.mem.insert(key.to_vec(), val.to_vec());
+        self.remap_if_behind(pos)?;
+        self.after_write()?;
         Ok(existed)
     }
 
+    // All the writes or none: one Batch record, one fsync. Later ops win
+    // over earlier ones on the same key. The whole batch must fit the
+    // value size limit.
+    pub fn apply(&mut self, ops: &[WriteOp]) -> Result<(), KVError> {
+        self.check_writable()?;
+        let limits = self.log.limits();
+        for op in ops {
+            match op {
+                WriteOp::Set(key, val) => limits.check(key, val)?,
+                WriteOp::Del(key) => limits.check(key, &[])?,
+            }
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let mut entry = Entry::with_kind(Vec::new(), batch::encode_batch(ops), EntryKind::Batch);
+        let pos = self.log.write(&mut entry)?;
+        apply_batched(&mut self.mem, ops, pos);
+
+        self.remap_if_behind(pos)?;
+        self.after_write()?;
+        Ok(())
+    }
+
+    // Value of any size, read from `src` one chunk at a time: each chunk
+    // is a record of its own, then a BlobRef lists them. Only the BlobRef
+    // makes the blob visible, one fsync for the whole blob.
+    pub fn set_blob(&mut self, key: &[u8], mut src: impl Read) -> Result<bool, KVError> {
+        self.check_writable()?;
+        let existed = self.mem.contains(key);
+
+        let mut manifest = BlobManifest { len: 0, chunks: Vec::new() };
+        let mut buf = vec![0u8; self.blob_chunk_size];
+        loop {
+            let n = read_full(&mut src, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            let mut chunk = Entry::with_kind(key.to_vec(), buf[..n].to_vec(), EntryKind::BlobChunk);
+            manifest.chunks.push(self.log.append(&mut chunk)?);
+            manifest.len += n as u64;
+        }
+
+        let mut val = Vec::new();
+        manifest.encode(&mut val);
+        let pos = self.log.append(&mut Entry::with_kind(key.to_vec(), val, EntryKind::BlobRef))?;
+        self.log.sync()?;
+
+        self.mem.insert_blob(key, manifest);
+
+        self.remap_if_behind(pos)?;
+        self.after_write()?;
+        Ok(existed)
+    }
+
+    fn remap_if_behind(&mut self, pos: RecordPos) -> Result<(), KVError> {
+        if let Some(mmap) = &mut self.mmap
+            && (pos.offset + pos.len as u64).saturating_sub(mmap.len() as u64) >= MMAP_REMAP_STEP
+        {
+            mmap.remap()?;
+        }
+        Ok(())
+    }
+
     pub fn del(&mut self, key: &[u8]) -> Result<bool, KVError> {
-        let existed = self.mem.contains_key(key);
+        self.check_writable()?;
+        let existed = self.mem.contains(key);
 
         if existed {
-            let entry = Entry::tombstone(key.to_vec());
-            self.log.write(&entry)?;
+            self.log.write(&mut Entry::tombstone(key.to_vec()))?;
             self.mem.remove(key);
+            self.after_write()?;
         }
 
         Ok(existed)
     }
 }
 
+// fills `buf` unless `src` ends first
+fn read_full(src: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match src.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::file_header::HEADER_LEN;
+    use crate::core::fsync::SyncMode;
+    use crate::core::sim_vfs::{Faults, SimVfs};
+    use std::io::Write;
+    use std::ops::Bound;
 
     #[test]
     fn can_open_and_close() {
-        let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("db.log");
-
-        let mut kv = KV::open(&path).unwrap();
+        let mut kv = KV::open_in_memory().unwrap();
         kv.close().unwrap();
     }
 
     #[test]
     fn get_missing_key() {
-        let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("db.log");
-        
-        let kv = KV::open(&path).unwrap();
+        let kv = KV::open_in_memory().unwrap();
         let value = kv.get(b"missing").unwrap();
         assert!(value.is_none());
     }
 
     #[test]
     fn can_set_and_get() {
-        let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("db.log");
-        
-        let mut kv = KV::open(&path).unwrap();
+        let mut kv = KV::open_in_memory().unwrap();
 
         let updated = kv.set(b"key", b"value").unwrap();
         assert!(!updated);
@@ -107,10 +598,7 @@ mod tests {
 
     #[test]
     fn can_set_update_existing_key() {
-        let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("db.log");
-        
-        let mut kv = KV::open(&path).unwrap();
+        let mut kv = KV::open_in_memory().unwrap();
 
         kv.set(b"key", b"value1").unwrap();
         let updated = kv.set(b"key", b"value2").unwrap();
@@ -122,10 +610,7 @@ mod tests {
 
     #[test]
     fn can_delete_key() {
-        let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("db.log");
-        
-        let mut kv = KV::open(&path).unwrap();
+        let mut kv = KV::open_in_memory().unwrap();
 
         kv.set(b"key", b"value").unwrap();
 
@@ -138,10 +623,7 @@ mod tests {
 
     #[test]
     fn cant_delete_missing_key() {
-        let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("db.log");
-        
-        let mut kv = KV::open(&path).unwrap();
+        let mut kv = KV::open_in_memory().unwrap();
 
         let deleted = kv.del(b"maybe").unwrap();
         assert!(!deleted);
@@ -218,4 +700,871 @@ mod tests {
         let kv = KV::open(&path).unwrap();
         assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
     }
+
+    fn disk_opts() -> Options {
+        Options {
+            value_mode: ValueMode::Disk,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn disk_mode_set_get_del() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+
+        assert!(!kv.set(b"a", b"1").unwrap());
+        assert!(kv.set(b"a", b"2").unwrap());
+        kv.set(b"b", b"3").unwrap();
+        assert!(kv.del(b"b").unwrap());
+
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert!(kv.get(b"b").unwrap().is_none());
+        assert!(matches!(kv.mem.get(b"a"), Some(Slot::OnDisk(_))));
+    }
+
+    #[test]
+    fn disk_mode_replays_without_hint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.set(b"b", b"2").unwrap();
+            kv.del(b"a").unwrap();
+        }
+
+        assert!(!key_dir::hint_path(&path).exists());
+
+        let kv = KV::open_with(&path, disk_opts()).unwrap();
+        assert!(kv.get(b"a").unwrap().is_none());
+        assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn disk_mode_uses_hint_and_log_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.set(b"b", b"2").unwrap();
+            kv.close().unwrap();
+        }
+        assert!(key_dir::hint_path(&path).exists());
+
+        // writes after the hint, no close
+        {
+            let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+            kv.set(b"c", b"3").unwrap();
+            kv.del(b"a").unwrap();
+        }
+
+        let kv = KV::open_with(&path, disk_opts()).unwrap();
+        assert!(kv.get(b"a").unwrap().is_none());
+        assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(kv.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn hint_from_longer_log_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.close().unwrap();
+        }
+
+        // log replaced behind our back
+        std::fs::write(&path, b"").unwrap();
+
+        let kv = KV::open_with(&path, disk_opts()).unwrap();
+        assert!(kv.get(b"a").unwrap().is_none());
+    }
+
+    #[test]
+    fn open_loads_snapshot_and_log_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open(&path).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.set(b"b", b"2").unwrap();
+            kv.snapshot().unwrap();
+            kv.set(b"c", b"3").unwrap();
+            kv.del(b"a").unwrap();
+        }
+
+        let kv = KV::open(&path).unwrap();
+        assert!(kv.get(b"a").unwrap().is_none());
+        assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(kv.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn snapshot_skips_covered_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open(&path).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.snapshot().unwrap();
+        }
+
+        // covered part of the log is not read any more
+        let mut data = std::fs::read(&path).unwrap();
+        data[HEADER_LEN] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let kv = KV::open(&path).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn snapshot_of_an_inconsistent_keydir_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kv = KV::open(dir.path().join("db.log")).unwrap();
+        kv.set(b"a", b"1").unwrap();
+        kv.mem.insert_slot(b"b".to_vec(), Slot::OnDisk(RecordPos { offset: 0, len: 1 }));
+        assert!(matches!(kv.snapshot(), Err(KVError::Corruption(_))));
+    }
+
+    #[test]
+    fn periodic_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        let opts = Options {
+            snapshot_every: Some(2),
+            ..Options::default()
+        };
+
+        {
+            let mut kv = KV::open_with(&path, opts.clone()).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.set(b"b", b"2").unwrap();
+            kv.set(b"c", b"3").unwrap();
+        }
+
+        let records = std::fs::metadata(&path).unwrap().len() - HEADER_LEN as u64;
+        let covered = HEADER_LEN as u64 + records * 2 / 3;
+        assert!(snapshot::snapshot_path(&path, covered).exists());
+
+        let kv = KV::open_with(&path, opts).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(kv.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn disk_mode_with_mmap_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        let opts = Options {
+            value_mode: ValueMode::Disk,
+            mmap_reads: true,
+            ..Options::default()
+        };
+
+        {
+            let mut kv = KV::open_with(&path, opts.clone()).unwrap();
+            kv.set(b"a", b"1").unwrap();
+        }
+
+        let mut kv = KV::open_with(&path, opts).unwrap();
+        let log_len = || std::fs::metadata(&path).unwrap().len();
+        assert_eq!(kv.mmap.as_ref().unwrap().len() as u64, log_len());
+
+        // mapped record
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        // record past the mapping
+        kv.set(b"b", b"2").unwrap();
+        assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        // big write remaps
+        let big = vec![7u8; MMAP_REMAP_STEP as usize];
+        kv.set(b"c", &big).unwrap();
+        assert_eq!(kv.mmap.as_ref().unwrap().len() as u64, log_len());
+        assert_eq!(kv.get(b"c").unwrap(), Some(big));
+    }
+
+    #[test]
+    fn set_rejects_oversize() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        let opts = Options {
+            limits: Limits { max_key_size: 3, max_value_size: 5 },
+            ..Options::default()
+        };
+        let mut kv = KV::open_with(&path, opts).unwrap();
+
+        assert!(matches!(kv.set(b"long", b"v"), Err(KVError::KeyTooLarge { .. })));
+        assert!(matches!(kv.set(b"k", b"123456"), Err(KVError::ValueTooLarge { .. })));
+        assert!(kv.get(b"k").unwrap().is_none());
+
+        kv.set(b"k", b"12345").unwrap();
+        assert_eq!(kv.get(b"k").unwrap(), Some(b"12345".to_vec()));
+    }
+
+    fn blob_opts(value_mode: ValueMode) -> Options {
+        Options {
+            value_mode,
+            blob_chunk_size: Some(4),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn blob_round_trip_across_reopen() {
+        for mode in [ValueMode::Memory, ValueMode::Disk] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let data: Vec<u8> = (0..30u8).collect();
+
+            {
+                let mut kv = KV::open_with(&path, blob_opts(mode)).unwrap();
+                assert!(!kv.set_blob(b"big", data.as_slice()).unwrap());
+                assert_eq!(kv.get(b"big").unwrap(), Some(data.clone()));
+            }
+
+            let kv = KV::open_with(&path, blob_opts(mode)).unwrap();
+            let mut out = Vec::new();
+            kv.get_blob(b"big").unwrap().unwrap().read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+            assert!(matches!(kv.mem.get(b"big"), Some(Slot::Blob(m)) if m.chunks.len() == 8));
+        }
+    }
+
+    #[test]
+    fn blob_survives_snapshot_and_hint() {
+        for mode in [ValueMode::Memory, ValueMode::Disk] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+
+            {
+                let mut kv = KV::open_with(&path, blob_opts(mode)).unwrap();
+                kv.set_blob(b"big", &b"0123456789"[..]).unwrap();
+                kv.set(b"small", b"v").unwrap();
+                kv.snapshot().unwrap();
+            }
+
+            let kv = KV::open_with(&path, blob_opts(mode)).unwrap();
+            assert_eq!(kv.get(b"big").unwrap(), Some(b"0123456789".to_vec()));
+            assert_eq!(kv.get(b"small").unwrap(), Some(b"v".to_vec()));
+        }
+    }
+
+    #[test]
+    fn blob_overwrite_and_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open_with(&path, blob_opts(ValueMode::Memory)).unwrap();
+            kv.set_blob(b"a", &b"long blob"[..]).unwrap();
+            assert!(kv.set(b"a", b"plain").unwrap());
+            kv.set_blob(b"b", &b"other blob"[..]).unwrap();
+            assert!(kv.del(b"b").unwrap());
+        }
+
+        let kv = KV::open_with(&path, blob_opts(ValueMode::Memory)).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"plain".to_vec()));
+        assert!(kv.get(b"b").unwrap().is_none());
+
+        // plain values stream too
+        let mut out = String::new();
+        kv.get_blob(b"a").unwrap().unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "plain");
+    }
+
+    #[test]
+    fn interrupted_blob_is_invisible() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open_with(&path, blob_opts(ValueMode::Memory)).unwrap();
+            kv.set(b"a", b"1").unwrap();
+        }
+
+        // chunks made it to disk, the BlobRef did not
+        {
+            let mut log = Log::open(&path).unwrap();
+            let mut chunk = Entry::with_kind(b"big".to_vec(), b"part".to_vec(), EntryKind::BlobChunk);
+            log.write(&mut chunk).unwrap();
+        }
+
+        let kv = KV::open_with(&path, blob_opts(ValueMode::Memory)).unwrap();
+        assert!(kv.get(b"big").unwrap().is_none());
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn compressed_values_across_modes() {
+        for mode in [ValueMode::Memory, ValueMode::Disk] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let big = vec![b'x'; 10_000];
+
+            let opts = Options {
+                value_mode: mode,
+                compress_above: Some(64),
+                ..Options::default()
+            };
+            {
+                let mut kv = KV::open_with(&path, opts).unwrap();
+                kv.set(b"big", &big).unwrap();
+                kv.set(b"small", b"v").unwrap();
+                assert_eq!(kv.get(b"big").unwrap(), Some(big.clone()));
+            }
+            assert!(std::fs::metadata(&path).unwrap().len() < 1000);
+
+            // compression is per record, a reader without it still decodes
+            let kv = KV::open_with(&path, Options { value_mode: mode, ..Options::default() }).unwrap();
+            assert_eq!(kv.get(b"big").unwrap(), Some(big));
+            assert_eq!(kv.get(b"small").unwrap(), Some(b"v".to_vec()));
+        }
+    }
+
+    #[test]
+    fn encrypted_db_across_modes() {
+        for mode in [ValueMode::Memory, ValueMode::Disk] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let opts = Options {
+                value_mode: mode,
+                mmap_reads: true,
+                blob_chunk_size: Some(8),
+                cipher: Some(Cipher::new(3, &[42; 32])),
+                ..Options::default()
+            };
+
+            {
+                let mut kv = KV::open_with(&path, opts.clone()).unwrap();
+                kv.set(b"alice", b"alice@example.com").unwrap();
+                kv.set_blob(b"doc", &b"a long secret document"[..]).unwrap();
+                kv.snapshot().unwrap();
+                kv.set(b"bob", b"bob@example.com").unwrap();
+            }
+
+            // no plaintext anywhere in the directory
+            for file in std::fs::read_dir(dir.path()).unwrap() {
+                let data = std::fs::read(file.unwrap().path()).unwrap();
+                assert!(!data.windows(7).any(|w| w == b"example"));
+                assert!(!data.windows(6).any(|w| w == b"secret"));
+            }
+
+            let kv = KV::open_with(&path, opts).unwrap();
+            assert_eq!(kv.get(b"alice").unwrap(), Some(b"alice@example.com".to_vec()));
+            assert_eq!(kv.get(b"bob").unwrap(), Some(b"bob@example.com".to_vec()));
+            assert_eq!(kv.get(b"doc").unwrap(), Some(b"a long secret document".to_vec()));
+        }
+    }
+
+    #[test]
+    fn encrypted_db_needs_right_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        KV::open_encrypted(&path, Cipher::new(1, &[1; 32])).unwrap().set(b"a", b"1").unwrap();
+
+        assert!(matches!(KV::open(&path), Err(KVError::KeyMismatch(_))));
+        assert!(matches!(
+            KV::open_encrypted(&path, Cipher::new(2, &[1; 32])),
+            Err(KVError::KeyMismatch(_))
+        ));
+        // right id, wrong key bytes: the records do not authenticate
+        assert!(matches!(
+            KV::open_encrypted(&path, Cipher::new(1, &[2; 32])),
+            Err(KVError::Tampered(_))
+        ));
+    }
+
+    #[test]
+    fn open_upgrades_v1_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        // headerless log as written before the header existed:
+        // | crc | key_len | val_len | deleted | key | val |
+        let mut data = Vec::new();
+        for (key, val, deleted) in [(b"a", &b"1"[..], 0u8), (b"b", b"2", 0), (b"a", b"", 1)] {
+            let mut payload = Vec::new();
+            payload.extend_from_slice(&1u32.to_le_bytes());
+            payload.extend_from_slice(&(val.len() as u32).to_le_bytes());
+            payload.push(deleted);
+            payload.extend_from_slice(key);
+            payload.extend_from_slice(val);
+            data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            data.extend_from_slice(&payload);
+        }
+        std::fs::write(&path, &data).unwrap();
+
+        // a hint with offsets into the old file
+        std::fs::write(key_dir::hint_path(&path), b"stale").unwrap();
+
+        {
+            let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+            assert!(kv.get(b"a").unwrap().is_none());
+            assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+            kv.set(b"c", b"3").unwrap();
+        }
+        assert!(!key_dir::hint_path(&path).exists());
+
+        let kv = KV::open_with(&path, disk_opts()).unwrap();
+        assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(kv.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(std::fs::read(log_storage::backup_path(&path, 1)).unwrap(), data);
+    }
+
+    #[test]
+    fn open_leaves_a_foreign_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, b"shopping list: milk, eggs").unwrap();
+
+        assert!(matches!(KV::open(&path), Err(KVError::Unsupported(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), b"shopping list: milk, eggs");
+    }
+
+    #[test]
+    fn lsn_keeps_growing_across_reopen() {
+        for mode in [ValueMode::Memory, ValueMode::Disk] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let opts = Options { value_mode: mode, ..Options::default() };
+
+            let first;
+            {
+                let mut kv = KV::open_with(&path, opts.clone()).unwrap();
+                assert_eq!(kv.last_lsn(), 0);
+                kv.set(b"a", b"1").unwrap();
+                first = kv.last_stamp();
+                kv.del(b"a").unwrap();
+                assert_eq!(kv.last_lsn(), 2);
+                assert!(kv.last_stamp().ts >= first.ts);
+                kv.snapshot().unwrap();
+            }
+
+            // covered by the snapshot or hint, nothing left to replay
+            let mut kv = KV::open_with(&path, opts.clone()).unwrap();
+            assert_eq!(kv.last_lsn(), 2);
+            kv.set(b"b", b"2").unwrap();
+            assert_eq!(kv.last_lsn(), 3);
+            drop(kv);
+
+            // replayed from the log tail
+            let kv = KV::open_with(&path, opts).unwrap();
+            assert_eq!(kv.last_lsn(), 3);
+        }
+    }
+
+    #[test]
+    fn records_carry_their_stamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open(&path).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.set(b"b", b"2").unwrap();
+        }
+
+        let mut log = Log::open(&path).unwrap();
+        let e1 = log.read().unwrap().unwrap();
+        let e2 = log.read().unwrap().unwrap();
+        assert_eq!((e1.lsn(), e2.lsn()), (1, 2));
+        assert!(e1.timestamp() > 0 && e2.timestamp() >= e1.timestamp());
+    }
+
+    #[test]
+    fn open_at_lsn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open(&path).unwrap();
+            kv.set(b"a", b"1").unwrap(); // lsn 1
+            kv.set(b"a", b"2").unwrap(); // lsn 2
+            kv.snapshot().unwrap();
+            kv.del(b"a").unwrap(); // lsn 3
+            kv.set(b"b", b"oops").unwrap(); // lsn 4
+        }
+
+        let kv = KV::open_at(&path, PointInTime::Lsn(1)).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(kv.last_lsn(), 1);
+
+        // the snapshot covers exactly up to here
+        let kv = KV::open_at(&path, PointInTime::Lsn(2)).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+        let kv = KV::open_at(&path, PointInTime::Lsn(3)).unwrap();
+        assert!(kv.get(b"a").unwrap().is_none());
+        assert!(kv.get(b"b").unwrap().is_none());
+
+        let kv = KV::open_at(&path, PointInTime::Lsn(0)).unwrap();
+        assert!(kv.get(b"a").unwrap().is_none());
+    }
+
+    #[test]
+    fn open_at_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+        kv.set(b"a", b"good").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let before_bad_write = SystemTime::now();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        kv.set(b"a", b"bad").unwrap();
+        kv.close().unwrap();
+
+        let old = KV::open_at_with(&path, PointInTime::Time(before_bad_write), disk_opts()).unwrap();
+        assert_eq!(old.get(b"a").unwrap(), Some(b"good".to_vec()));
+
+        // restore the bad write from the old view
+        let good = old.get(b"a").unwrap().unwrap();
+        drop(old);
+        kv.set(b"a", &good).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"good".to_vec()));
+    }
+
+    #[test]
+    fn open_at_is_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        {
+            let mut kv = KV::open_with(&path, disk_opts()).unwrap();
+            kv.set(b"a", b"1").unwrap();
+        }
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let mut kv = KV::open_at_with(&path, PointInTime::Lsn(1), disk_opts()).unwrap();
+        assert!(kv.is_read_only());
+        assert!(matches!(kv.set(b"b", b"2"), Err(KVError::ReadOnly)));
+        assert!(matches!(kv.del(b"a"), Err(KVError::ReadOnly)));
+        assert!(matches!(kv.snapshot(), Err(KVError::ReadOnly)));
+        kv.close().unwrap();
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert!(!key_dir::hint_path(&path).exists());
+
+        // does not create a db
+        let missing = dir.path().join("missing.log");
+        assert!(KV::open_at(&missing, PointInTime::Lsn(1)).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn subscribe_sees_every_mode_and_blobs() {
+        use crate::core::cdc::ChangeOp;
+
+        for opts in [Options::default(), disk_opts()] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let mut kv = KV::open_with(&path, Options { blob_chunk_size: Some(4), ..opts }).unwrap();
+
+            let mut sub = kv.subscribe(0).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.set_blob(b"b", &b"0123456789"[..]).unwrap();
+            kv.del(b"a").unwrap();
+
+            let changes: Vec<_> = sub.by_ref().map(|c| c.unwrap()).collect();
+            let ops: Vec<_> = changes.iter().map(|c| (c.key.as_slice(), &c.op)).collect();
+            assert_eq!(
+                ops,
+                [
+                    (&b"a"[..], &ChangeOp::Set(b"1".to_vec())),
+                    (b"b", &ChangeOp::Set(b"0123456789".to_vec())),
+                    (b"a", &ChangeOp::Del),
+                ]
+            );
+            assert!(changes.windows(2).all(|w| w[0].lsn < w[1].lsn));
+            assert_eq!(sub.last_lsn(), kv.last_lsn());
+        }
+    }
+
+    #[test]
+    fn subscribe_resumes_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+
+        let stored = {
+            let mut kv = KV::open(&path).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            let mut sub = kv.subscribe(0).unwrap();
+            assert_eq!(sub.next_change().unwrap().unwrap().key, b"a");
+            kv.set(b"b", b"2").unwrap();
+            kv.snapshot().unwrap();
+            sub.last_lsn()
+        };
+
+        let mut kv = KV::open(&path).unwrap();
+        kv.set(b"c", b"3").unwrap();
+        let keys: Vec<_> = kv.subscribe(stored).unwrap().map(|c| c.unwrap().key).collect();
+        assert_eq!(keys, [b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn subscribe_to_encrypted_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = Cipher::new(1, &[7; 32]);
+        let mut kv = KV::open_encrypted(dir.path().join("db.log"), cipher).unwrap();
+        kv.set(b"secret", b"value").unwrap();
+
+        let change = kv.subscribe(0).unwrap().next_change().unwrap().unwrap();
+        assert_eq!(change.key, b"secret");
+    }
+
+    #[test]
+    fn scan_in_key_order() {
+        for opts in [Options::default(), disk_opts()] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut kv = KV::open_with(dir.path().join("db.log"), opts).unwrap();
+            for key in [&b"b"[..], b"d", b"a", b"c"] {
+                kv.set(key, key).unwrap();
+            }
+            kv.del(b"c").unwrap();
+
+            let keys = |range| -> Vec<Vec<u8>> { kv.scan(range).map(|p| p.unwrap().0).collect() };
+            assert_eq!(keys((Bound::Unbounded, Bound::Unbounded)), [b"a", b"b", b"d"]);
+            assert_eq!(keys((Bound::Included(b"b".to_vec()), Bound::Excluded(b"d".to_vec()))), [b"b"]);
+            assert_eq!(keys((Bound::Included(b"d".to_vec()), Bound::Excluded(b"a".to_vec()))), [] as [&[u8]; 0]);
+            let (key, val) = kv.scan(b"d".to_vec()..).next().unwrap().unwrap();
+            assert_eq!((key, val), (b"d".to_vec(), b"d".to_vec()));
+        }
+    }
+
+    #[test]
+    fn apply_batch_across_modes_and_reopen() {
+        for opts in [Options::default(), disk_opts()] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let ops = [
+                WriteOp::Set(b"a".to_vec(), b"1".to_vec()),
+                WriteOp::Set(b"b".to_vec(), b"2".to_vec()),
+                WriteOp::Del(b"c".to_vec()),
+                // later op on the same key wins
+                WriteOp::Set(b"a".to_vec(), b"3".to_vec()),
+            ];
+            {
+                let mut kv = KV::open_with(&path, opts.clone()).unwrap();
+                kv.set(b"c", b"old").unwrap();
+                kv.apply(&ops).unwrap();
+                assert_eq!(kv.last_lsn(), 2);
+                assert_eq!(kv.get(b"a").unwrap(), Some(b"3".to_vec()));
+                kv.close().unwrap();
+            }
+
+            // from the hint, then from the log alone
+            for _ in 0..2 {
+                let kv = KV::open_with(&path, opts.clone()).unwrap();
+                assert_eq!(kv.get(b"a").unwrap(), Some(b"3".to_vec()));
+                assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+                assert!(kv.get(b"c").unwrap().is_none());
+                drop(kv);
+                let _ = std::fs::remove_file(key_dir::hint_path(&path));
+            }
+        }
+    }
+
+    #[test]
+    fn torn_batch_is_dropped_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+        {
+            let mut kv = KV::open(&path).unwrap();
+            kv.set(b"a", b"1").unwrap();
+            kv.apply(&[WriteOp::Set(b"a".to_vec(), b"2".to_vec()), WriteOp::Set(b"b".to_vec(), b"3".to_vec())])
+                .unwrap();
+        }
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+
+        let kv = KV::open(&path).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(kv.get(b"b").unwrap().is_none());
+    }
+
+    #[test]
+    fn oversize_op_fails_the_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kv = KV::open(dir.path().join("db.log")).unwrap();
+
+        let ops = [WriteOp::Set(b"a".to_vec(), b"1".to_vec()), WriteOp::Del(vec![0; 100 << 10])];
+        assert!(matches!(kv.apply(&ops), Err(KVError::KeyTooLarge { .. })));
+        assert!(kv.get(b"a").unwrap().is_none());
+        assert_eq!(kv.last_lsn(), 0);
+    }
+
+    #[test]
+    fn in_memory_db_across_modes() {
+        for opts in [Options::default(), disk_opts(), Options { snapshot_every: Some(1), ..disk_opts() }] {
+            let mut kv = KV::open_in_memory_with(opts).unwrap();
+            assert!(kv.is_in_memory());
+            assert_eq!(kv.path(), Path::new(log_storage::IN_MEMORY_PATH));
+
+            kv.set(b"a", b"1").unwrap();
+            kv.apply(&[WriteOp::Set(b"b".to_vec(), b"2".to_vec()), WriteOp::Del(b"a".to_vec())]).unwrap();
+            kv.set_blob(b"blob", &[7u8; 100][..]).unwrap();
+            kv.snapshot().unwrap();
+            kv.close().unwrap();
+
+            assert!(kv.get(b"a").unwrap().is_none());
+            assert_eq!(kv.get(b"b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(kv.get(b"blob").unwrap(), Some(vec![7; 100]));
+            assert_eq!(kv.last_lsn(), 4);
+            assert!(matches!(kv.subscribe(0), Err(KVError::Unsupported(_))));
+        }
+        assert!(!Path::new(log_storage::IN_MEMORY_PATH).exists());
+    }
+
+    #[test]
+    fn failed_fsync_poisons_the_db() {
+        let vfs = SimVfs::new(1);
+        let opts = Options { vfs: SharedVfs::new(vfs.clone()), ..disk_opts() };
+        let mut kv = KV::open_with("/db/db.log", opts.clone()).unwrap();
+        kv.set(b"a", b"1").unwrap();
+
+        vfs.set_faults(Faults { sync_error: 1.0, ..Faults::default() });
+        assert!(matches!(kv.set(b"a", b"2"), Err(KVError::Poisoned(_))));
+        vfs.set_faults(Faults::default());
+        // the disk works again, the db still refuses
+        assert!(kv.is_poisoned());
+        assert!(matches!(kv.set(b"b", b"1"), Err(KVError::Poisoned(_))));
+        assert!(matches!(kv.del(b"a"), Err(KVError::Poisoned(_))));
+        assert!(matches!(kv.snapshot(), Err(KVError::Poisoned(_))));
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+        drop(kv);
+
+        // recovery: the record whose fsync failed is gone after a crash
+        vfs.crash();
+        let mut kv = KV::open_with("/db/db.log", opts).unwrap();
+        assert!(!kv.is_poisoned());
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"1".to_vec()));
+        kv.set(b"b", b"1").unwrap();
+    }
+
+    #[test]
+    fn durability_options_keep_the_data() {
+        let durability = Durability {
+            sync: SyncMode::Data,
+            preallocate: Some(1 << 16),
+            direct_io: true,
+            write_through: true,
+        };
+        for opts in [Options::default(), disk_opts()] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let opts = Options { durability, ..opts };
+
+            let mut kv = match KV::open_with(&path, opts.clone()) {
+                Ok(kv) => kv,
+                // no O_DIRECT here
+                Err(KVError::Io(e)) if e.raw_os_error() == Some(libc::EINVAL) => return,
+                Err(e) => panic!("{e}"),
+            };
+            kv.set(b"a", b"1").unwrap();
+            kv.apply(&[WriteOp::Set(b"b".to_vec(), vec![2; 5000]), WriteOp::Del(b"a".to_vec())]).unwrap();
+            assert_eq!(kv.get(b"b").unwrap(), Some(vec![2; 5000]));
+            drop(kv);
+
+            let mut kv = KV::open_with(&path, opts.clone()).unwrap();
+            kv.set(b"c", b"3").unwrap();
+            drop(kv);
+
+            let kv = KV::open_with(&path, opts).unwrap();
+            assert!(kv.get(b"a").unwrap().is_none());
+            assert_eq!(kv.get(b"b").unwrap(), Some(vec![2; 5000]));
+            assert_eq!(kv.get(b"c").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(kv.last_lsn(), 3);
+        }
+    }
+
+    #[test]
+    fn second_writer_is_locked_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+        let mut kv = KV::open(&path).unwrap();
+        kv.set(b"a", b"1").unwrap();
+
+        let err = KV::open(&path).err().unwrap();
+        assert!(matches!(err, KVError::Locked(_)), "{err}");
+        // a view reads next to the writer
+        let view = KV::open_at(&path, PointInTime::Lsn(1)).unwrap();
+        assert_eq!(view.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        drop(kv);
+        let mut kv = KV::open(&path).unwrap();
+        kv.set(b"b", b"2").unwrap();
+    }
+
+    #[test]
+    fn read_only_next_to_a_writer() {
+        let files = |dir: &Path| {
+            let mut files: Vec<(PathBuf, Vec<u8>)> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|item| {
+                    let path = item.unwrap().path();
+                    let data = std::fs::read(&path).unwrap();
+                    (path, data)
+                })
+                .collect();
+            files.sort();
+            files
+        };
+
+        for opts in [Options::default(), disk_opts()] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db.log");
+            let opts = Options { snapshot_every: Some(2), ..opts };
+            assert!(KV::open_read_only(&path).is_err());
+            assert!(files(dir.path()).is_empty());
+
+            let mut writer = KV::open_with(&path, opts.clone()).unwrap();
+            for i in 0..5u8 {
+                writer.set(&[i], b"v").unwrap();
+            }
+            writer.del(&[0]).unwrap();
+
+            let before = files(dir.path());
+            let mut reader = KV::open_read_only_with(&path, opts.clone()).unwrap();
+            assert!(reader.is_read_only());
+            assert_eq!(reader.keys().count(), 4);
+            assert!(matches!(reader.set(b"x", b"1"), Err(KVError::ReadOnly)));
+            assert!(matches!(reader.del(&[1]), Err(KVError::ReadOnly)));
+            reader.close().unwrap();
+            assert_eq!(files(dir.path()), before);
+
+            // the reader keeps what it saw, a new one sees the rest
+            writer.set(&[9], b"v").unwrap();
+            writer.del(&[1]).unwrap();
+            assert_eq!(reader.get(&[1]).unwrap(), Some(b"v".to_vec()));
+            assert!(reader.get(&[9]).unwrap().is_none());
+            let reader = KV::open_read_only_with(&path, opts.clone()).unwrap();
+            assert!(reader.get(&[1]).unwrap().is_none());
+            assert_eq!(reader.last_lsn(), writer.last_lsn());
+            drop(writer);
+
+            // a torn tail is read past, not cut off
+            let mut log = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+            log.write_all(&[1, 2, 3]).unwrap();
+            let before = files(dir.path());
+            let reader = KV::open_read_only_with(&path, opts).unwrap();
+            assert_eq!(reader.keys().count(), 4);
+            assert_eq!(files(dir.path()), before);
+        }
+    }
 }
@@ -0,0 +1,272 @@
+//! Storage engine interface: what every backend offers
+use crate::core::batch::WriteOp;
+use crate::core::key_value::KV;
+use crate::error::KVError;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+
+// key, value
+pub type Pair = (Vec<u8>, Vec<u8>);
+
+// Code written against this runs on the log-backed KV, the in-memory
+// MemStore or a client of the silly-db server alike.
+pub trait KvStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError>;
+
+    fn contains(&self, key: &[u8]) -> Result<bool, KVError> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    // true if the key existed
+    fn set(&mut self, key: &[u8], val: &[u8]) -> Result<bool, KVError>;
+
+    // true if the key existed
+    fn del(&mut self, key: &[u8]) -> Result<bool, KVError>;
+
+    // pairs in key order
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> impl Iterator<Item = Result<Pair, KVError>> + '_;
+
+    // all of the writes or none, later ops win on the same key
+    fn apply(&mut self, ops: &[WriteOp]) -> Result<(), KVError>;
+
+    fn begin(&mut self) -> Txn<'_, Self>
+    where
+        Self: Sized,
+    {
+        Txn::new(self)
+    }
+}
+
+// BTreeMap::range panics on these instead of being empty
+pub(crate) fn is_empty_range(range: &impl RangeBounds<Vec<u8>>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => {
+            start >= end
+        }
+        _ => false,
+    }
+}
+
+fn owned_range(range: impl RangeBounds<Vec<u8>>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+impl KvStore for KV {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        KV::get(self, key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, KVError> {
+        Ok(KV::contains(self, key))
+    }
+
+    fn set(&mut self, key: &[u8], val: &[u8]) -> Result<bool, KVError> {
+        KV::set(self, key, val)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<bool, KVError> {
+        KV::del(self, key)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> impl Iterator<Item = Result<Pair, KVError>> + '_ {
+        KV::scan(self, range)
+    }
+
+    fn apply(&mut self, ops: &[WriteOp]) -> Result<(), KVError> {
+        KV::apply(self, ops)
+    }
+}
+
+// Writes are held back until commit, which applies them as one batch;
+// reads through the Txn see them. Dropped without commit = rolled back.
+// The store is borrowed mutably, so nothing else writes in between.
+pub struct Txn<'a, S: KvStore> {
+    store: &'a mut S,
+    // None = deleted
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a, S: KvStore> Txn<'a, S> {
+    pub fn new(store: &'a mut S) -> Self {
+        Txn { store, writes: BTreeMap::new() }
+    }
+
+    pub fn commit(self) -> Result<(), KVError> {
+        let ops: Vec<WriteOp> = self
+            .writes
+            .into_iter()
+            .map(|(key, val)| match val {
+                Some(val) => WriteOp::Set(key, val),
+                None => WriteOp::Del(key),
+            })
+            .collect();
+        self.store.apply(&ops)
+    }
+
+    pub fn rollback(self) {}
+}
+
+impl<S: KvStore> KvStore for Txn<'_, S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        match self.writes.get(key) {
+            Some(val) => Ok(val.clone()),
+            None => self.store.get(key),
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, KVError> {
+        match self.writes.get(key) {
+            Some(val) => Ok(val.is_some()),
+            None => self.store.contains(key),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], val: &[u8]) -> Result<bool, KVError> {
+        let existed = self.contains(key)?;
+        self.writes.insert(key.to_vec(), Some(val.to_vec()));
+        Ok(existed)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<bool, KVError> {
+        let existed = self.contains(key)?;
+        self.writes.insert(key.to_vec(), None);
+        Ok(existed)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> impl Iterator<Item = Result<Pair, KVError>> + '_ {
+        let range = owned_range(range);
+        let writes = (!is_empty_range(&range)).then(|| self.writes.range(range.clone()));
+        Merge {
+            base: self.store.scan(range).peekable(),
+            writes: writes.into_iter().flatten().peekable(),
+        }
+    }
+
+    fn apply(&mut self, ops: &[WriteOp]) -> Result<(), KVError> {
+        for op in ops {
+            match op {
+                WriteOp::Set(key, val) => self.writes.insert(key.clone(), Some(val.clone())),
+                WriteOp::Del(key) => self.writes.insert(key.clone(), None),
+            };
+        }
+        Ok(())
+    }
+}
+
+// store pairs with the pending writes laid over them, in key order
+struct Merge<'a, B: Iterator, W: Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)>> {
+    base: Peekable<B>,
+    writes: Peekable<W>,
+}
+
+impl<'a, B, W> Iterator for Merge<'a, B, W>
+where
+    B: Iterator<Item = Result<Pair, KVError>>,
+    W: Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)>,
+{
+    type Item = Result<Pair, KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let order = match (self.base.peek(), self.writes.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(Ok((base_key, _))), Some((key, _))) => base_key.cmp(key),
+            };
+            match order {
+                Ordering::Less => return self.base.next(),
+                // the pending write replaces the stored pair
+                Ordering::Equal => drop(self.base.next()),
+                Ordering::Greater => {}
+            }
+            match self.writes.next()? {
+                (key, Some(val)) => return Some(Ok((key.clone(), val.clone()))),
+                // deleted in this txn
+                (_, None) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::mem_store::MemStore;
+
+    fn keys(store: &impl KvStore, range: impl RangeBounds<Vec<u8>>) -> Vec<Vec<u8>> {
+        store.scan(range).map(|pair| pair.unwrap().0).collect()
+    }
+
+    #[test]
+    fn txn_reads_its_own_writes() {
+        let mut store = MemStore::new();
+        for key in [b"a", b"b", b"c"] {
+            store.set(key, b"old").unwrap();
+        }
+
+        let mut txn = store.begin();
+        assert!(txn.set(b"b", b"new").unwrap());
+        assert!(txn.del(b"c").unwrap());
+        assert!(!txn.set(b"d", b"new").unwrap());
+        assert!(!txn.del(b"c").unwrap());
+
+        assert_eq!(txn.get(b"b").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(txn.get(b"c").unwrap(), None);
+        assert_eq!(keys(&txn, ..), [b"a", b"b", b"d"]);
+        assert_eq!(keys(&txn, b"b".to_vec()..b"d".to_vec()), [b"b"]);
+        let (_, val) = txn.scan(b"b".to_vec()..).next().unwrap().unwrap();
+        assert_eq!(val, b"new");
+        txn.commit().unwrap();
+
+        assert_eq!(keys(&store, ..), [b"a", b"b", b"d"]);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn dropped_txn_changes_nothing() {
+        let mut store = MemStore::new();
+        store.set(b"a", b"1").unwrap();
+
+        let mut txn = store.begin();
+        txn.del(b"a").unwrap();
+        txn.set(b"b", b"2").unwrap();
+        txn.rollback();
+
+        assert_eq!(keys(&store, ..), [b"a"]);
+    }
+
+    #[test]
+    fn txn_on_kv_is_one_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.log");
+        let mut kv = KV::open(&path).unwrap();
+        kv.set(b"a", b"1").unwrap();
+
+        let mut txn = kv.begin();
+        txn.set(b"a", b"2").unwrap();
+        txn.set(b"b", b"3").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(kv.last_lsn(), 2);
+        drop(kv);
+
+        let kv = KV::open(&path).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(kv.get(b"b").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn empty_ranges_do_not_panic() {
+        let mut store = MemStore::new();
+        store.set(b"a", b"1").unwrap();
+        let a = || b"a".to_vec();
+
+        assert!(keys(&store, (Bound::Excluded(a()), Bound::Excluded(a()))).is_empty());
+        assert!(keys(&store, b"b".to_vec()..a()).is_empty());
+        assert_eq!(keys(&store, a()..=a()), [a()]);
+        assert!(keys(&store.begin(), b"b".to_vec()..a()).is_empty());
+    }
+}
@@ -1,48 +1,496 @@
 // Log Storage
-use crate::core::binary_serializer::Entry;
-use crate::core::fsync::create_file_sync;
-use std::io::{self, Seek, SeekFrom};
-use std::path::PathBuf;
+use crate::core::binary_serializer::{Entry, EntryKind, Limits, Seal, Stamp};
+use crate::core::blob::BlobManifest;
+use crate::core::crypto::Cipher;
+use crate::core::db_lock::SyncMark;
+use crate::core::file_header::{self, FileHeader, HEADER_LEN, VERSION};
+use crate::core::fsync::{Durability, create_file_sync_with, parent_dir};
+use crate::core::vfs::{MemFile, OpenMode, SharedVfs, Vfs, VfsFile};
+use crate::error::KVError;
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// where a record lives in the log file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordPos {
+    pub offset: u64,
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    pub limits: Limits,
+    // compress values of at least this many bytes, None = never
+    pub compress_above: Option<usize>,
+    // encrypt every record; a log is either fully encrypted or not at all
+    pub cipher: Option<Cipher>,
+    // no create, no header, append is ReadOnly
+    pub read_only: bool,
+    pub vfs: SharedVfs,
+    pub durability: Durability,
+}
+
+// path() of a log that is not in a file
+pub const IN_MEMORY_PATH: &str = ":memory:";
+
+// Read + Seek over a VfsFile, for the record decoder
+struct FileCursor<'a> {
+    file: &'a dyn VfsFile,
+    pos: u64,
+}
+
+impl Read for FileCursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FileCursor<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => self.file.len()?.checked_add_signed(d),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
 
 pub struct Log {
-    #[allow(unused)]
     filename: PathBuf,
-    fileptr: std::fs::File,
+    file: Box<dyn VfsFile>,
+    in_memory: bool,
+    // next record read()
+    pos: u64,
+    // where read() ran out of records: the next append goes there and
+    // cuts off a torn record after it, which would hide later records
+    end: Option<u64>,
+    // past `end` there are only zeros we preallocated, nothing to cut off
+    clean_tail: bool,
+    opts: LogOptions,
+    // newest stamp written or read
+    last: Stamp,
+    // why a sync failed; appends and syncs fail from then on
+    poisoned: Option<String>,
+    // told the synced end after each sync, for readers of the raw log
+    sync_mark: Option<SyncMark>,
 }
 
 impl Log {
-    pub fn open(filename: impl Into<PathBuf>) -> io::Result<Self> {
+    pub fn open(filename: impl Into<PathBuf>) -> Result<Self, KVError> {
+        Self::open_with(filename, LogOptions::default())
+    }
+
+    // writes the header on a new file and checks it on an existing one;
+    // a log of an older version is Unsupported until upgrade() rewrites it
+    pub fn open_with(filename: impl Into<PathBuf>, opts: LogOptions) -> Result<Self, KVError> {
         let filename = filename.into();
-        let fileptr = create_file_sync(&filename)?;
+        let file = if opts.read_only {
+            opts.vfs.open(&filename, OpenMode::Read)?
+        } else {
+            create_file_sync_with(&*opts.vfs, &filename, &opts.durability)?
+        };
+        Self::init(filename, file, false, opts)
+    }
+
+    // a log in a buffer: nothing touches the file system, all of it is
+    // lost when the Log is dropped
+    pub fn open_in_memory(opts: LogOptions) -> Result<Self, KVError> {
+        let opts = LogOptions { read_only: false, ..opts };
+        Self::init(PathBuf::from(IN_MEMORY_PATH), Box::new(MemFile::default()), true, opts)
+    }
+
+    fn init(filename: PathBuf, file: Box<dyn VfsFile>, in_memory: bool, opts: LogOptions) -> Result<Self, KVError> {
+        let len = file.len()?;
 
-        Ok(Log { filename, fileptr })
+        let mut head = vec![0u8; HEADER_LEN.min(len as usize)];
+        file.read_exact_at(&mut head, 0)?;
+
+        if file_header::is_torn_header(&head) && opts.read_only {
+            // nothing was written yet: reads find no records
+        } else if file_header::is_torn_header(&head) {
+            file.set_len(0)?;
+            file.write_all_at(&FileHeader::new(opts.cipher.as_ref()).encode(), 0)?;
+            file.sync()?;
+        } else {
+            let version = log_version(&head)?;
+            if version != VERSION {
+                return Err(KVError::Unsupported(format!(
+                    "log version {version}, needs upgrade"
+                )));
+            }
+            FileHeader::decode(&head)?.check_key(opts.cipher.as_ref())?;
+        }
+
+        Ok(Log {
+            filename,
+            file,
+            in_memory,
+            pos: HEADER_LEN as u64,
+            end: None,
+            clean_tail: false,
+            opts,
+            last: Stamp::default(),
+            poisoned: None,
+            sync_mark: None,
+        })
     }
 
-    pub fn close(self) -> io::Result<()> {
+    pub fn close(self) -> Result<(), KVError> {
         Ok(())
     }
 
-    pub fn write(&mut self, entry: &Entry) -> io::Result<()> {
-        self.fileptr.seek(SeekFrom::End(0))?;
-        entry.encode_into(&mut self.fileptr)?;
-        self.fileptr.sync_all()?;
+    // IN_MEMORY_PATH for a log in memory
+    pub fn path(&self) -> &Path {
+        &self.filename
+    }
+
+    pub fn is_in_memory(&self) -> bool {
+        self.in_memory
+    }
+
+    // bytes in the file, a torn tail included
+    pub fn file_len(&self) -> Result<u64, KVError> {
+        Ok(self.file.len()?)
+    }
+
+    // offset of the first record
+    pub fn data_start(&self) -> u64 {
+        HEADER_LEN as u64
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.opts.limits
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.opts.cipher.as_ref()
+    }
+
+    pub fn vfs(&self) -> &SharedVfs {
+        &self.opts.vfs
+    }
+
+    fn seal(&self, offset: u64) -> Option<Seal<'_>> {
+        self.opts.cipher.as_ref().map(|cipher| Seal { cipher, offset })
+    }
+
+    // stamp of the newest record written or read
+    pub fn last_stamp(&self) -> Stamp {
+        self.last
+    }
+
+    // records skipped thanks to a snapshot still count for the next lsn
+    pub fn observe_stamp(&mut self, stamp: Stamp) {
+        self.last = self.last.max(stamp);
+    }
+
+    // a point-in-time view reads one record past its end; never appends
+    pub(crate) fn rewind_stamp(&mut self, stamp: Stamp) {
+        debug_assert!(self.opts.read_only);
+        self.last = stamp;
+    }
+
+    // append + fsync
+    pub fn write(&mut self, entry: &mut Entry) -> Result<RecordPos, KVError> {
+        let pos = self.append(entry)?;
+        self.sync()?;
+        Ok(pos)
+    }
+
+    // one write_all per record, so the record lands in one piece;
+    // not durable until sync(). Stamps the entry with the next lsn: the
+    // whole log must have been read (or observed) before appending
+    pub fn append(&mut self, entry: &mut Entry) -> Result<RecordPos, KVError> {
+        if self.opts.read_only {
+            return Err(KVError::ReadOnly);
+        }
+        self.check_poisoned()?;
+        self.opts.limits.check(entry.key(), entry.value())?;
+
+        let stamp = Stamp {
+            lsn: self.last.lsn + 1,
+            ts: now_micros().max(self.last.ts),
+        };
+        entry.set_stamp(stamp);
+
+        let len = self.file.len()?;
+        let (offset, len) = match self.end {
+            Some(end) if end < len && !self.clean_tail => {
+                self.file.set_len(end)?;
+                (end, end)
+            }
+            Some(end) => (end, len),
+            None => (len, len),
+        };
+        let mut buf = Vec::new();
+        entry.encode_into_with(&mut buf, self.opts.compress_above, self.seal(offset))?;
+
+        let needed = offset + buf.len() as u64;
+        if let Some(step) = self.opts.durability.preallocate
+            && needed > len
+        {
+            self.file.allocate(len, step.max(needed - len))?;
+        }
+
+        // a write that fails halfway is cut off by the next append
+        self.end = Some(offset);
+        self.clean_tail = false;
+        self.file.write_all_at(&buf, offset)?;
+        self.clean_tail = true;
+
+        self.last = stamp;
+        self.pos = offset + buf.len() as u64;
+        self.end = Some(self.pos);
+        Ok(RecordPos { offset, len: buf.len() as u32 })
+    }
+
+    // A failed fsync poisons the log: the kernel may have dropped the
+    // unsynced pages and a later fsync would report success anyway
+    pub fn sync(&mut self) -> Result<(), KVError> {
+        self.check_poisoned()?;
+        // appended so far: set before the sync, so not past what it covers
+        let end = match self.end {
+            Some(end) => end,
+            None => self.file.len()?,
+        };
+        if let Err(e) = self.opts.durability.sync(&*self.file) {
+            self.poisoned = Some(e.to_string());
+            return self.check_poisoned();
+        }
+        if let Some(mark) = &self.sync_mark {
+            mark.set(end)?;
+        }
         Ok(())
     }
 
-    pub fn read(&mut self) -> io::Result<Option<Entry>> {
-        match Entry::decode(&mut self.fileptr) {
-            Ok(entry) => Ok(Some(entry)),
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
-            Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(None),
+    // the writer's: syncs what is there now and marks it, then every sync
+    pub(crate) fn set_sync_mark(&mut self, mark: SyncMark) -> Result<(), KVError> {
+        self.sync_mark = Some(mark);
+        self.sync()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.is_some()
+    }
+
+    pub(crate) fn check_poisoned(&self) -> Result<(), KVError> {
+        match &self.poisoned {
+            Some(msg) => Err(KVError::Poisoned(msg.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // pread: does not move the read cursor
+    pub fn read_at(&self, pos: RecordPos) -> Result<Entry, KVError> {
+        let mut buf = vec![0u8; pos.len as usize];
+        self.file.read_exact_at(&mut buf, pos.offset)?;
+        Entry::decode_sealed(&mut buf.as_slice(), &self.opts.limits, self.seal(pos.offset))
+    }
+
+    // offset of the next record read()
+    pub fn position(&mut self) -> Result<u64, KVError> {
+        Ok(self.pos)
+    }
+
+    pub fn seek(&mut self, offset: u64) -> Result<(), KVError> {
+        self.pos = offset;
+        Ok(())
+    }
+
+    // Tampered and KeyMismatch are errors: the log is not cut short there.
+    // At the end the cursor stays before the torn record, if any
+    pub fn read(&mut self) -> Result<Option<Entry>, KVError> {
+        let offset = self.pos;
+        let seal = self.seal(offset);
+        let mut cursor = FileCursor { file: &*self.file, pos: offset };
+        match Entry::decode_sealed(&mut cursor, &self.opts.limits, seal) {
+            Ok(entry) => {
+                self.pos = cursor.pos;
+                self.observe_stamp(entry.stamp());
+                Ok(Some(entry))
+            }
+            Err(e) if e.is_eof() || matches!(e, KVError::Corruption(_)) => {
+                if self.end != Some(offset) {
+                    self.clean_tail = false;
+                }
+                self.end = Some(offset);
+                Ok(None)
+            }
             Err(e) => Err(e),
         }
     }
 }
 
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// 1 for a headerless log
+fn log_version(head: &[u8]) -> Result<u16, KVError> {
+    if !file_header::has_magic(head) {
+        return Ok(1);
+    }
+    Ok(FileHeader::decode(head)?.version)
+}
+
+// an existing log that upgrade() would rewrite
+pub fn needs_upgrade(path: &Path, opts: &LogOptions) -> Result<bool, KVError> {
+    Ok(old_version(path, opts)?.is_some())
+}
+
+// an old log file and its header
+type OldLog = (Box<dyn VfsFile>, Vec<u8>);
+
+// the file and its header, if it is a log of an older version
+fn old_version(path: &Path, opts: &LogOptions) -> Result<Option<OldLog>, KVError> {
+    let old = match opts.vfs.open(path, OpenMode::Read) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut head = vec![0u8; HEADER_LEN.min(old.len()? as usize)];
+    old.read_exact_at(&mut head, 0)?;
+    if file_header::is_torn_header(&head) || log_version(&head)? == VERSION {
+        return Ok(None);
+    }
+    // no magic: a version 1 log only if its first record is one
+    if !file_header::has_magic(&head) && !starts_with_v1_record(&*old, opts) {
+        return Err(KVError::Unsupported(format!("{} is not a log", path.display())));
+    }
+    Ok(Some((old, head)))
+}
+
+fn starts_with_v1_record(file: &dyn VfsFile, opts: &LogOptions) -> bool {
+    let mut reader = BufReader::new(FileCursor { file, pos: 0 });
+    Entry::decode_version(&mut reader, &opts.limits, None, 1)
+        .is_ok_and(|entry| matches!(entry.kind(), EntryKind::Value | EntryKind::Tombstone))
+}
+
+// <log>.v1 for a version 1 log
+pub fn backup_path(path: &Path, version: u16) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(format!(".v{version}"));
+    PathBuf::from(p)
+}
+
+// Rewrites a log of an older version as the current one, record by record
+// through a new Log, so `opts` may also compress or encrypt it (an
+// encrypted log needs its key and stays encrypted). Records get fresh stamps in
+// log order. A torn last record is dropped, a damaged one before it is
+// Corruption and leaves the log as it was. The old file is kept as
+// backup_path(). Record offsets change: blob manifests are rewritten,
+// snapshots and hints of the old file are stale.
+// Ok(false) if there was nothing to upgrade.
+pub fn upgrade(path: &Path, opts: &LogOptions) -> Result<bool, KVError> {
+    let vfs = &opts.vfs;
+    let Some((old, head)) = old_version(path, opts)? else { return Ok(false) };
+    let version = log_version(&head)?;
+
+    // old records are read with the key only if the old log was encrypted
+    let (start, old_cipher) = if version == 1 {
+        (0, None)
+    } else {
+        let header = FileHeader::decode(&head)?;
+        if header.encrypted() {
+            header.check_key(opts.cipher.as_ref())?;
+            (HEADER_LEN as u64, opts.cipher.as_ref())
+        } else {
+            (HEADER_LEN as u64, None)
+        }
+    };
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".upgrade");
+    let tmp = PathBuf::from(tmp);
+    // left over from an upgrade that crashed before the rename
+    let _ = vfs.remove_file(&tmp);
+
+    let old_len = old.len()?;
+    let mut new = Log::open_with(&tmp, opts.clone())?;
+    let mut reader = BufReader::new(FileCursor { file: &*old, pos: start });
+    // old chunk offset -> new place, for the manifests
+    let mut moved: HashMap<u64, RecordPos> = HashMap::new();
+    loop {
+        let offset = reader.stream_position()?;
+        let seal = old_cipher.map(|cipher| Seal { cipher, offset });
+        let decoded = match Entry::decode_version(&mut reader, &opts.limits, seal, version) {
+            // version 1 only had the 0/1 deleted byte
+            Ok(entry) if version == 1 && !matches!(entry.kind(), EntryKind::Value | EntryKind::Tombstone) => {
+                Err(KVError::corruption("bad record kind in a version 1 log"))
+            }
+            decoded => decoded,
+        };
+        let mut entry = match decoded {
+            Ok(entry) => entry,
+            Err(e) if e.is_eof() => break,
+            // torn only if nothing comes after it
+            Err(KVError::Corruption(_)) if reader.stream_position()? >= old_len => break,
+            Err(KVError::Corruption(msg)) => {
+                return Err(KVError::Corruption(format!("{msg} at offset {offset}, log not upgraded")));
+            }
+            Err(e) => return Err(e),
+        };
+
+        match entry.kind() {
+            EntryKind::BlobChunk => {
+                let pos = new.append(&mut entry)?;
+                moved.insert(offset, pos);
+            }
+            EntryKind::BlobRef => {
+                let mut manifest = BlobManifest::decode(&mut entry.value())?;
+                for chunk in &mut manifest.chunks {
+                    *chunk = *moved
+                        .get(&chunk.offset)
+                        .ok_or_else(|| KVError::corruption("blob chunk not in the log"))?;
+                }
+                let mut val = Vec::new();
+                manifest.encode(&mut val);
+                let (key, _) = entry.into_parts();
+                new.append(&mut Entry::with_kind(key, val, EntryKind::BlobRef))?;
+            }
+            _ => {
+                new.append(&mut entry)?;
+            }
+        }
+    }
+    new.sync()?;
+
+    copy_file(&**vfs, &*old, &backup_path(path, version))?;
+    vfs.rename(&tmp, path)?;
+    vfs.sync_dir(parent_dir(path))?;
+    Ok(true)
+}
+
+// synced, not its directory entry
+fn copy_file(vfs: &dyn Vfs, from: &dyn VfsFile, to: &Path) -> Result<(), KVError> {
+    let out = vfs.open(to, OpenMode::Truncate)?;
+    let mut buf = vec![0u8; 64 << 10];
+    let mut offset = 0;
+    loop {
+        let n = from.read_at(&mut buf, offset)?;
+        if n == 0 {
+            break;
+        }
+        out.write_all_at(&buf[..n], offset)?;
+        offset += n as u64;
+    }
+    Ok(out.sync()?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::SeekFrom;
+    use crate::core::fsync::SyncMode;
 
     #[test]
     fn log_write_then_read() {
@@ -51,14 +499,14 @@ mod tests {
 
         let mut log = Log::open(&path).unwrap();
 
-        let e1 = Entry::new(b"a".to_vec(), b"1".to_vec());
-        let e2 = Entry::new(b"b".to_vec(), b"2".to_vec());
+        let mut e1 = Entry::new(b"a".to_vec(), b"1".to_vec());
+        let mut e2 = Entry::new(b"b".to_vec(), b"2".to_vec());
 
-        log.write(&e1).unwrap();
-        log.write(&e2).unwrap();
+        log.write(&mut e1).unwrap();
+        log.write(&mut e2).unwrap();
 
         // читаем с начала
-        log.fileptr.seek(SeekFrom::Start(0)).unwrap();
+        log.seek(log.data_start()).unwrap();
 
         let r1 = log.read().unwrap().unwrap();
         let r2 = log.read().unwrap().unwrap();
@@ -71,21 +519,19 @@ mod tests {
 
     #[test]
     fn ignore_partial_entry() {
-        use std::io::Write;
-
         let dir = tempfile::tempdir().unwrap();
         let path = dir.path().join("wal.log");
 
         let mut log = Log::open(&path).unwrap();
 
-        let e1 = Entry::new(b"a".to_vec(), b"1".to_vec());
-        log.write(&e1).unwrap();
+        let mut e1 = Entry::new(b"a".to_vec(), b"1".to_vec());
+        log.write(&mut e1).unwrap();
 
         // add trash
-        log.fileptr.write_all(&[1, 2, 3, 4]).unwrap();
-        log.fileptr.sync_all().unwrap();
+        log.file.write_all_at(&[1, 2, 3, 4], log.file.len().unwrap()).unwrap();
+        log.file.sync().unwrap();
 
-        log.fileptr.seek(std::io::SeekFrom::Start(0)).unwrap();
+        log.seek(log.data_start()).unwrap();
 
         let r1 = log.read().unwrap().unwrap();
         let r2 = log.read().unwrap();
@@ -94,4 +540,343 @@ mod tests {
         assert!(r2.is_none());
     }
 
+    #[test]
+    fn preallocated_zeros_read_as_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let durability = Durability { sync: SyncMode::Data, preallocate: Some(4096), ..Durability::default() };
+        let opts = LogOptions { durability, ..LogOptions::default() };
+
+        let keys: Vec<Vec<u8>> = (0..20u8).map(|i| vec![b'k', i]).collect();
+        let mut log = Log::open_with(&path, opts.clone()).unwrap();
+        for key in &keys[..10] {
+            log.write(&mut Entry::new(key.clone(), b"v".to_vec())).unwrap();
+        }
+        // one fallocate for all of them
+        assert_eq!(log.file_len().unwrap(), HEADER_LEN as u64 + 4096);
+
+        for key in &keys[10..] {
+            let mut log = Log::open_with(&path, opts.clone()).unwrap();
+            while log.read().unwrap().is_some() {}
+            log.write(&mut Entry::new(key.clone(), b"v".to_vec())).unwrap();
+        }
+
+        let mut log = Log::open_with(&path, opts).unwrap();
+        let mut read = Vec::new();
+        while let Some(entry) = log.read().unwrap() {
+            read.push(entry.key().to_vec());
+        }
+        assert_eq!(read, keys);
+    }
+
+    #[test]
+    fn write_returns_pos_for_read_at() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut log = Log::open(&path).unwrap();
+
+        let p1 = log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+        let p2 = log.write(&mut Entry::new(b"bb".to_vec(), b"22".to_vec())).unwrap();
+
+        assert_eq!(p1.offset, HEADER_LEN as u64);
+        assert_eq!(p2.offset, p1.offset + p1.len as u64);
+
+        let e2 = log.read_at(p2).unwrap();
+        let e1 = log.read_at(p1).unwrap();
+
+        assert_eq!(e1.value(), b"1");
+        assert_eq!(e2.key(), b"bb");
+        assert_eq!(e2.value(), b"22");
+
+        // cursor untouched by read_at
+        assert_eq!(log.position().unwrap(), p2.offset + p2.len as u64);
+    }
+
+    #[test]
+    fn write_checks_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let opts = LogOptions {
+            limits: Limits { max_key_size: 4, max_value_size: 8 },
+            ..LogOptions::default()
+        };
+        let mut log = Log::open_with(&path, opts).unwrap();
+
+        let err = log.write(&mut Entry::new(b"12345".to_vec(), Vec::new())).err().unwrap();
+        assert!(matches!(err, KVError::KeyTooLarge { len: 5, max: 4 }));
+
+        let err = log.write(&mut Entry::new(b"k".to_vec(), vec![0; 9])).err().unwrap();
+        assert!(matches!(err, KVError::ValueTooLarge { len: 9, max: 8 }));
+
+        // nothing written
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), HEADER_LEN as u64);
+    }
+
+    fn crypt_opts(key_id: u64) -> LogOptions {
+        LogOptions {
+            cipher: Some(Cipher::new(key_id, &[1; 32])),
+            ..LogOptions::default()
+        }
+    }
+
+    #[test]
+    fn encrypted_log_has_header_and_no_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        {
+            let mut log = Log::open_with(&path, crypt_opts(5)).unwrap();
+            assert_eq!(log.data_start(), HEADER_LEN as u64);
+            let pos = log.write(&mut Entry::new(b"card".to_vec(), b"4111-1111".to_vec())).unwrap();
+            assert_eq!(pos.offset, log.data_start());
+            assert_eq!(log.read_at(pos).unwrap().value(), b"4111-1111");
+        }
+
+        let data = std::fs::read(&path).unwrap();
+        assert!(!data.windows(4).any(|w| w == b"4111"));
+
+        let mut log = Log::open_with(&path, crypt_opts(5)).unwrap();
+        assert_eq!(log.read().unwrap().unwrap().key(), b"card");
+        assert!(log.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn encrypted_log_needs_its_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        Log::open_with(&path, crypt_opts(5)).unwrap();
+
+        assert!(matches!(Log::open(&path), Err(KVError::KeyMismatch(_))));
+        assert!(matches!(Log::open_with(&path, crypt_opts(6)), Err(KVError::KeyMismatch(_))));
+
+        // plain log, key given
+        let plain = dir.path().join("plain.log");
+        Log::open(&plain).unwrap().write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+        assert!(matches!(Log::open_with(&plain, crypt_opts(5)), Err(KVError::KeyMismatch(_))));
+    }
+
+    #[test]
+    fn tampered_record_is_an_error_not_a_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        {
+            let mut log = Log::open_with(&path, crypt_opts(5)).unwrap();
+            log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+        }
+
+        // same key, different value, crc recomputed by the forger
+        let mut data = std::fs::read(&path).unwrap();
+        let start = HEADER_LEN;
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let crc = crc32fast::hash(&data[start + 4..]);
+        data[start..start + 4].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+
+        let mut log = Log::open_with(&path, crypt_opts(5)).unwrap();
+        assert!(matches!(log.read(), Err(KVError::Tampered(_))));
+    }
+
+    #[test]
+    fn header_written_and_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        Log::open(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(FileHeader::decode(&data).unwrap(), FileHeader::new(None));
+
+        // from a newer version
+        let mut header = FileHeader::new(None);
+        header.version += 1;
+        std::fs::write(&path, header.encode()).unwrap();
+        assert!(matches!(Log::open(&path), Err(KVError::Unsupported(_))));
+
+        // not a log at all, or a version 1 log
+        std::fs::write(&path, b"some other file").unwrap();
+        assert!(matches!(Log::open(&path), Err(KVError::Unsupported(_))));
+    }
+
+    #[test]
+    fn torn_header_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        std::fs::write(&path, &FileHeader::new(None).encode()[..10]).unwrap();
+
+        let mut log = Log::open(&path).unwrap();
+        let pos = log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+        assert_eq!(pos.offset, HEADER_LEN as u64);
+    }
+
+    // | crc | key_len | val_len | kind | key | val |, versions 1 and 2
+    fn encode_v2(key: &[u8], val: &[u8], kind: EntryKind) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&(val.len() as u32).to_le_bytes());
+        payload.push(kind as u8);
+        payload.extend_from_slice(key);
+        payload.extend_from_slice(val);
+
+        let mut data = crc32fast::hash(&payload).to_le_bytes().to_vec();
+        data.extend_from_slice(&payload);
+        data
+    }
+
+    // what the log looked like before the header
+    fn write_v1(path: &Path, records: &[Entry]) {
+        let mut data = Vec::new();
+        for entry in records {
+            data.extend_from_slice(&encode_v2(entry.key(), entry.value(), entry.kind()));
+        }
+        data.extend_from_slice(&[1, 2, 3]);
+        std::fs::write(path, &data).unwrap();
+    }
+
+    #[test]
+    fn upgrade_from_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        write_v1(&path, &[
+            Entry::new(b"a".to_vec(), b"1".to_vec()),
+            Entry::tombstone(b"a".to_vec()),
+            Entry::new(b"b".to_vec(), b"2".to_vec()),
+        ]);
+
+        let original = std::fs::read(&path).unwrap();
+        assert!(matches!(Log::open(&path), Err(KVError::Unsupported(_))));
+        assert!(upgrade(&path, &LogOptions::default()).unwrap());
+        assert!(!upgrade(&path, &LogOptions::default()).unwrap());
+        assert_eq!(std::fs::read(backup_path(&path, 1)).unwrap(), original);
+
+        let mut log = Log::open(&path).unwrap();
+        let mut keys = Vec::new();
+        while let Some(entry) = log.read().unwrap() {
+            keys.push((entry.key().to_vec(), entry.is_deleted()));
+        }
+        assert_eq!(keys, vec![
+            (b"a".to_vec(), false),
+            (b"a".to_vec(), true),
+            (b"b".to_vec(), false),
+        ]);
+    }
+
+    #[test]
+    fn foreign_file_is_not_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        std::fs::write(&path, b"some other file, not a log at all").unwrap();
+
+        assert!(matches!(needs_upgrade(&path, &LogOptions::default()), Err(KVError::Unsupported(_))));
+        assert!(matches!(upgrade(&path, &LogOptions::default()), Err(KVError::Unsupported(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), b"some other file, not a log at all");
+    }
+
+    #[test]
+    fn damaged_v1_record_stops_the_upgrade() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        write_v1(&path, &[
+            Entry::new(b"a".to_vec(), b"1".to_vec()),
+            Entry::new(b"b".to_vec(), b"2".to_vec()),
+            Entry::new(b"c".to_vec(), b"3".to_vec()),
+        ]);
+        let mut data = std::fs::read(&path).unwrap();
+        // the value of b
+        let at = data.windows(2).position(|w| w == b"b2").unwrap() + 1;
+        data[at] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        assert!(matches!(upgrade(&path, &LogOptions::default()), Err(KVError::Corruption(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        // the same damage in the last record is a torn tail
+        write_v1(&path, &[Entry::new(b"a".to_vec(), b"1".to_vec()), Entry::new(b"b".to_vec(), b"2".to_vec())]);
+        let mut data = std::fs::read(&path).unwrap();
+        data.truncate(data.len() - 3);
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        assert!(upgrade(&path, &LogOptions::default()).unwrap());
+        let mut log = Log::open(&path).unwrap();
+        assert_eq!(log.read().unwrap().unwrap().key(), b"a");
+        assert!(log.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn upgrade_can_encrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        write_v1(&path, &[Entry::new(b"card".to_vec(), b"4111-1111".to_vec())]);
+        assert!(upgrade(&path, &crypt_opts(5)).unwrap());
+
+        let data = std::fs::read(&path).unwrap();
+        assert!(!data.windows(4).any(|w| w == b"4111"));
+
+        let mut log = Log::open_with(&path, crypt_opts(5)).unwrap();
+        assert_eq!(log.read().unwrap().unwrap().value(), b"4111-1111");
+    }
+
+    #[test]
+    fn upgrade_from_v2_moves_blob_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut header = FileHeader::new(None);
+        header.version = 2;
+        let mut data = header.encode();
+
+        let mut chunks = Vec::new();
+        for part in [&b"hello "[..], b"world"] {
+            let rec = encode_v2(b"big", part, EntryKind::BlobChunk);
+            chunks.push(RecordPos { offset: data.len() as u64, len: rec.len() as u32 });
+            data.extend_from_slice(&rec);
+        }
+        let mut manifest = Vec::new();
+        BlobManifest { len: 11, chunks }.encode(&mut manifest);
+        data.extend_from_slice(&encode_v2(b"big", &manifest, EntryKind::BlobRef));
+        std::fs::write(&path, &data).unwrap();
+
+        assert!(matches!(Log::open(&path), Err(KVError::Unsupported(_))));
+        assert!(upgrade(&path, &LogOptions::default()).unwrap());
+
+        let mut log = Log::open(&path).unwrap();
+        let mut last = None;
+        while let Some(entry) = log.read().unwrap() {
+            last = Some(entry);
+        }
+        let last = last.unwrap();
+        assert_eq!(last.kind(), EntryKind::BlobRef);
+        assert_eq!(last.lsn(), 3);
+
+        let manifest = BlobManifest::decode(&mut last.value()).unwrap();
+        let mut out = String::new();
+        crate::core::blob::BlobReader::new(&log, b"big", &manifest)
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "hello world");
+    }
+
+    #[test]
+    fn read_only_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let ro = LogOptions { read_only: true, ..LogOptions::default() };
+        assert!(Log::open_with(&path, ro.clone()).is_err());
+        assert!(!path.exists());
+
+        Log::open(&path).unwrap().write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+
+        let mut log = Log::open_with(&path, ro).unwrap();
+        assert_eq!(log.read().unwrap().unwrap().key(), b"a");
+        let err = log.append(&mut Entry::new(b"b".to_vec(), b"2".to_vec())).err().unwrap();
+        assert!(matches!(err, KVError::ReadOnly));
+    }
 }
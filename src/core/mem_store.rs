@@ -0,0 +1,83 @@
+//! In-memory engine: nothing on disk, for tests and throwaway data
+use crate::core::batch::WriteOp;
+use crate::core::kv_store::{self, KvStore, Pair};
+use crate::error::KVError;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+
+#[derive(Debug, Clone, Default)]
+pub struct MemStore {
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KvStore for MemStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        Ok(self.map.get(key).cloned())
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, KVError> {
+        Ok(self.map.contains_key(key))
+    }
+
+    fn set(&mut self, key: &[u8], val: &[u8]) -> Result<bool, KVError> {
+        Ok(self.map.insert(key.to_vec(), val.to_vec()).is_some())
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<bool, KVError> {
+        Ok(self.map.remove(key).is_some())
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> impl Iterator<Item = Result<Pair, KVError>> + '_ {
+        let pairs = (!kv_store::is_empty_range(&range)).then(|| self.map.range(range));
+        pairs
+            .into_iter()
+            .flatten()
+            .map(|(key, val)| Ok((key.clone(), val.clone())))
+    }
+
+    // nothing here can fail half-way
+    fn apply(&mut self, ops: &[WriteOp]) -> Result<(), KVError> {
+        for op in ops {
+            match op {
+                WriteOp::Set(key, val) => self.map.insert(key.clone(), val.clone()),
+                WriteOp::Del(key) => self.map.remove(key),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn behaves_like_kv() {
+        let mut store = MemStore::new();
+
+        assert!(!store.set(b"b", b"1").unwrap());
+        assert!(store.set(b"b", b"2").unwrap());
+        store.apply(&[WriteOp::Set(b"a".to_vec(), b"0".to_vec()), WriteOp::Del(b"b".to_vec())]).unwrap();
+
+        assert_eq!(store.get(b"a").unwrap(), Some(b"0".to_vec()));
+        assert!(!store.contains(b"b").unwrap());
+        assert!(!store.del(b"b").unwrap());
+        let pairs: Vec<Pair> = store.scan(..).map(Result::unwrap).collect();
+        assert_eq!(pairs, [(b"a".to_vec(), b"0".to_vec())]);
+        assert_eq!(store.len(), 1);
+    }
+}
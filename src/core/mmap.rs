@@ -0,0 +1,275 @@
+//! Memory-mapped reads
+use crate::core::binary_serializer::{Entry, Limits, Seal};
+use crate::core::buffer_pool::{PageId, PAGE_SIZE};
+use crate::core::crypto::Cipher;
+use crate::core::file_header::{self, HEADER_LEN};
+use crate::core::log_storage::RecordPos;
+use crate::error::KVError;
+use libc::{mmap, munmap, MAP_FAILED, MAP_SHARED, PROT_READ};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+
+// read-only view of a whole file
+// the file must only grow while mapped: reading a truncated part is SIGBUS
+pub struct MmapReader {
+    file: File,
+    ptr: Option<NonNull<u8>>,
+    len: usize,
+    limits: Limits,
+    cipher: Option<Cipher>,
+}
+
+// the mapping is private to the reader and only read: it can move to
+// another thread with it (a KV behind a Mutex in the server)
+unsafe impl Send for MmapReader {}
+
+impl MmapReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_with(path, Limits::default(), None)
+    }
+
+    // limits and key for decoding records; the log header is checked by Log
+    pub fn open_with(path: &Path, limits: Limits, cipher: Option<Cipher>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = MmapReader {
+            file,
+            ptr: None,
+            len: 0,
+            limits,
+            cipher,
+        };
+        reader.remap()?;
+        Ok(reader)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self.ptr {
+            // the mapping lives until drop or remap, both need &mut self
+            Some(ptr) => unsafe { std::slice::from_raw_parts(ptr.as_ptr(), self.len) },
+            None => &[],
+        }
+    }
+
+    // map again if the file grew; true if the mapping changed
+    pub fn remap(&mut self) -> io::Result<bool> {
+        let len = self.file.metadata()?.len() as usize;
+        if len == self.len && (len == 0 || self.ptr.is_some()) {
+            return Ok(false);
+        }
+
+        self.unmap();
+
+        // mmap of 0 bytes is EINVAL
+        if len > 0 {
+            let ptr = unsafe {
+                mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    PROT_READ,
+                    MAP_SHARED,
+                    self.file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            self.ptr = NonNull::new(ptr as *mut u8);
+        }
+        self.len = len;
+
+        Ok(true)
+    }
+
+    // None if the record is not mapped (yet)
+    pub fn entry_at(&self, pos: RecordPos) -> Option<Result<Entry, KVError>> {
+        let start = pos.offset as usize;
+        let end = start.checked_add(pos.len as usize)?;
+        let mut data = self.as_slice().get(start..end)?;
+        Some(Entry::decode_sealed(&mut data, &self.limits, self.seal(pos.offset)))
+    }
+
+    // all intact records after the log header,
+    // stops at the first bad or partial one
+    pub fn entries(&self) -> Entries<'_> {
+        let start = if file_header::has_magic(self.as_slice()) {
+            HEADER_LEN.min(self.len)
+        } else {
+            0
+        };
+        Entries {
+            reader: self,
+            data: &self.as_slice()[start..],
+            offset: start as u64,
+        }
+    }
+
+    fn seal(&self, offset: u64) -> Option<Seal<'_>> {
+        self.cipher.as_ref().map(|cipher| Seal { cipher, offset })
+    }
+
+    // None for pages not (fully) mapped
+    pub fn page(&self, id: PageId) -> Option<&[u8]> {
+        let start = (id as usize).checked_mul(PAGE_SIZE)?;
+        self.as_slice().get(start..start + PAGE_SIZE)
+    }
+
+    fn unmap(&mut self) {
+        if let Some(ptr) = self.ptr.take() {
+            unsafe { munmap(ptr.as_ptr() as *mut libc::c_void, self.len) };
+        }
+        self.len = 0;
+    }
+}
+
+impl Drop for MmapReader {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
+
+pub struct Entries<'a> {
+    reader: &'a MmapReader,
+    data: &'a [u8],
+    offset: u64,
+}
+
+impl Iterator for Entries<'_> {
+    type Item = (RecordPos, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut rest = self.data;
+        let seal = self.reader.seal(self.offset);
+        let entry = Entry::decode_sealed(&mut rest, &self.reader.limits, seal).ok()?;
+
+        let len = self.data.len() - rest.len();
+        let pos = RecordPos {
+            offset: self.offset,
+            len: len as u32,
+        };
+        self.data = rest;
+        self.offset += len as u64;
+
+        Some((pos, entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::log_storage::Log;
+
+    #[test]
+    fn empty_file_maps_to_empty_slice() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        std::fs::write(&path, b"").unwrap();
+
+        let reader = MmapReader::open(&path).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(reader.entries().count(), 0);
+    }
+
+    #[test]
+    fn reads_log_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut log = Log::open(&path).unwrap();
+        let p1 = log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+        let p2 = log.write(&mut Entry::tombstone(b"a".to_vec())).unwrap();
+
+        let reader = MmapReader::open(&path).unwrap();
+
+        let all: Vec<_> = reader.entries().collect();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].0, p1);
+        assert_eq!(all[1].0, p2);
+        assert!(all[1].1.is_deleted());
+
+        let e1 = reader.entry_at(p1).unwrap().unwrap();
+        assert_eq!(e1.value(), b"1");
+    }
+
+    #[test]
+    fn remap_after_growth() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut log = Log::open(&path).unwrap();
+        log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+
+        let mut reader = MmapReader::open(&path).unwrap();
+
+        let p2 = log.write(&mut Entry::new(b"b".to_vec(), b"2".to_vec())).unwrap();
+        assert!(reader.entry_at(p2).is_none());
+
+        assert!(reader.remap().unwrap());
+        assert!(!reader.remap().unwrap());
+
+        let e2 = reader.entry_at(p2).unwrap().unwrap();
+        assert_eq!(e2.key(), b"b");
+    }
+
+    #[test]
+    fn entries_stop_at_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut log = Log::open(&path).unwrap();
+        log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+        drop(log);
+
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[7, 7, 7]);
+        std::fs::write(&path, &data).unwrap();
+
+        let reader = MmapReader::open(&path).unwrap();
+        assert_eq!(reader.entries().count(), 1);
+    }
+
+    #[test]
+    fn pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        let mut data = vec![0u8; PAGE_SIZE * 2];
+        data[PAGE_SIZE] = 9;
+        std::fs::write(&path, &data).unwrap();
+
+        let reader = MmapReader::open(&path).unwrap();
+        assert_eq!(reader.page(1).unwrap()[0], 9);
+        assert!(reader.page(2).is_none());
+    }
+
+    #[test]
+    fn reads_encrypted_log() {
+        use crate::core::log_storage::LogOptions;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let cipher = Cipher::new(1, &[3; 32]);
+
+        let opts = LogOptions { cipher: Some(cipher.clone()), ..LogOptions::default() };
+        let mut log = Log::open_with(&path, opts).unwrap();
+        let p1 = log.write(&mut Entry::new(b"a".to_vec(), b"1".to_vec())).unwrap();
+
+        let reader = MmapReader::open_with(&path, Limits::default(), Some(cipher)).unwrap();
+        assert_eq!(reader.entry_at(p1).unwrap().unwrap().value(), b"1");
+
+        let all: Vec<_> = reader.entries().collect();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].0, p1);
+    }
+}
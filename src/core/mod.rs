@@ -1,4 +1,23 @@
 pub mod binary_serializer;
 pub mod key_value;
 pub mod log_storage;
-pub mod fsync;
\ No newline at end of file
+pub mod fsync;
+pub mod key_dir;
+pub mod snapshot;
+pub mod buffer_pool;
+pub mod mmap;
+pub mod pager;
+pub mod btree;
+pub mod blob;
+pub mod crypto;
+pub mod file_header;
+pub mod cdc;
+pub mod replication;
+pub mod kv_store;
+pub mod batch;
+pub mod mem_store;
+pub mod vfs;
+pub mod sim_vfs;
+pub mod db_lock;
+#[cfg(test)]
+mod crash_tests;
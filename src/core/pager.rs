@@ -0,0 +1,597 @@
+//! Page file with a persistent free list
+use crate::core::buffer_pool::{BufferPool, PageId, PAGE_SIZE};
+use crate::core::vfs::{OpenMode, StdVfs, Vfs};
+use crate::error::KVError;
+use crc32fast::Hasher;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::Path;
+
+const META_MAGIC: &[u8; 8] = b"SDBPAGE2";
+
+// two meta pages, commits alternate between them:
+// a torn meta write leaves the previous one intact
+const META_PAGES: [PageId; 2] = [0, 1];
+const FIRST_DATA_PAGE: PageId = 2;
+
+// free list page: | next | count | ids... |
+//                 | 8    | 4     | 8 * count |
+const LIST_HEADER: usize = 12;
+const LIST_CAP: usize = (PAGE_SIZE - LIST_HEADER) / 8;
+
+// page 0 is meta, so 0 doubles as "no page"
+pub const NO_PAGE: PageId = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    seq: u64,
+    npages: u64,
+    root: PageId,
+    free_head: PageId,
+}
+
+impl Meta {
+    // | magic | seq | npages | root | free_head | crc32 |
+    // | 8     | 8   | 8      | 8    | 8         | 4     |
+    fn encode(&self, page: &mut [u8]) {
+        page.fill(0);
+        page[0..8].copy_from_slice(META_MAGIC);
+        page[8..16].copy_from_slice(&self.seq.to_le_bytes());
+        page[16..24].copy_from_slice(&self.npages.to_le_bytes());
+        page[24..32].copy_from_slice(&self.root.to_le_bytes());
+        page[32..40].copy_from_slice(&self.free_head.to_le_bytes());
+
+        let mut hasher = Hasher::new();
+        hasher.update(&page[0..40]);
+        page[40..44].copy_from_slice(&hasher.finalize().to_le_bytes());
+    }
+
+    // None if torn or never written
+    fn decode(page: &[u8]) -> Option<Self> {
+        let mut hasher = Hasher::new();
+        hasher.update(&page[0..40]);
+        let crc = u32::from_le_bytes(page[40..44].try_into().unwrap());
+
+        if &page[0..8] != META_MAGIC || hasher.finalize() != crc {
+            return None;
+        }
+
+        Some(Meta {
+            seq: u64::from_le_bytes(page[8..16].try_into().unwrap()),
+            npages: u64::from_le_bytes(page[16..24].try_into().unwrap()),
+            root: u64::from_le_bytes(page[24..32].try_into().unwrap()),
+            free_head: u64::from_le_bytes(page[32..40].try_into().unwrap()),
+        })
+    }
+}
+
+// Free pages are only reused after the commit that released them:
+// until the new meta page is durable the old pages are still live.
+// Pages released while a read snapshot may still see them are held back
+// in memory (on disk they are free) until the snapshot is released.
+pub struct Pager {
+    pool: BufferPool,
+    seq: u64,
+    npages: u64,
+    root: PageId,
+    // reusable now
+    free: Vec<PageId>,
+    // released since the last commit
+    pending: Vec<PageId>,
+    // handed out since the last commit
+    allocated: Vec<PageId>,
+    // released by commit `seq`, reusable once no snapshot older than it is open
+    retired: Vec<(u64, Vec<PageId>)>,
+    // pages holding the committed free list
+    list_pages: Vec<PageId>,
+    // open read snapshots: commit seq -> count
+    readers: BTreeMap<u64, usize>,
+}
+
+impl Pager {
+    // budget: buffer pool memory in bytes
+    pub fn open(path: &Path, budget: usize) -> Result<Self, KVError> {
+        Self::open_in(&StdVfs, path, budget)
+    }
+
+    pub fn open_in(vfs: &dyn Vfs, path: &Path, budget: usize) -> Result<Self, KVError> {
+        let is_new = !vfs.exists(path) || vfs.open(path, OpenMode::Read)?.len()? == 0;
+        let pool = BufferPool::open_in(vfs, path, budget)?;
+
+        let mut pager = Pager {
+            pool,
+            seq: 0,
+            npages: FIRST_DATA_PAGE,
+            root: NO_PAGE,
+            free: Vec::new(),
+            pending: Vec::new(),
+            allocated: Vec::new(),
+            retired: Vec::new(),
+            list_pages: Vec::new(),
+            readers: BTreeMap::new(),
+        };
+
+        if is_new {
+            pager.commit()?;
+        } else {
+            let meta = pager.load_meta()?;
+            let (free, list_pages) = pager.load_list(meta.free_head, meta.npages)?;
+            pager.seq = meta.seq;
+            pager.npages = meta.npages;
+            pager.root = meta.root;
+            pager.free = free;
+            pager.list_pages = list_pages;
+        }
+
+        Ok(pager)
+    }
+
+    pub fn npages(&self) -> u64 {
+        self.npages
+    }
+
+    // last committed transaction
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // root page of the data structure, saved by commit
+    pub fn root(&self) -> PageId {
+        self.root
+    }
+
+    pub fn set_root(&mut self, root: PageId) {
+        self.root = root;
+    }
+
+    // reuse a free page or grow the file
+    pub fn alloc(&mut self) -> PageId {
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.npages += 1;
+                self.npages - 1
+            }
+        };
+        self.allocated.push(id);
+        id
+    }
+
+    // a page out of range comes from a corrupt tree
+    pub fn free(&mut self, id: PageId) -> Result<(), KVError> {
+        if id < FIRST_DATA_PAGE || id >= self.npages {
+            return Err(corrupt(&format!("freeing bad page {id}")));
+        }
+        self.pending.push(id);
+        Ok(())
+    }
+
+    pub fn read_page(&mut self, id: PageId) -> Result<Vec<u8>, KVError> {
+        self.pool.pin(id)?;
+        let data = self.pool.page(id)?.to_vec();
+        self.pool.unpin(id)?;
+        Ok(data)
+    }
+
+    pub fn write_page(&mut self, id: PageId, data: &[u8]) -> Result<(), KVError> {
+        assert!(data.len() <= PAGE_SIZE);
+        self.pool.pin(id)?;
+        let page = self.pool.page_mut(id)?;
+        page[..data.len()].copy_from_slice(data);
+        page[data.len()..].fill(0);
+        self.pool.unpin(id)?;
+        Ok(())
+    }
+
+    // Forget the changes since the last commit: released pages are still
+    // used by the committed state, allocated ones go back to the free list.
+    // Grown pages stay: the next commit lists them as free.
+    pub fn rollback(&mut self) {
+        self.pending.clear();
+        self.free.append(&mut self.allocated);
+    }
+
+    // keep the pages of the last committed state; returns its seq
+    pub fn hold_snapshot(&mut self) -> u64 {
+        *self.readers.entry(self.seq).or_insert(0) += 1;
+        self.seq
+    }
+
+    // a seq not held is a caller bug: an error, nothing changes
+    pub fn release_snapshot(&mut self, seq: u64) -> Result<(), KVError> {
+        let Some(count) = self.readers.get_mut(&seq) else {
+            return Err(KVError::Io(io::Error::other(format!("pager: snapshot {seq} is not held"))));
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.readers.remove(&seq);
+        }
+        self.reclaim();
+        Ok(())
+    }
+
+    // Write a new copy of the free list and fsync, then write the other
+    // meta page with the new root and fsync again. The meta write is the
+    // commit point.
+    pub fn commit(&mut self) -> Result<(), KVError> {
+        // new list pages come from pages that are free in the committed state
+        let mut new_pages = Vec::new();
+        while new_pages.len() < pages_for(self.list_len()) {
+            new_pages.push(self.alloc());
+        }
+
+        let mut entries = self.free.clone();
+        entries.extend_from_slice(&self.pending);
+        entries.extend_from_slice(&self.list_pages);
+        for (_, ids) in &self.retired {
+            entries.extend_from_slice(ids);
+        }
+
+        for (i, chunk) in entries.chunks(LIST_CAP).enumerate() {
+            let next = new_pages.get(i + 1).copied().unwrap_or(NO_PAGE);
+            let mut page = vec![0u8; PAGE_SIZE];
+            page[0..8].copy_from_slice(&next.to_le_bytes());
+            page[8..12].copy_from_slice(&(chunk.len() as u32).to_le_bytes());
+            for (j, id) in chunk.iter().enumerate() {
+                let at = LIST_HEADER + j * 8;
+                page[at..at + 8].copy_from_slice(&id.to_le_bytes());
+            }
+            self.write_page(new_pages[i], &page)?;
+        }
+        self.pool.flush()?;
+
+        let meta = Meta {
+            seq: self.seq + 1,
+            npages: self.npages,
+            root: self.root,
+            free_head: new_pages.first().copied().unwrap_or(NO_PAGE),
+        };
+        let mut page = vec![0u8; PAGE_SIZE];
+        meta.encode(&mut page);
+        self.write_page(META_PAGES[(meta.seq % 2) as usize], &page)?;
+        self.pool.flush()?;
+
+        self.seq = meta.seq;
+        // old list pages are not read by snapshots
+        let old_list = std::mem::replace(&mut self.list_pages, new_pages);
+        self.free.extend(old_list);
+        self.allocated.clear();
+        let released = std::mem::take(&mut self.pending);
+        if !released.is_empty() {
+            self.retired.push((self.seq, released));
+        }
+        self.reclaim();
+
+        Ok(())
+    }
+
+    // Consistency check of the committed state on disk. `used` are the pages
+    // referenced by the data structure; every page must be exactly one of
+    // meta, used, free or free list.
+    pub fn check(&mut self, used: &HashSet<PageId>) -> Result<(), KVError> {
+        let meta = self.load_meta()?;
+        let (free, list_pages) = self.load_list(meta.free_head, meta.npages)?;
+
+        let mut seen: HashSet<PageId> = META_PAGES.into_iter().collect();
+
+        if meta.root != NO_PAGE && !used.contains(&meta.root) {
+            return Err(corrupt(&format!("root page {} is not in use", meta.root)));
+        }
+
+        let groups = [("free", &free), ("free list", &list_pages)];
+        for (what, ids) in groups {
+            for &id in ids {
+                if id < FIRST_DATA_PAGE || id >= meta.npages {
+                    return Err(corrupt(&format!("{what} page {id} out of range")));
+                }
+                if used.contains(&id) {
+                    return Err(corrupt(&format!("{what} page {id} is in use")));
+                }
+                if !seen.insert(id) {
+                    return Err(corrupt(&format!("{what} page {id} listed twice")));
+                }
+            }
+        }
+
+        for id in FIRST_DATA_PAGE..meta.npages {
+            if !seen.contains(&id) && !used.contains(&id) {
+                return Err(corrupt(&format!("page {id} leaked")));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn list_len(&self) -> usize {
+        let retired: usize = self.retired.iter().map(|(_, ids)| ids.len()).sum();
+        self.free.len() + self.pending.len() + self.list_pages.len() + retired
+    }
+
+    // retired pages nobody can see any more become reusable
+    fn reclaim(&mut self) {
+        let oldest = self.readers.keys().next().copied().unwrap_or(u64::MAX);
+        let (ready, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retired)
+            .into_iter()
+            .partition(|(seq, _)| *seq <= oldest);
+        self.retired = kept;
+        for (_, ids) in ready {
+            self.free.extend(ids);
+        }
+    }
+
+    // newest intact meta page
+    fn load_meta(&mut self) -> Result<Meta, KVError> {
+        let mut best: Option<Meta> = None;
+        for id in META_PAGES {
+            if let Some(meta) = Meta::decode(&self.read_page(id)?)
+                && best.is_none_or(|b| meta.seq > b.seq)
+            {
+                best = Some(meta);
+            }
+        }
+        best.ok_or_else(|| corrupt("no valid meta page"))
+    }
+
+    // -> (free ids, pages holding them)
+    fn load_list(&mut self, head: PageId, npages: u64) -> Result<(Vec<PageId>, Vec<PageId>), KVError> {
+        let mut free = Vec::new();
+        let mut list_pages = Vec::new();
+
+        let mut next = head;
+        while next != NO_PAGE {
+            // a cycle would loop forever
+            if next >= npages || list_pages.len() as u64 >= npages {
+                return Err(corrupt("bad free list chain"));
+            }
+            list_pages.push(next);
+
+            let page = self.read_page(next)?;
+            let count = u32::from_le_bytes(page[8..12].try_into().unwrap()) as usize;
+            if count > LIST_CAP {
+                return Err(corrupt("bad free list page"));
+            }
+            for j in 0..count {
+                let at = LIST_HEADER + j * 8;
+                free.push(u64::from_le_bytes(page[at..at + 8].try_into().unwrap()));
+            }
+
+            next = u64::from_le_bytes(page[0..8].try_into().unwrap());
+        }
+
+        Ok((free, list_pages))
+    }
+}
+
+fn pages_for(entries: usize) -> usize {
+    entries.div_ceil(LIST_CAP)
+}
+
+fn corrupt(msg: &str) -> KVError {
+    KVError::corruption(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUDGET: usize = 16 * PAGE_SIZE;
+
+    fn used(ids: &[PageId]) -> HashSet<PageId> {
+        ids.iter().copied().collect()
+    }
+
+    #[test]
+    fn new_file_has_only_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pager = Pager::open(&dir.path().join("data.db"), BUDGET).unwrap();
+
+        assert_eq!(pager.npages(), 2);
+        assert_eq!(pager.root(), NO_PAGE);
+        pager.check(&used(&[])).unwrap();
+    }
+
+    #[test]
+    fn freed_page_reused_after_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pager = Pager::open(&dir.path().join("data.db"), BUDGET).unwrap();
+
+        let a = pager.alloc();
+        let b = pager.alloc();
+        pager.commit().unwrap();
+
+        pager.free(a).unwrap();
+        // not before the commit
+        assert_ne!(pager.alloc(), a);
+
+        pager.commit().unwrap();
+        let mut reused = HashSet::new();
+        for _ in 0..3 {
+            reused.insert(pager.alloc());
+        }
+        assert!(reused.contains(&a));
+        assert!(!reused.contains(&b));
+    }
+
+    #[test]
+    fn free_list_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        let ids: Vec<PageId>;
+        {
+            let mut pager = Pager::open(&path, BUDGET).unwrap();
+            ids = (0..10).map(|_| pager.alloc()).collect();
+            pager.commit().unwrap();
+            for &id in &ids[..4] {
+                pager.free(id).unwrap();
+            }
+            pager.commit().unwrap();
+        }
+
+        let mut pager = Pager::open(&path, BUDGET).unwrap();
+        pager.check(&used(&ids[4..])).unwrap();
+
+        // freed pages come back before the file grows
+        let npages = pager.npages();
+        let mut got = HashSet::new();
+        while pager.npages() == npages {
+            got.insert(pager.alloc());
+        }
+        for id in &ids[..4] {
+            assert!(got.contains(id));
+        }
+    }
+
+    #[test]
+    fn uncommitted_changes_are_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        let ids: Vec<PageId>;
+        {
+            let mut pager = Pager::open(&path, BUDGET).unwrap();
+            ids = (0..3).map(|_| pager.alloc()).collect();
+            pager.commit().unwrap();
+
+            // "crash" before commit
+            pager.free(ids[0]).unwrap();
+            pager.alloc();
+            pager.alloc();
+        }
+
+        let mut pager = Pager::open(&path, BUDGET).unwrap();
+        pager.check(&used(&ids)).unwrap();
+    }
+
+    #[test]
+    fn long_free_list_spans_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        let n = LIST_CAP * 2 + 7;
+        {
+            let mut pager = Pager::open(&path, BUDGET).unwrap();
+            let ids: Vec<PageId> = (0..n).map(|_| pager.alloc()).collect();
+            pager.commit().unwrap();
+            for id in ids {
+                pager.free(id).unwrap();
+            }
+            pager.commit().unwrap();
+        }
+
+        let mut pager = Pager::open(&path, BUDGET).unwrap();
+        pager.check(&used(&[])).unwrap();
+        assert_eq!(pager.free.len(), n);
+        assert_eq!(pager.list_pages.len(), 3);
+    }
+
+    #[test]
+    fn checker_finds_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pager = Pager::open(&dir.path().join("data.db"), BUDGET).unwrap();
+
+        let a = pager.alloc();
+        let b = pager.alloc();
+        pager.commit().unwrap();
+        pager.free(a).unwrap();
+        pager.commit().unwrap();
+
+        // leak: b not referenced by anyone
+        assert!(pager.check(&used(&[])).is_err());
+        // a is free but claimed as used
+        assert!(pager.check(&used(&[a, b])).is_err());
+        pager.check(&used(&[b])).unwrap();
+
+        assert!(pager.free(1).is_err());
+        assert!(pager.free(pager.npages()).is_err());
+    }
+
+    #[test]
+    fn torn_meta_falls_back_to_previous() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        let root;
+        {
+            let mut pager = Pager::open(&path, BUDGET).unwrap();
+            root = pager.alloc();
+            pager.set_root(root);
+            pager.commit().unwrap();
+            assert_eq!(pager.seq(), 2);
+        }
+
+        // seq 2 went to meta page 0
+        let mut data = std::fs::read(&path).unwrap();
+        data[10] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let pager = Pager::open(&path, BUDGET).unwrap();
+        assert_eq!(pager.seq(), 1);
+        assert_eq!(pager.root(), NO_PAGE);
+        drop(pager);
+
+        // both torn
+        data[PAGE_SIZE + 10] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        assert!(Pager::open(&path, BUDGET).is_err());
+    }
+
+    #[test]
+    fn root_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+
+        let root;
+        {
+            let mut pager = Pager::open(&path, BUDGET).unwrap();
+            root = pager.alloc();
+            pager.set_root(root);
+            pager.commit().unwrap();
+        }
+
+        let mut pager = Pager::open(&path, BUDGET).unwrap();
+        assert_eq!(pager.root(), root);
+        pager.check(&used(&[root])).unwrap();
+    }
+
+    #[test]
+    fn rollback_restores_the_free_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pager = Pager::open(&dir.path().join("data.db"), BUDGET).unwrap();
+
+        let a = pager.alloc();
+        let b = pager.alloc();
+        pager.commit().unwrap();
+        pager.free(b).unwrap();
+        pager.commit().unwrap();
+
+        // reuses b, grows the file, releases a
+        let ids = [pager.alloc(), pager.alloc()];
+        assert!(ids.contains(&b));
+        pager.free(a).unwrap();
+        pager.rollback();
+        pager.commit().unwrap();
+        pager.check(&used(&[a])).unwrap();
+    }
+
+    #[test]
+    fn snapshot_holds_back_released_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pager = Pager::open(&dir.path().join("data.db"), BUDGET).unwrap();
+
+        let a = pager.alloc();
+        pager.commit().unwrap();
+
+        let snap = pager.hold_snapshot();
+        pager.free(a).unwrap();
+        pager.commit().unwrap();
+        assert!(!pager.free.contains(&a));
+
+        // on disk it is free already
+        pager.check(&used(&[])).unwrap();
+
+        pager.release_snapshot(snap).unwrap();
+        assert!(pager.free.contains(&a));
+        assert!(pager.release_snapshot(snap).is_err());
+    }
+}
@@ -0,0 +1,512 @@
+//! Leader/follower replication: the leader's log shipped over TCP
+use crate::core::batch::WriteOp;
+use crate::core::cdc::{Change, ChangeOp, Subscription};
+use crate::core::fsync::write_file_atomic;
+use crate::core::key_value::{Options, KV};
+use crate::core::log_storage::LogOptions;
+use crate::core::vfs::Vfs;
+use crate::error::KVError;
+use std::collections::HashSet;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// follower -> leader, once: | magic | applied lsn |
+const HELLO_MAGIC: &[u8; 8] = b"SDBREPL1";
+
+// leader -> follower frames, a tag byte first:
+const TAG_SNAPSHOT: u8 = 1; // | lsn |, then PAIRs, then SNAPSHOT_END
+const TAG_PAIR: u8 = 2; // | key | val |
+const TAG_SNAPSHOT_END: u8 = 3;
+const TAG_SET: u8 = 4; // | lsn | ts | key | val |
+const TAG_DEL: u8 = 5; // | lsn | ts | key |
+const TAG_CAUGHT_UP: u8 = 6; // | lsn |, also sent as a heartbeat
+const TAG_ERROR: u8 = 7; // | message |
+// the ops of one batch, applied together: | lsn | ts | count |, then
+// count times | TAG_SET | key | val | or | TAG_DEL | key |
+const TAG_BATCH: u8 = 8;
+
+// an idle leader still writes this often, so a gone follower is noticed
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct LeaderOptions {
+    // how the leader db is opened: value mode, limits, cipher
+    pub kv: Options,
+    // a follower more changes behind than this gets a snapshot
+    // instead of the log since its applied lsn
+    pub snapshot_behind: u64,
+    // how often an idle leader looks for new records
+    pub poll_interval: Duration,
+}
+
+impl Default for LeaderOptions {
+    fn default() -> Self {
+        LeaderOptions {
+            kv: Options::default(),
+            snapshot_behind: 10_000,
+            poll_interval: Duration::from_millis(10),
+        }
+    }
+}
+
+// Ships the log at `path` to every follower that connects, a thread each.
+// Reads the files only: the writer may be another KV, even another process.
+pub fn serve(
+    listener: TcpListener,
+    path: impl Into<PathBuf>,
+    opts: LeaderOptions,
+) -> Result<(), KVError> {
+    let path = path.into();
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let (path, opts) = (path.clone(), opts.clone());
+        // a follower that goes away ends its thread with an io error
+        std::thread::spawn(move || ship(stream, &path, &opts));
+    }
+    Ok(())
+}
+
+// one follower, until it disconnects
+pub fn ship(stream: TcpStream, path: &Path, opts: &LeaderOptions) -> Result<(), KVError> {
+    stream.set_nodelay(true)?;
+    let mut hello = [0u8; 16];
+    (&stream).read_exact(&mut hello)?;
+    if &hello[..8] != HELLO_MAGIC {
+        return Err(KVError::Replication("bad handshake".into()));
+    }
+    let mut applied = read_u64(&mut &hello[8..])?;
+    let mut out = BufWriter::new(&stream);
+
+    let view = KV::open_read_only_with(path, opts.kv.clone())?;
+    if applied > view.last_lsn() {
+        let msg = format!("follower at lsn {applied}, leader at {}", view.last_lsn());
+        out.write_all(&[TAG_ERROR])?;
+        write_bytes(&mut out, msg.as_bytes())?;
+        out.flush()?;
+        return Err(KVError::Replication(msg));
+    }
+    if view.last_lsn() - applied > opts.snapshot_behind {
+        out.write_all(&[TAG_SNAPSHOT])?;
+        out.write_all(&view.last_lsn().to_le_bytes())?;
+        for key in view.keys() {
+            let val = view.get(key)?.ok_or_else(|| KVError::corruption("key without a value"))?;
+            out.write_all(&[TAG_PAIR])?;
+            write_bytes(&mut out, key)?;
+            write_bytes(&mut out, &val)?;
+        }
+        out.write_all(&[TAG_SNAPSHOT_END])?;
+        applied = view.last_lsn();
+    }
+    drop(view);
+
+    let log_opts = LogOptions {
+        limits: opts.kv.limits,
+        cipher: opts.kv.cipher.clone(),
+        vfs: opts.kv.vfs.clone(),
+        ..LogOptions::default()
+    };
+    let mut sub = Subscription::open(path, log_opts, applied)?;
+    let mut reported = None;
+    let mut last_write = Instant::now();
+    loop {
+        match sub.next_commit()? {
+            Some(changes) => {
+                // one lsn for all of them: the follower skips by lsn
+                let (lsn, ts) = (changes[0].lsn, changes[0].ts);
+                if changes.len() > 1 {
+                    out.write_all(&[TAG_BATCH])?;
+                    out.write_all(&lsn.to_le_bytes())?;
+                    out.write_all(&ts.to_le_bytes())?;
+                    out.write_all(&(changes.len() as u64).to_le_bytes())?;
+                    for change in changes {
+                        write_op(&mut out, change)?;
+                    }
+                } else {
+                    for change in changes {
+                        let tag = match change.op {
+                            ChangeOp::Set(_) => TAG_SET,
+                            ChangeOp::Del => TAG_DEL,
+                        };
+                        out.write_all(&[tag])?;
+                        out.write_all(&lsn.to_le_bytes())?;
+                        out.write_all(&ts.to_le_bytes())?;
+                        write_op_body(&mut out, change)?;
+                    }
+                }
+                last_write = Instant::now();
+            }
+            None => {
+                let lsn = sub.last_lsn().max(applied);
+                if reported != Some(lsn) || last_write.elapsed() >= HEARTBEAT {
+                    out.write_all(&[TAG_CAUGHT_UP])?;
+                    out.write_all(&lsn.to_le_bytes())?;
+                    out.flush()?;
+                    reported = Some(lsn);
+                    last_write = Instant::now();
+                }
+                std::thread::sleep(opts.poll_interval);
+            }
+        }
+    }
+}
+
+// A read replica: applies the leader's changes to its own KV. The applied
+// lsn is the leader's, kept in `<log>.applied` next to the db. Applying a
+// set or a delete twice is harmless, so it is saved only when caught up.
+pub struct Follower {
+    kv: KV,
+    opts: Options,
+    applied: u64,
+}
+
+pub struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Follower {
+    pub fn open(path: impl Into<PathBuf>, opts: Options) -> Result<Self, KVError> {
+        let kv = KV::open_with(path, opts.clone())?;
+        let applied = read_applied(&*opts.vfs, &applied_path(kv.path()))?;
+        Ok(Follower { kv, opts, applied })
+    }
+
+    // leader lsn this replica is at
+    pub fn applied_lsn(&self) -> u64 {
+        self.applied
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KVError> {
+        self.kv.get(key)
+    }
+
+    // reads only: writes would be lost on the next snapshot
+    pub fn kv(&self) -> &KV {
+        &self.kv
+    }
+
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> Result<Connection, KVError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut hello = HELLO_MAGIC.to_vec();
+        hello.extend_from_slice(&self.applied.to_le_bytes());
+        (&stream).write_all(&hello)?;
+        Ok(Connection { stream: BufReader::new(stream) })
+    }
+
+    // apply changes until the leader says this replica has caught up
+    pub fn pull(&mut self, conn: &mut Connection) -> Result<(), KVError> {
+        let r = &mut conn.stream;
+        loop {
+            match read_u8(r)? {
+                TAG_SNAPSHOT => self.apply_snapshot(r)?,
+                TAG_SET => {
+                    let (lsn, _ts) = (read_u64(r)?, read_u64(r)?);
+                    let (key, val) = (read_bytes(r)?, read_bytes(r)?);
+                    if lsn > self.applied {
+                        self.apply_set(&key, &val)?;
+                        self.applied = lsn;
+                    }
+                }
+                TAG_DEL => {
+                    let (lsn, _ts) = (read_u64(r)?, read_u64(r)?);
+                    let key = read_bytes(r)?;
+                    if lsn > self.applied {
+                        self.kv.del(&key)?;
+                        self.applied = lsn;
+                    }
+                }
+                TAG_BATCH => {
+                    let (lsn, _ts) = (read_u64(r)?, read_u64(r)?);
+                    let count = read_u64(r)?;
+                    let mut ops = Vec::new();
+                    for _ in 0..count {
+                        ops.push(read_op(r)?);
+                    }
+                    if lsn > self.applied {
+                        self.kv.apply(&ops)?;
+                        self.applied = lsn;
+                    }
+                }
+                TAG_CAUGHT_UP => {
+                    let lsn = read_u64(r)?;
+                    if lsn != self.applied {
+                        return Err(KVError::Replication(format!(
+                            "leader at lsn {lsn}, follower applied {}",
+                            self.applied
+                        )));
+                    }
+                    return self.save_applied();
+                }
+                TAG_ERROR => {
+                    let msg = read_bytes(r)?;
+                    return Err(KVError::Replication(String::from_utf8_lossy(&msg).into_owned()));
+                }
+                tag => return Err(KVError::Replication(format!("unknown frame {tag}"))),
+            }
+        }
+    }
+
+    // pull forever; returns only on an error, e.g. the leader going away
+    pub fn run(&mut self, conn: &mut Connection) -> Result<(), KVError> {
+        loop {
+            self.pull(conn)?;
+        }
+    }
+
+    // keys the snapshot does not have are deleted at the end;
+    // cut short, the next connect sends a snapshot again
+    fn apply_snapshot(&mut self, r: &mut impl Read) -> Result<(), KVError> {
+        let lsn = read_u64(r)?;
+        let mut seen = HashSet::new();
+        loop {
+            match read_u8(r)? {
+                TAG_PAIR => {
+                    let (key, val) = (read_bytes(r)?, read_bytes(r)?);
+                    self.apply_set(&key, &val)?;
+                    seen.insert(key);
+                }
+                TAG_SNAPSHOT_END => break,
+                tag => return Err(KVError::Replication(format!("unknown frame {tag} in snapshot"))),
+            }
+        }
+
+        let stale: Vec<Vec<u8>> = self
+            .kv
+            .keys()
+            .filter(|key| !seen.contains(*key))
+            .map(<[u8]>::to_vec)
+            .collect();
+        for key in stale {
+            self.kv.del(&key)?;
+        }
+        self.applied = lsn;
+        self.save_applied()
+    }
+
+    // blobs arrive whole: too big for one record, they are chunked again
+    fn apply_set(&mut self, key: &[u8], val: &[u8]) -> Result<(), KVError> {
+        if val.len() > self.opts.limits.max_value_size {
+            self.kv.set_blob(key, val)?;
+        } else {
+            self.kv.set(key, val)?;
+        }
+        Ok(())
+    }
+
+    fn save_applied(&self) -> Result<(), KVError> {
+        write_file_atomic(&*self.opts.vfs, &applied_path(self.kv.path()), &self.applied.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+pub fn applied_path(log_path: &Path) -> PathBuf {
+    let mut path = log_path.as_os_str().to_owned();
+    path.push(".applied");
+    PathBuf::from(path)
+}
+
+fn read_applied(vfs: &dyn Vfs, path: &Path) -> Result<u64, KVError> {
+    match vfs.read(path) {
+        Ok(data) if data.len() == 8 => read_u64(&mut data.as_slice()),
+        Ok(_) => Err(KVError::corruption("bad applied lsn file")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_bytes(w: &mut impl Write, data: &[u8]) -> Result<(), KVError> {
+    w.write_all(&(data.len() as u64).to_le_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+// | TAG_SET | key | val | or | TAG_DEL | key |
+fn write_op(w: &mut impl Write, change: Change) -> Result<(), KVError> {
+    let tag = match change.op {
+        ChangeOp::Set(_) => TAG_SET,
+        ChangeOp::Del => TAG_DEL,
+    };
+    w.write_all(&[tag])?;
+    write_op_body(w, change)
+}
+
+// | key | val | or | key |
+fn write_op_body(w: &mut impl Write, change: Change) -> Result<(), KVError> {
+    write_bytes(w, &change.key)?;
+    match change.op {
+        ChangeOp::Set(val) => write_bytes(w, &val),
+        ChangeOp::Del => Ok(()),
+    }
+}
+
+fn read_op(r: &mut impl Read) -> Result<WriteOp, KVError> {
+    match read_u8(r)? {
+        TAG_SET => Ok(WriteOp::Set(read_bytes(r)?, read_bytes(r)?)),
+        TAG_DEL => Ok(WriteOp::Del(read_bytes(r)?)),
+        tag => Err(KVError::Replication(format!("unknown frame {tag} in batch"))),
+    }
+}
+
+// no allocation up front: the length comes from the network
+fn read_bytes(r: &mut impl Read) -> Result<Vec<u8>, KVError> {
+    let len = read_u64(r)?;
+    let mut data = Vec::new();
+    r.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(data)
+}
+
+fn read_u8(r: &mut impl Read) -> Result<u8, KVError> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64, KVError> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::kv_store::KvStore;
+    use crate::core::vfs::StdVfs;
+    use std::net::SocketAddr;
+
+    fn start_leader(path: &Path, opts: LeaderOptions) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let path = path.to_path_buf();
+        std::thread::spawn(move || serve(listener, path, opts));
+        addr
+    }
+
+    #[test]
+    fn follower_tails_the_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader_path = dir.path().join("leader.log");
+        let mut leader = KV::open(&leader_path).unwrap();
+        leader.set(b"a", b"1").unwrap();
+        let addr = start_leader(&leader_path, LeaderOptions::default());
+
+        let mut follower = Follower::open(dir.path().join("follower.log"), Options::default()).unwrap();
+        let mut conn = follower.connect(addr).unwrap();
+        follower.pull(&mut conn).unwrap();
+        assert_eq!(follower.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        leader.set(b"b", b"2").unwrap();
+        leader.del(b"a").unwrap();
+        follower.pull(&mut conn).unwrap();
+        assert!(follower.get(b"a").unwrap().is_none());
+        assert_eq!(follower.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(follower.applied_lsn(), leader.last_lsn());
+    }
+
+    #[test]
+    fn follower_resumes_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader_path = dir.path().join("leader.log");
+        let follower_path = dir.path().join("follower.log");
+        let mut leader = KV::open(&leader_path).unwrap();
+        let addr = start_leader(&leader_path, LeaderOptions::default());
+
+        leader.set(b"a", b"1").unwrap();
+        {
+            let mut follower = Follower::open(&follower_path, Options::default()).unwrap();
+            let mut conn = follower.connect(addr).unwrap();
+            follower.pull(&mut conn).unwrap();
+        }
+
+        leader.set(b"b", b"2").unwrap();
+        let mut follower = Follower::open(&follower_path, Options::default()).unwrap();
+        assert_eq!(follower.applied_lsn(), 1);
+        let mut conn = follower.connect(addr).unwrap();
+        follower.pull(&mut conn).unwrap();
+        assert_eq!(follower.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(follower.kv().last_lsn(), 2);
+    }
+
+    #[test]
+    fn far_behind_follower_gets_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader_path = dir.path().join("leader.log");
+        let mut leader = KV::open(&leader_path).unwrap();
+        let opts = LeaderOptions { snapshot_behind: 2, ..LeaderOptions::default() };
+        let addr = start_leader(&leader_path, opts);
+
+        leader.set(b"gone", b"x").unwrap();
+        let mut follower = Follower::open(dir.path().join("follower.log"), Options::default()).unwrap();
+        let mut conn = follower.connect(addr).unwrap();
+        follower.pull(&mut conn).unwrap();
+        drop(conn);
+
+        leader.del(b"gone").unwrap();
+        for i in 0..5u8 {
+            leader.set(&[i], b"v").unwrap();
+            leader.set(&[i], &[i]).unwrap();
+        }
+
+        let mut conn = follower.connect(addr).unwrap();
+        follower.pull(&mut conn).unwrap();
+        assert!(follower.get(b"gone").unwrap().is_none());
+        assert_eq!(follower.get(&[3]).unwrap(), Some(vec![3]));
+        assert_eq!(follower.applied_lsn(), leader.last_lsn());
+        // the snapshot is applied as a handful of sets, not the leader's history
+        assert!(follower.kv().last_lsn() < leader.last_lsn());
+    }
+
+    #[test]
+    fn follower_ahead_of_leader_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader_path = dir.path().join("leader.log");
+        KV::open(&leader_path).unwrap().set(b"a", b"1").unwrap();
+        let addr = start_leader(&leader_path, LeaderOptions::default());
+
+        let follower_path = dir.path().join("follower.log");
+        write_file_atomic(&StdVfs, &applied_path(&follower_path), &7u64.to_le_bytes()).unwrap();
+        let mut follower = Follower::open(&follower_path, Options::default()).unwrap();
+        let mut conn = follower.connect(addr).unwrap();
+        assert!(matches!(follower.pull(&mut conn), Err(KVError::Replication(_))));
+    }
+
+    #[test]
+    fn batches_reach_the_follower_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader_path = dir.path().join("leader.log");
+        let mut leader = KV::open(&leader_path).unwrap();
+        leader.set(b"old", b"x").unwrap();
+        let addr = start_leader(&leader_path, LeaderOptions::default());
+
+        let mut follower = Follower::open(dir.path().join("follower.log"), Options::default()).unwrap();
+        let mut conn = follower.connect(addr).unwrap();
+        follower.pull(&mut conn).unwrap();
+
+        leader
+            .apply(&[
+                WriteOp::Set(b"a".to_vec(), b"1".to_vec()),
+                WriteOp::Set(b"b".to_vec(), b"2".to_vec()),
+                WriteOp::Del(b"old".to_vec()),
+            ])
+            .unwrap();
+        let mut txn = leader.begin();
+        txn.set(b"c", b"3").unwrap();
+        txn.set(b"d", b"4").unwrap();
+        txn.commit().unwrap();
+
+        follower.pull(&mut conn).unwrap();
+        assert_eq!(follower.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(follower.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert!(follower.get(b"old").unwrap().is_none());
+        assert_eq!(follower.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(follower.get(b"d").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(follower.applied_lsn(), leader.last_lsn());
+        // each batch one record on the follower too
+        assert_eq!(follower.kv().last_lsn(), 3);
+    }
+}
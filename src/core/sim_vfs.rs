@@ -0,0 +1,441 @@
+//! Simulated file system for crash tests: forgets what was not synced,
+//! tears writes and fails calls on demand
+use crate::core::fsync::{Durability, parent_dir};
+use crate::core::vfs::{DirectFile, OpenMode, Vfs, VfsFile};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// chance per call, 0.0 = never
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    // write_at and set_len fail without changing the file
+    pub write_error: f64,
+    // sync fails and, like Linux, the unsynced writes are then dropped:
+    // reads still see them, a crash loses them, a later sync does not
+    // save them
+    pub sync_error: f64,
+}
+
+// splitmix64: small, seeded, good enough for picking faults
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // 0..n, 0 if n is 0
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        match n {
+            0 => 0,
+            n => self.next_u64() % n,
+        }
+    }
+
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64) < p * (1u64 << 53) as f64
+    }
+}
+
+type Ino = u64;
+
+// a change since the last sync
+enum Op {
+    Write(u64, Vec<u8>),
+    SetLen(u64),
+}
+
+#[derive(Default)]
+struct Inode {
+    // what reads see
+    data: Vec<u8>,
+    // what survives a crash for sure
+    synced: Vec<u8>,
+    // what may survive a crash, in order
+    pending: Vec<Op>,
+    // ranges dropped by a failed sync: the next sync keeps the old bytes
+    lost: Vec<(u64, u64)>,
+}
+
+fn apply(data: &mut Vec<u8>, op: &Op) {
+    match op {
+        Op::Write(offset, buf) => {
+            let start = *offset as usize;
+            if data.len() < start + buf.len() {
+                data.resize(start + buf.len(), 0);
+            }
+            data[start..start + buf.len()].copy_from_slice(buf);
+        }
+        Op::SetLen(len) => data.resize(*len as usize, 0),
+    }
+}
+
+struct State {
+    rng: Rng,
+    faults: Faults,
+    // calls that change something before the power goes out
+    power_left: Option<u64>,
+    // bumped by crash(): handles opened before are dead
+    epoch: u64,
+    names: HashMap<PathBuf, Ino>,
+    // names as of the last sync_dir of their directory
+    synced_names: HashMap<PathBuf, Ino>,
+    inodes: HashMap<Ino, Inode>,
+    next_ino: Ino,
+}
+
+impl State {
+    // a call that changes something; fails once the power is out
+    fn step(&mut self) -> io::Result<()> {
+        match &mut self.power_left {
+            Some(0) => Err(io::Error::other("simulated power cut")),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn inode(&mut self, ino: Ino) -> &mut Inode {
+        self.inodes.get_mut(&ino).expect("open file has an inode")
+    }
+
+    fn create(&mut self, path: &Path) -> Ino {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(ino, Inode::default());
+        self.names.insert(path.to_path_buf(), ino);
+        ino
+    }
+}
+
+// In-memory files with crash semantics. Clones share the files.
+// Directory changes (create, rename, remove) last only once the
+// directory is synced; file data only once the file is.
+#[derive(Clone)]
+pub struct SimVfs {
+    state: Arc<Mutex<State>>,
+}
+
+impl SimVfs {
+    pub fn new(seed: u64) -> Self {
+        SimVfs {
+            state: Arc::new(Mutex::new(State {
+                rng: Rng::new(seed),
+                faults: Faults::default(),
+                power_left: None,
+                epoch: 0,
+                names: HashMap::new(),
+                synced_names: HashMap::new(),
+                inodes: HashMap::new(),
+                next_ino: 1,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        self.lock().faults = faults;
+    }
+
+    // after `calls` more writes, syncs or directory changes every call
+    // fails, as if the machine stopped there; crash() brings it back
+    pub fn cut_power_after(&self, calls: u64) {
+        self.lock().power_left = Some(calls);
+    }
+
+    // Power loss and reboot: every file keeps what was synced plus a
+    // random prefix of its later changes, the last of them possibly torn;
+    // unsynced directory changes are undone. Handles opened before fail.
+    pub fn crash(&self) {
+        let mut state = self.lock();
+        let state = &mut *state;
+
+        for inode in state.inodes.values_mut() {
+            let mut data = std::mem::take(&mut inode.synced);
+            let kept = state.rng.below(inode.pending.len() as u64 + 1) as usize;
+            for (i, op) in inode.pending.iter().take(kept).enumerate() {
+                match op {
+                    Op::Write(offset, buf) if i + 1 == kept && state.rng.chance(0.5) => {
+                        let torn = state.rng.below(buf.len() as u64) as usize;
+                        apply(&mut data, &Op::Write(*offset, buf[..torn].to_vec()));
+                    }
+                    op => apply(&mut data, op),
+                }
+            }
+            *inode = Inode { data: data.clone(), synced: data, ..Inode::default() };
+        }
+
+        state.names = state.synced_names.clone();
+        let live: Vec<Ino> = state.names.values().copied().collect();
+        state.inodes.retain(|ino, _| live.contains(ino));
+        state.power_left = None;
+        state.epoch += 1;
+    }
+}
+
+struct SimFile {
+    state: Arc<Mutex<State>>,
+    ino: Ino,
+    epoch: u64,
+}
+
+impl SimFile {
+    fn lock(&self) -> io::Result<MutexGuard<'_, State>> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.epoch != self.epoch {
+            return Err(io::Error::other("file handle from before the crash"));
+        }
+        Ok(state)
+    }
+
+    fn change(&self, op: Op) -> io::Result<()> {
+        let mut state = self.lock()?;
+        state.step()?;
+        let p = state.faults.write_error;
+        if state.rng.chance(p) {
+            return Err(io::Error::other("simulated write error"));
+        }
+
+        let inode = state.inode(self.ino);
+        if let Op::Write(offset, buf) = &op {
+            // that part is dirty again
+            let end = offset + buf.len() as u64;
+            inode.lost.retain(|&(start, len)| start + len <= *offset || end <= start);
+        }
+        apply(&mut inode.data, &op);
+        inode.pending.push(op);
+        Ok(())
+    }
+}
+
+impl VfsFile for SimFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.lock()?;
+        let data = &state.inode(self.ino).data;
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.change(Op::Write(offset, buf.to_vec()))?;
+        Ok(buf.len())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        let mut state = self.lock()?;
+        Ok(state.inode(self.ino).data.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.change(Op::SetLen(len))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut state = self.lock()?;
+        state.step()?;
+        let p = state.faults.sync_error;
+        let failed = state.rng.chance(p);
+
+        let inode = state.inode(self.ino);
+        if failed {
+            for op in inode.pending.drain(..) {
+                if let Op::Write(offset, buf) = op {
+                    inode.lost.push((offset, buf.len() as u64));
+                }
+            }
+            return Err(io::Error::other("simulated fsync error"));
+        }
+
+        let mut synced = inode.data.clone();
+        for &(start, len) in &inode.lost {
+            for i in start as usize..((start + len) as usize).min(synced.len()) {
+                synced[i] = inode.synced.get(i).copied().unwrap_or(0);
+            }
+        }
+        inode.synced = synced;
+        inode.pending.clear();
+        inode.lost.clear();
+        Ok(())
+    }
+}
+
+impl Vfs for SimVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.lock();
+        let ino = match (mode, state.names.get(path).copied()) {
+            (OpenMode::Read, None) => return Err(io::ErrorKind::NotFound.into()),
+            (OpenMode::Read | OpenMode::Write, Some(ino)) => ino,
+            (OpenMode::Write | OpenMode::Truncate, None) => {
+                state.step()?;
+                state.create(path)
+            }
+            (OpenMode::Truncate, Some(ino)) => {
+                state.step()?;
+                let inode = state.inode(ino);
+                apply(&mut inode.data, &Op::SetLen(0));
+                inode.pending.push(Op::SetLen(0));
+                ino
+            }
+        };
+        Ok(Box::new(SimFile { state: self.state.clone(), ino, epoch: state.epoch }))
+    }
+
+    // no O_DIRECT here, but the same whole-block writes
+    fn open_with(&self, path: &Path, mode: OpenMode, durability: &Durability) -> io::Result<Box<dyn VfsFile>> {
+        let file = self.open(path, mode)?;
+        match durability.direct_io {
+            true => Ok(Box::new(DirectFile::new(file)?)),
+            false => Ok(file),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.lock().names.contains_key(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.step()?;
+        match state.names.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.step()?;
+        let ino = state.names.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        state.names.insert(to.to_path_buf(), ino);
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.lock();
+        Ok(state.names.keys().filter(|path| parent_dir(path) == dir).cloned().collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.step()?;
+        let p = state.faults.sync_error;
+        if state.rng.chance(p) {
+            return Err(io::Error::other("simulated fsync error"));
+        }
+
+        let state = &mut *state;
+        state.synced_names.retain(|path, _| parent_dir(path) != dir);
+        for (path, &ino) in &state.names {
+            if parent_dir(path) == dir {
+                state.synced_names.insert(path.clone(), ino);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(vfs: &SimVfs, path: &str) -> Vec<u8> {
+        vfs.read(Path::new(path)).unwrap()
+    }
+
+    #[test]
+    fn synced_data_survives_a_crash() {
+        let vfs = SimVfs::new(1);
+        let file = vfs.open(Path::new("/db/a"), OpenMode::Write).unwrap();
+        vfs.sync_dir(Path::new("/db")).unwrap();
+        file.write_all_at(b"kept", 0).unwrap();
+        file.sync().unwrap();
+
+        vfs.crash();
+        assert_eq!(read(&vfs, "/db/a"), b"kept");
+        assert!(file.write_all_at(b"x", 0).is_err());
+    }
+
+    #[test]
+    fn unsynced_changes_may_be_lost_or_torn() {
+        let mut outcomes = std::collections::HashSet::new();
+        for seed in 0..50 {
+            let vfs = SimVfs::new(seed);
+            let file = vfs.open(Path::new("/db/a"), OpenMode::Write).unwrap();
+            vfs.sync_dir(Path::new("/db")).unwrap();
+            file.write_all_at(b"abcd", 0).unwrap();
+
+            vfs.crash();
+            let data = read(&vfs, "/db/a");
+            assert!(b"abcd".starts_with(&data));
+            outcomes.insert(data);
+        }
+        // none, some or all of it
+        assert!(outcomes.len() > 2);
+    }
+
+    #[test]
+    fn directory_changes_need_sync_dir() {
+        let vfs = SimVfs::new(1);
+        let dir = Path::new("/db");
+        vfs.open(Path::new("/db/a"), OpenMode::Write).unwrap().sync().unwrap();
+        vfs.crash();
+        assert!(!vfs.exists(Path::new("/db/a")));
+
+        vfs.open(Path::new("/db/a"), OpenMode::Write).unwrap();
+        vfs.sync_dir(dir).unwrap();
+        vfs.rename(Path::new("/db/a"), Path::new("/db/b")).unwrap();
+        assert_eq!(vfs.read_dir(dir).unwrap(), [PathBuf::from("/db/b")]);
+        vfs.crash();
+        assert_eq!(vfs.read_dir(dir).unwrap(), [PathBuf::from("/db/a")]);
+    }
+
+    #[test]
+    fn failed_sync_drops_the_data_for_good() {
+        let vfs = SimVfs::new(1);
+        let file = vfs.open(Path::new("/db/a"), OpenMode::Write).unwrap();
+        vfs.sync_dir(Path::new("/db")).unwrap();
+
+        file.write_all_at(b"lost", 0).unwrap();
+        vfs.set_faults(Faults { sync_error: 1.0, ..Faults::default() });
+        assert!(file.sync().is_err());
+        vfs.set_faults(Faults::default());
+        // still read back, and a later sync says Ok
+        assert_eq!(read(&vfs, "/db/a"), b"lost");
+        file.write_all_at(b"kept", 4).unwrap();
+        file.sync().unwrap();
+
+        vfs.crash();
+        assert_eq!(read(&vfs, "/db/a"), b"\0\0\0\0kept");
+    }
+
+    #[test]
+    fn power_cut_fails_every_later_call() {
+        let vfs = SimVfs::new(1);
+        let file = vfs.open(Path::new("/db/a"), OpenMode::Write).unwrap();
+        vfs.cut_power_after(1);
+        file.write_all_at(b"a", 0).unwrap();
+        assert!(file.write_all_at(b"b", 1).is_err());
+        assert!(file.sync().is_err());
+
+        vfs.crash();
+        assert!(vfs.open(Path::new("/db/b"), OpenMode::Write).is_ok());
+    }
+}
@@ -0,0 +1,274 @@
+//! Snapshot files
+use crate::core::binary_serializer::Stamp;
+use crate::core::crypto::Cipher;
+use crate::core::fsync::{parent_dir, write_file_atomic};
+use crate::core::vfs::Vfs;
+use crate::core::key_dir::Slot;
+use crate::error::KVError;
+use crc32fast::Hasher;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const SNAP_MAGIC: &[u8; 8] = b"SDBSNAP3";
+const SNAP_SUFFIX: &str = ".snap.";
+
+// how many snapshots stay on disk, older ones are removed
+const SNAP_KEEP: usize = 2;
+
+// full db state at some log offset; slots are Inline or Blob
+// | magic | covered | lsn | ts | count | (key_len | key | slot)* | crc32 |
+// | 8     | 8       | 8   | 8  | 8     | 4        | ... | ...   | 4     |
+pub struct Snapshot {
+    pub covered: u64,
+    // stamp of the last record before `covered`
+    pub stamp: Stamp,
+    pub pairs: Vec<(Vec<u8>, Slot)>,
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(SNAP_MAGIC);
+        buf.extend_from_slice(&self.covered.to_le_bytes());
+        buf.extend_from_slice(&self.stamp.lsn.to_le_bytes());
+        buf.extend_from_slice(&self.stamp.ts.to_le_bytes());
+        buf.extend_from_slice(&(self.pairs.len() as u64).to_le_bytes());
+
+        for (key, slot) in &self.pairs {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+            slot.encode(&mut buf);
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&buf);
+        buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+        buf
+    }
+
+    // None if damaged
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < SNAP_MAGIC.len() + 8 + 16 + 8 + 4 {
+            return None;
+        }
+
+        let (body, crc) = data.split_at(data.len() - 4);
+        let mut hasher = Hasher::new();
+        hasher.update(body);
+        if hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return None;
+        }
+
+        if &body[..8] != SNAP_MAGIC {
+            return None;
+        }
+        let covered = u64::from_le_bytes(body[8..16].try_into().unwrap());
+        let stamp = Stamp {
+            lsn: u64::from_le_bytes(body[16..24].try_into().unwrap()),
+            ts: u64::from_le_bytes(body[24..32].try_into().unwrap()),
+        };
+        let count = u64::from_le_bytes(body[32..40].try_into().unwrap());
+
+        let mut pairs = Vec::new();
+        let mut rest = &body[40..];
+
+        for _ in 0..count {
+            let key_len = u32::from_le_bytes(rest.get(0..4)?.try_into().unwrap()) as usize;
+            let key = rest.get(4..4 + key_len)?.to_vec();
+            rest = &rest[4 + key_len..];
+
+            match Slot::decode(&mut rest)? {
+                Slot::OnDisk(_) | Slot::Batched { .. } => return None,
+                slot => pairs.push((key, slot)),
+            }
+        }
+
+        if !rest.is_empty() {
+            return None;
+        }
+
+        Some(Snapshot { covered, stamp, pairs })
+    }
+}
+
+// db.log -> db.log.snap.00000000000000001234
+pub fn snapshot_path(log_path: &Path, covered: u64) -> PathBuf {
+    let mut p = log_path.as_os_str().to_owned();
+    p.push(format!("{SNAP_SUFFIX}{covered:020}"));
+    PathBuf::from(p)
+}
+
+// snapshots of this log, newest first
+fn list_snapshots(vfs: &dyn Vfs, log_path: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let dir = parent_dir(log_path);
+    let Some(name) = log_path.file_name().and_then(|n| n.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{name}{SNAP_SUFFIX}");
+
+    let mut found = Vec::new();
+    for path in vfs.read_dir(dir)? {
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+        let Some(covered) = file_name.strip_prefix(&prefix) else { continue };
+        // skips leftover .tmp files too
+        let Ok(covered) = covered.parse::<u64>() else { continue };
+        found.push((covered, path));
+    }
+
+    found.sort_by_key(|s| std::cmp::Reverse(s.0));
+    Ok(found)
+}
+
+// with a cipher the whole file is sealed, magic as associated data
+pub fn write_snapshot(
+    vfs: &dyn Vfs,
+    log_path: &Path,
+    snap: &Snapshot,
+    cipher: Option<&Cipher>,
+) -> Result<(), KVError> {
+    let mut data = snap.encode();
+    if let Some(cipher) = cipher {
+        data = cipher.seal(SNAP_MAGIC, &data)?;
+    }
+    write_file_atomic(vfs, &snapshot_path(log_path, snap.covered), &data)?;
+
+    for (_, old) in list_snapshots(vfs, log_path)?.into_iter().skip(SNAP_KEEP) {
+        vfs.remove_file(&old)?;
+    }
+
+    Ok(())
+}
+
+// after the log was rewritten their offsets mean nothing
+pub fn remove_snapshots(vfs: &dyn Vfs, log_path: &Path) -> io::Result<()> {
+    for (_, path) in list_snapshots(vfs, log_path)? {
+        vfs.remove_file(&path)?;
+    }
+    Ok(())
+}
+
+// newest snapshot that is intact and not past the end of the log;
+// a sealed one that does not open is Tampered, it cannot be torn
+pub fn latest_snapshot(
+    vfs: &dyn Vfs,
+    log_path: &Path,
+    log_len: u64,
+    cipher: Option<&Cipher>,
+) -> Result<Option<Snapshot>, KVError> {
+    for (covered, path) in list_snapshots(vfs, log_path)? {
+        if covered > log_len {
+            continue;
+        }
+        let mut data = match vfs.read(&path) {
+            Ok(data) => data,
+            // removed by the writer since the listing, a read-only open races it
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(cipher) = cipher {
+            data = cipher.open(SNAP_MAGIC, &data)?;
+        }
+        if let Some(snap) = Snapshot::decode(&data)
+            && snap.covered == covered
+        {
+            return Ok(Some(snap));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::vfs::StdVfs;
+
+    fn snap(covered: u64) -> Snapshot {
+        Snapshot {
+            covered,
+            stamp: Stamp { lsn: covered / 2, ts: 1000 + covered },
+            pairs: vec![
+                (b"a".to_vec(), Slot::Inline(b"1".to_vec())),
+                (b"bb".to_vec(), Slot::Inline(Vec::new())),
+            ],
+        }
+    }
+
+    #[test]
+    fn encode_then_decode() {
+        let s = snap(42);
+        let decoded = Snapshot::decode(&s.encode()).unwrap();
+
+        assert_eq!(decoded.covered, 42);
+        assert_eq!(decoded.stamp, Stamp { lsn: 21, ts: 1042 });
+        assert_eq!(decoded.pairs, s.pairs);
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        let mut data = snap(42).encode();
+        data[26] ^= 1;
+
+        assert!(Snapshot::decode(&data).is_none());
+    }
+
+    #[test]
+    fn latest_valid_snapshot_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("db.log");
+
+        write_snapshot(&StdVfs, &log, &snap(10), None).unwrap();
+        write_snapshot(&StdVfs, &log, &snap(20), None).unwrap();
+
+        // newest one is torn
+        let newest = snapshot_path(&log, 20);
+        let data = std::fs::read(&newest).unwrap();
+        std::fs::write(&newest, &data[..data.len() - 3]).unwrap();
+
+        let found = latest_snapshot(&StdVfs, &log, 100, None).unwrap().unwrap();
+        assert_eq!(found.covered, 10);
+    }
+
+    #[test]
+    fn snapshot_past_log_end_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("db.log");
+
+        write_snapshot(&StdVfs, &log, &snap(50), None).unwrap();
+
+        assert!(latest_snapshot(&StdVfs, &log, 49, None).unwrap().is_none());
+        assert!(latest_snapshot(&StdVfs, &log, 50, None).unwrap().is_some());
+    }
+
+    #[test]
+    fn old_snapshots_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("db.log");
+
+        for covered in [1, 2, 3, 4] {
+            write_snapshot(&StdVfs, &log, &snap(covered), None).unwrap();
+        }
+
+        let left: Vec<u64> = list_snapshots(&StdVfs, &log).unwrap().iter().map(|s| s.0).collect();
+        assert_eq!(left, vec![4, 3]);
+    }
+
+    #[test]
+    fn sealed_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("db.log");
+        let cipher = Cipher::new(1, &[9; 32]);
+
+        write_snapshot(&StdVfs, &log, &snap(10), Some(&cipher)).unwrap();
+        let found = latest_snapshot(&StdVfs, &log, 10, Some(&cipher)).unwrap().unwrap();
+        assert_eq!(found.pairs, snap(10).pairs);
+
+        let path = snapshot_path(&log, 10);
+        let mut data = std::fs::read(&path).unwrap();
+        assert!(!data.starts_with(SNAP_MAGIC));
+
+        data[30] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert!(matches!(latest_snapshot(&StdVfs, &log, 10, Some(&cipher)), Err(KVError::Tampered(_))));
+    }
+}
@@ -0,0 +1,660 @@
+//! File system interface: every file the core reads or writes goes through a Vfs
+use crate::core::fsync::{Durability, sync_dir};
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    // must exist
+    Read,
+    // read and write, created if missing
+    Write,
+    // write, created if missing, emptied if not
+    Truncate,
+}
+
+// Positional reads and writes, like pread/pwrite: no shared cursor, so
+// one handle serves many readers.
+pub trait VfsFile: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    // data and size are durable once this returns Ok
+    fn sync(&self) -> io::Result<()>;
+
+    // like sync, but metadata a read does not need may stay behind
+    fn sync_data(&self) -> io::Result<()> {
+        self.sync()
+    }
+
+    // at least offset + len bytes long, the new bytes zeros, their space
+    // reserved up front
+    fn allocate(&self, offset: u64, len: u64) -> io::Result<()> {
+        if self.len()? < offset + len {
+            self.set_len(offset + len)?;
+        }
+        Ok(())
+    }
+
+    // Advisory lock on byte `at`, taken without waiting: false if another
+    // handle, in this process or not, holds a conflicting one. Held until
+    // unlock or the handle is dropped. Without locks every one is granted.
+    fn try_lock(&self, _at: u64, _kind: LockKind) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn unlock(&self, _at: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// A created, renamed or removed file is durable once its directory is
+// synced. Missing files are io::ErrorKind::NotFound.
+pub trait Vfs: Send + Sync {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>;
+
+    // with the open flags `durability` asks for; a Vfs without such flags
+    // opens the file as open() does
+    fn open_with(&self, path: &Path, mode: OpenMode, _durability: &Durability) -> io::Result<Box<dyn VfsFile>> {
+        self.open(path, mode)
+    }
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    // replaces `to` if it exists
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    // paths of the files in `dir`, in no particular order
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    // files of this Vfs are real files that can be mapped
+    fn supports_mmap(&self) -> bool {
+        false
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path, OpenMode::Read)?;
+        let mut data = vec![0u8; file.len()? as usize];
+        file.read_exact_at(&mut data, 0)?;
+        Ok(data)
+    }
+}
+
+// std::fs and fsync(2)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdVfs;
+
+impl VfsFile for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        FileExt::write_at(self, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn allocate(&self, offset: u64, len: u64) -> io::Result<()> {
+        let res = unsafe { libc::fallocate(self.as_raw_fd(), 0, offset as libc::off_t, len as libc::off_t) };
+        if res == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(e);
+        }
+        // the file system cannot reserve space: zeros will do
+        if VfsFile::len(self)? < offset + len {
+            File::set_len(self, offset + len)?;
+        }
+        Ok(())
+    }
+
+    fn try_lock(&self, at: u64, kind: LockKind) -> io::Result<bool> {
+        let l_type = match kind {
+            LockKind::Shared => libc::F_RDLCK,
+            LockKind::Exclusive => libc::F_WRLCK,
+        };
+        match ofd_lock(self, at, l_type as libc::c_short) {
+            Ok(()) => Ok(true),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EACCES)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn unlock(&self, at: u64) -> io::Result<()> {
+        ofd_lock(self, at, libc::F_UNLCK as libc::c_short)
+    }
+}
+
+// fcntl(F_OFD_SETLK) on one byte: the lock belongs to the open file, not
+// the process, so two handles conflict even in one process, and closing
+// some other handle of the file does not drop it as it would a POSIX lock
+fn ofd_lock(file: &File, at: u64, l_type: libc::c_short) -> io::Result<()> {
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = l_type;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = at as libc::off_t;
+    lock.l_len = 1;
+    let res = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &lock) };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+impl Vfs for StdVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        self.open_with(path, mode, &Durability::default())
+    }
+
+    fn open_with(&self, path: &Path, mode: OpenMode, durability: &Durability) -> io::Result<Box<dyn VfsFile>> {
+        let mut opts = OpenOptions::new();
+        match mode {
+            OpenMode::Read => opts.read(true),
+            OpenMode::Write => opts.read(true).write(true).create(true).truncate(false),
+            OpenMode::Truncate => opts.write(true).create(true).truncate(true),
+        };
+        let mut flags = 0;
+        if durability.direct_io {
+            flags |= libc::O_DIRECT;
+        }
+        if durability.write_through {
+            flags |= libc::O_DSYNC;
+        }
+        let file = opts.custom_flags(flags).open(path)?;
+        match durability.direct_io {
+            true => Ok(Box::new(DirectFile::new(Box::new(file))?)),
+            false => Ok(Box::new(file)),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(dir)?.map(|item| Ok(item?.path())).collect()
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        sync_dir(dir)
+    }
+
+    fn supports_mmap(&self) -> bool {
+        true
+    }
+}
+
+// O_DIRECT wants buffers, offsets and lengths aligned to the block size
+const DIRECT_ALIGN: u64 = 4096;
+
+// heap buffer aligned for O_DIRECT
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn zeroed(len: usize) -> Self {
+        let layout = Layout::from_size_align(len.max(1), DIRECT_ALIGN as usize).expect("valid layout");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+}
+
+// owns its allocation, as a Box<[u8]> would
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+fn align_down(n: u64) -> u64 {
+    n & !(DIRECT_ALIGN - 1)
+}
+
+fn align_up(n: u64) -> u64 {
+    align_down(n + DIRECT_ALIGN - 1)
+}
+
+// reads until `buf` is full or the file ends
+fn read_full_at(file: &dyn VfsFile, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+// A file opened with O_DIRECT: any read or write becomes whole aligned
+// blocks. The partial block the last write ended in is kept in memory,
+// so the next append fills it up from there: committed bytes are never
+// read back to be written out again. Whole blocks grow the file past
+// its length; sync and drop cut the padding off, so only preallocation
+// grows the file for good.
+pub(crate) struct DirectFile {
+    file: Box<dyn VfsFile>,
+    state: Mutex<DirectState>,
+}
+
+struct DirectState {
+    // what len() says: written or allocated
+    len: u64,
+    // on disk, with the padding of the last block
+    disk_len: u64,
+    // (offset, contents) of the block the last write ended in
+    tail: Option<(u64, AlignedBuf)>,
+}
+
+impl DirectFile {
+    pub(crate) fn new(file: Box<dyn VfsFile>) -> io::Result<Self> {
+        let len = file.len()?;
+        let state = DirectState { len, disk_len: len, tail: None };
+        Ok(DirectFile { file, state: Mutex::new(state) })
+    }
+
+    fn state(&self) -> MutexGuard<'_, DirectState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // the block at `at` as it is now: the kept tail, zeros past the end,
+    // or read from disk for a write that is not an append
+    fn load_block(&self, state: &DirectState, at: u64, block: &mut [u8]) -> io::Result<()> {
+        match &state.tail {
+            Some((tail_at, tail)) if *tail_at == at => block.copy_from_slice(tail),
+            _ if at >= state.len => block.fill(0),
+            _ => {
+                read_full_at(&*self.file, block, at)?;
+                // padding, or what a failed write left
+                let keep = (state.len - at).min(DIRECT_ALIGN) as usize;
+                block[keep..].fill(0);
+            }
+        }
+        Ok(())
+    }
+
+    fn trim(&self, state: &mut DirectState) -> io::Result<()> {
+        if state.disk_len > state.len {
+            self.file.set_len(state.len)?;
+            state.disk_len = state.len;
+        }
+        Ok(())
+    }
+}
+
+impl VfsFile for DirectFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = self.state().len;
+        if offset >= len {
+            return Ok(0);
+        }
+        let want = buf.len().min((len - offset) as usize);
+
+        let start = align_down(offset);
+        let mut block = AlignedBuf::zeroed((align_up(offset + want as u64) - start) as usize);
+        let read = read_full_at(&*self.file, &mut block, start)?;
+
+        let skip = (offset - start) as usize;
+        let n = read.saturating_sub(skip).min(want);
+        buf[..n].copy_from_slice(&block[skip..skip + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.state();
+        let end = offset + buf.len() as u64;
+        let start = align_down(offset);
+        let stop = align_up(end);
+        let mut block = AlignedBuf::zeroed((stop - start) as usize);
+
+        // what is already in the first and last block
+        self.load_block(&state, start, &mut block[..DIRECT_ALIGN as usize])?;
+        let last = stop - DIRECT_ALIGN;
+        if last > start {
+            self.load_block(&state, last, &mut block[(last - start) as usize..])?;
+        }
+
+        let skip = (offset - start) as usize;
+        block[skip..skip + buf.len()].copy_from_slice(buf);
+        state.disk_len = state.disk_len.max(stop);
+        self.file.write_all_at(&block, start)?;
+        state.len = state.len.max(end);
+
+        state.tail = match end % DIRECT_ALIGN {
+            0 => None,
+            _ => {
+                let mut tail = AlignedBuf::zeroed(DIRECT_ALIGN as usize);
+                tail.copy_from_slice(&block[(last - start) as usize..]);
+                Some((last, tail))
+            }
+        };
+        Ok(buf.len())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.state().len)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        self.file.set_len(len)?;
+        state.len = len;
+        state.disk_len = len;
+        if let Some((at, tail)) = &mut state.tail
+            && *at + DIRECT_ALIGN > len
+        {
+            tail[len.saturating_sub(*at) as usize..].fill(0);
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.trim(&mut self.state())?;
+        self.file.sync()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.trim(&mut self.state())?;
+        self.file.sync_data()
+    }
+
+    fn allocate(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        self.file.allocate(offset, len)?;
+        state.len = state.len.max(offset + len);
+        state.disk_len = state.disk_len.max(offset + len);
+        Ok(())
+    }
+
+    fn try_lock(&self, at: u64, kind: LockKind) -> io::Result<bool> {
+        self.file.try_lock(at, kind)
+    }
+
+    fn unlock(&self, at: u64) -> io::Result<()> {
+        self.file.unlock(at)
+    }
+}
+
+impl Drop for DirectFile {
+    // best effort: padding left by a crash reads as zeros, the end of the log
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        if state.disk_len > state.len {
+            let _ = self.file.set_len(state.len);
+        }
+    }
+}
+
+// A file that is only a buffer: sync does nothing, gone when dropped
+#[derive(Debug, Default)]
+pub struct MemFile {
+    data: RwLock<Vec<u8>>,
+}
+
+impl VfsFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.data.read().unwrap_or_else(|e| e.into_inner());
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut data = self.data.write().unwrap_or_else(|e| e.into_inner());
+        let end = offset as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap_or_else(|e| e.into_inner()).len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap_or_else(|e| e.into_inner()).resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// What options hold: cheap to clone, the real file system by default
+#[derive(Clone)]
+pub struct SharedVfs(Arc<dyn Vfs>);
+
+impl SharedVfs {
+    pub fn new(vfs: impl Vfs + 'static) -> Self {
+        SharedVfs(Arc::new(vfs))
+    }
+}
+
+impl<V: Vfs + 'static> From<Arc<V>> for SharedVfs {
+    fn from(vfs: Arc<V>) -> Self {
+        SharedVfs(vfs)
+    }
+}
+
+impl Default for SharedVfs {
+    fn default() -> Self {
+        SharedVfs::new(StdVfs)
+    }
+}
+
+impl Deref for SharedVfs {
+    type Target = dyn Vfs;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for SharedVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedVfs")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn std_vfs_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let vfs = StdVfs;
+
+        assert!(matches!(vfs.open(&path, OpenMode::Read), Err(e) if e.kind() == io::ErrorKind::NotFound));
+        let file = vfs.open(&path, OpenMode::Write).unwrap();
+        file.write_all_at(b"hello", 0).unwrap();
+        file.write_all_at(b"!", 7).unwrap();
+        file.sync().unwrap();
+        assert_eq!(vfs.read(&path).unwrap(), b"hello\0\0!");
+
+        vfs.rename(&path, &dir.path().join("b")).unwrap();
+        assert_eq!(vfs.read_dir(dir.path()).unwrap(), [dir.path().join("b")]);
+        vfs.open(&dir.path().join("b"), OpenMode::Truncate).unwrap();
+        assert!(vfs.read(&dir.path().join("b")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn direct_io_handles_unaligned_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let durability = Durability { direct_io: true, write_through: true, ..Durability::default() };
+        let file = match StdVfs.open_with(&path, OpenMode::Write, &durability) {
+            Ok(file) => file,
+            // tmpfs and some others refuse O_DIRECT
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("{e}"),
+        };
+
+        file.write_all_at(b"head", 0).unwrap();
+        file.write_all_at(&[7; 10], 4090).unwrap();
+        file.write_all_at(b"mid", 2).unwrap();
+        assert_eq!(file.len().unwrap(), 4100);
+        file.sync_data().unwrap();
+
+        let data = StdVfs.read(&path).unwrap();
+        assert_eq!(&data[..5], b"hemid");
+        assert!(data[5..4090].iter().all(|&b| b == 0));
+        assert_eq!(&data[4090..], [7; 10]);
+
+        let mut buf = [0u8; 8];
+        assert_eq!(file.read_at(&mut buf, 4094).unwrap(), 6);
+        assert_eq!(buf[..6], [7; 6]);
+        file.allocate(4100, 100).unwrap();
+        assert_eq!(file.len().unwrap(), 4200);
+
+        // appends inside preallocated space leave the size alone
+        file.allocate(4200, 8192).unwrap();
+        file.write_all_at(b"tail", 4200).unwrap();
+        file.sync_data().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 12392);
+        file.set_len(4204).unwrap();
+        file.write_all_at(b"!", 4204).unwrap();
+        drop(file);
+        let data = StdVfs.read(&path).unwrap();
+        assert_eq!(&data[4200..], b"tail!");
+    }
+
+    #[test]
+    fn direct_file_never_reads_back_its_tail() {
+        let vfs = crate::core::sim_vfs::SimVfs::new(1);
+        let path = Path::new("/a");
+        let file = DirectFile::new(vfs.open(path, OpenMode::Write).unwrap()).unwrap();
+        file.write_all_at(b"abc", 0).unwrap();
+
+        // the disk changes under it: the next append keeps what it wrote
+        vfs.open(path, OpenMode::Write).unwrap().write_all_at(b"X", 1).unwrap();
+        file.write_all_at(b"de", 3).unwrap();
+        file.sync().unwrap();
+        assert_eq!(vfs.read(path).unwrap(), b"abcde");
+    }
+
+    #[test]
+    fn mem_file_reads_what_was_written() {
+        let file = MemFile::default();
+        file.write_all_at(b"abc", 2).unwrap();
+        assert_eq!(file.len().unwrap(), 5);
+
+        let mut buf = [9u8; 5];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, *b"\0\0abc");
+        assert_eq!(file.read_at(&mut buf, 4).unwrap(), 1);
+        assert!(file.read_exact_at(&mut buf, 1).is_err());
+        file.set_len(1).unwrap();
+        assert_eq!(file.read_at(&mut buf, 3).unwrap(), 0);
+    }
+}
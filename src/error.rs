@@ -0,0 +1,120 @@
+//! Errors shared by core and model
+use crate::model::data_types::DecodeError;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum KVError {
+    Io(std::io::Error),
+    // data on disk is damaged: checksum, magic, broken structure
+    Corruption(String),
+    // encrypted data failed authentication: changed on purpose, not by a bad disk
+    Tampered(String),
+    // missing or different encryption key for an encrypted file
+    KeyMismatch(String),
+    // file from a newer version or with features we do not know
+    Unsupported(String),
+    KeyTooLarge { len: usize, max: usize },
+    ValueTooLarge { len: usize, max: usize },
+    // row or key does not match the table schema
+    SchemaMismatch(String),
+    Decode(DecodeError),
+    Conflict(String),
+    NotFound,
+    ConstraintViolation(String),
+    // write to a db opened read-only
+    ReadOnly,
+    // an fsync of the log failed: what was written since the last good one
+    // may be lost whatever later fsyncs say, so no more writes until reopen
+    Poisoned(String),
+    // another process (or handle) holds the db's lock file
+    Locked(String),
+    // leader and follower disagree: bad handshake, follower ahead of the leader
+    Replication(String),
+    // malformed request or reply on the wire
+    Protocol(String),
+}
+
+impl fmt::Display for KVError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KVError::Io(e) => write!(f, "io error: {e}"),
+            KVError::Corruption(msg) => write!(f, "corruption: {msg}"),
+            KVError::Tampered(msg) => write!(f, "tampered data: {msg}"),
+            KVError::KeyMismatch(msg) => write!(f, "encryption key mismatch: {msg}"),
+            KVError::Unsupported(msg) => write!(f, "unsupported: {msg}"),
+            KVError::KeyTooLarge { len, max } => {
+                write!(f, "key too large: {len} bytes, max {max}")
+            }
+            KVError::ValueTooLarge { len, max } => {
+                write!(f, "value too large: {len} bytes, max {max}")
+            }
+            KVError::SchemaMismatch(msg) => write!(f, "schema mismatch: {msg}"),
+            KVError::Decode(e) => write!(f, "decode error: {e}"),
+            KVError::Conflict(msg) => write!(f, "conflict: {msg}"),
+            KVError::NotFound => write!(f, "not found"),
+            KVError::ConstraintViolation(msg) => write!(f, "constraint violation: {msg}"),
+            KVError::ReadOnly => write!(f, "database is read-only"),
+            KVError::Poisoned(msg) => write!(f, "database poisoned by a failed fsync, reopen it: {msg}"),
+            KVError::Locked(msg) => write!(f, "database is locked: {msg}"),
+            KVError::Replication(msg) => write!(f, "replication: {msg}"),
+            KVError::Protocol(msg) => write!(f, "protocol error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for KVError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KVError::Io(e) => Some(e),
+            KVError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for KVError {
+    fn from(e: std::io::Error) -> Self {
+        KVError::Io(e)
+    }
+}
+
+impl From<DecodeError> for KVError {
+    fn from(e: DecodeError) -> Self {
+        KVError::Decode(e)
+    }
+}
+
+impl KVError {
+    pub(crate) fn corruption(msg: impl Into<String>) -> Self {
+        KVError::Corruption(msg.into())
+    }
+
+    // end of input while reading a record: a torn tail, not damage
+    pub fn is_eof(&self) -> bool {
+        matches!(self, KVError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn display_messages() {
+        let e = KVError::KeyTooLarge { len: 10, max: 4 };
+        assert_eq!(e.to_string(), "key too large: 10 bytes, max 4");
+
+        let e = KVError::from(DecodeError::UnknownType(9));
+        assert_eq!(e.to_string(), "decode error: unknown cell type 9");
+    }
+
+    #[test]
+    fn io_source_is_kept() {
+        let e = KVError::from(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof"));
+
+        assert!(e.is_eof());
+        assert!(e.source().is_some());
+        assert!(!KVError::NotFound.is_eof());
+    }
+}
@@ -1,2 +1,4 @@
 pub mod core;
 pub mod model;
+pub mod error;
+pub mod server;
@@ -1,3 +1,107 @@
-fn main() {
-    println!("Hello, Silly DB!");
+use silly_db::core::key_dir::ValueMode;
+use silly_db::core::key_value::{Options, KV};
+use silly_db::server::Server;
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: silly-db <db path> [--tcp <addr>] [--unix <socket path>] [--disk] [--read-only]
+  --tcp        listen on a TCP address (default 127.0.0.1:6380 if no --unix)
+  --unix       listen on a Unix socket; a stale socket file is replaced
+  --disk       keep only key positions in memory, values stay in the log
+  --read-only  serve the db as it is at startup, next to its writer; writes fail";
+
+struct Args {
+    db: PathBuf,
+    tcp: Option<String>,
+    unix: Option<PathBuf>,
+    disk: bool,
+    read_only: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut db = None;
+    let (mut tcp, mut unix, mut disk, mut read_only) = (None, None, false, false);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tcp" => tcp = Some(args.next().ok_or("--tcp needs an address")?),
+            "--unix" => unix = Some(PathBuf::from(args.next().ok_or("--unix needs a path")?)),
+            "--disk" => disk = true,
+            "--read-only" => read_only = true,
+            "-h" | "--help" => return Err(String::new()),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {flag}")),
+            path if db.is_none() => db = Some(PathBuf::from(path)),
+            extra => return Err(format!("unexpected argument {extra}")),
+        }
+    }
+    let db = db.ok_or("missing db path")?;
+    if tcp.is_none() && unix.is_none() {
+        tcp = Some("127.0.0.1:6380".into());
+    }
+    Ok(Args { db, tcp, unix, disk, read_only })
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let value_mode = if args.disk { ValueMode::Disk } else { ValueMode::Memory };
+    let opts = Options { value_mode, ..Options::default() };
+    let kv = match args.read_only {
+        true => KV::open_read_only_with(&args.db, opts)?,
+        false => KV::open_with(&args.db, opts)?,
+    };
+    let server = Server::new(kv);
+
+    let unix = match &args.unix {
+        Some(path) => {
+            // left over from a server that did not exit cleanly
+            if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            eprintln!("silly-db: {} on unix {}", args.db.display(), path.display());
+            Some(listener)
+        }
+        None => None,
+    };
+    let tcp = match &args.tcp {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)?;
+            eprintln!("silly-db: {} on tcp {}", args.db.display(), listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    match (tcp, unix) {
+        (Some(tcp), Some(unix)) => {
+            let unix_server = server.clone();
+            std::thread::spawn(move || unix_server.serve_unix(unix));
+            server.serve_tcp(tcp)?;
+        }
+        (Some(tcp), None) => server.serve_tcp(tcp)?,
+        (None, Some(unix)) => server.serve_unix(unix)?,
+        (None, None) => unreachable!("parse_args picks a default"),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            if !msg.is_empty() {
+                eprintln!("silly-db: {msg}");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("silly-db: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,150 @@
+use crate::core::kv_store::KvStore;
+use crate::error::KVError;
+use crate::model::table_row::Row;
+use crate::model::table_schema::Schema;
+use crate::model::update_modes::UpdateMode;
+
+// Looks the row up by its primary key cells and fills in the rest.
+// false if there is no such row.
+pub fn get_row<S: KvStore>(store: &S, schema: &Schema, row: &mut Row) -> Result<bool, KVError> {
+    let key = row.encode_key(schema)?;
+    match store.get(&key)? {
+        Some(val) => {
+            row.decode_val(schema, &val)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// true if a row with the same primary key was replaced
+pub fn set_row<S: KvStore>(store: &mut S, schema: &Schema, row: &Row, mode: UpdateMode) -> Result<bool, KVError> {
+    let key = row.encode_key(schema)?;
+    let val = row.encode_val(schema)?;
+
+    if mode != UpdateMode::Upsert {
+        match (mode, store.contains(&key)?) {
+            (UpdateMode::InsertOnly, true) => {
+                return Err(KVError::ConstraintViolation(format!("duplicate primary key in table {}", schema.table)));
+            }
+            (UpdateMode::UpdateOnly, false) => return Err(KVError::NotFound),
+            _ => {}
+        }
+    }
+    store.set(&key, &val)
+}
+
+pub fn insert_row<S: KvStore>(store: &mut S, schema: &Schema, row: &Row) -> Result<(), KVError> {
+    set_row(store, schema, row, UpdateMode::InsertOnly).map(drop)
+}
+
+pub fn update_row<S: KvStore>(store: &mut S, schema: &Schema, row: &Row) -> Result<(), KVError> {
+    set_row(store, schema, row, UpdateMode::UpdateOnly).map(drop)
+}
+
+// only the primary key cells are used; false if there was no such row
+pub fn delete_row<S: KvStore>(store: &mut S, schema: &Schema, row: &Row) -> Result<bool, KVError> {
+    let key = row.encode_key(schema)?;
+    store.del(&key)
+}
+
+// every row of the table, in primary key order
+pub fn scan_rows<'a, S: KvStore>(
+    store: &'a S,
+    schema: &'a Schema,
+) -> impl Iterator<Item = Result<Row, KVError>> + 'a {
+    // keys are "table\0" + cells
+    let start = [schema.table.as_bytes(), &[0x00]].concat();
+    let end = [schema.table.as_bytes(), &[0x01]].concat();
+    store.scan(start..end).map(move |pair| {
+        let (key, val) = pair?;
+        let mut row = schema.new_row();
+        row.decode_key(schema, &key)?;
+        row.decode_val(schema, &val)?;
+        Ok(row)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::key_value::KV;
+    use crate::core::mem_store::MemStore;
+    use crate::model::data_types::CellType;
+    use crate::model::table_schema::Column;
+
+    fn schema() -> Schema {
+        Schema {
+            table: "user".into(),
+            cols: vec![
+                Column { name: "id".into(), data_types: CellType::I64(0) },
+                Column { name: "name".into(), data_types: CellType::Str(vec![]) },
+            ],
+            pkey: vec![0],
+        }
+    }
+
+    fn user(id: i64, name: &str) -> Row {
+        Row { cells: vec![CellType::I64(id), CellType::Str(name.as_bytes().to_vec())] }
+    }
+
+    #[test]
+    fn crud_without_files() {
+        let mut store = MemStore::new();
+        let schema = schema();
+
+        insert_row(&mut store, &schema, &user(2, "bob")).unwrap();
+        insert_row(&mut store, &schema, &user(1, "ann")).unwrap();
+        // a key of another table sorts in between
+        store.set(b"user\x01", b"x").unwrap();
+        store.set(b"users\0", b"x").unwrap();
+
+        let mut row = user(2, "");
+        assert!(get_row(&store, &schema, &mut row).unwrap());
+        assert_eq!(row, user(2, "bob"));
+        assert!(!get_row(&store, &schema, &mut user(3, "")).unwrap());
+
+        let rows: Vec<Row> = scan_rows(&store, &schema).map(Result::unwrap).collect();
+        assert_eq!(rows, [user(1, "ann"), user(2, "bob")]);
+
+        assert!(delete_row(&mut store, &schema, &user(1, "")).unwrap());
+        assert!(!delete_row(&mut store, &schema, &user(1, "")).unwrap());
+    }
+
+    #[test]
+    fn update_modes() {
+        let mut store = MemStore::new();
+        let schema = schema();
+
+        assert!(matches!(update_row(&mut store, &schema, &user(1, "ann")), Err(KVError::NotFound)));
+        assert!(!set_row(&mut store, &schema, &user(1, "ann"), UpdateMode::Upsert).unwrap());
+        assert!(matches!(
+            insert_row(&mut store, &schema, &user(1, "bob")),
+            Err(KVError::ConstraintViolation(_))
+        ));
+        update_row(&mut store, &schema, &user(1, "bob")).unwrap();
+        assert!(set_row(&mut store, &schema, &user(1, "cat"), UpdateMode::Upsert).unwrap());
+
+        let mut row = user(1, "");
+        get_row(&store, &schema, &mut row).unwrap();
+        assert_eq!(row, user(1, "cat"));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn rows_in_a_txn_on_kv() {
+        let mut kv = KV::open_in_memory().unwrap();
+        let schema = schema();
+        insert_row(&mut kv, &schema, &user(1, "ann")).unwrap();
+
+        let mut txn = kv.begin();
+        delete_row(&mut txn, &schema, &user(1, "")).unwrap();
+        insert_row(&mut txn, &schema, &user(2, "bob")).unwrap();
+        let rows: Vec<Row> = scan_rows(&txn, &schema).map(Result::unwrap).collect();
+        assert_eq!(rows, [user(2, "bob")]);
+        txn.commit().unwrap();
+
+        let rows: Vec<Row> = scan_rows(&kv, &schema).map(Result::unwrap).collect();
+        assert_eq!(rows, [user(2, "bob")]);
+    }
+}
@@ -1,4 +1,5 @@
 use std::convert::TryInto;
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum CellType {
@@ -15,6 +16,17 @@ pub enum DecodeError {
     UnknownType(u8),
 }
 
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEOF => write!(f, "unexpected end of data"),
+            DecodeError::UnknownType(t) => write!(f, "unknown cell type {t}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 impl CellType {
     pub fn encode(&self, out: &mut Vec<u8>) {
         match self {
@@ -31,7 +43,7 @@ impl CellType {
     }
 
     pub fn decode(mut data: &[u8]) -> Result<(CellType, &[u8]), DecodeError> {
-        if data.len() < 1 {
+        if data.is_empty() {
             return Err(DecodeError::UnexpectedEOF);
         }
 
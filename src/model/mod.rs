@@ -1,5 +1,6 @@
 pub mod data_types;
 pub mod table_schema;
-mod table_row;
+pub mod table_row;
 pub mod update_modes;
 pub mod crud_apis;
+pub mod table_changes;
@@ -0,0 +1,125 @@
+use crate::core::cdc::{Change, ChangeOp, Subscription};
+use crate::error::KVError;
+use crate::model::table_row::Row;
+use crate::model::table_schema::Schema;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowOp {
+    Set(Row),
+    // only the primary key cells are filled in
+    Del(Row),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowChange {
+    pub lsn: u64,
+    pub ts: u64,
+    pub op: RowOp,
+}
+
+// None if the key is not from this table
+pub fn decode_change(schema: &Schema, change: &Change) -> Result<Option<RowChange>, KVError> {
+    let prefix = schema.table.as_bytes();
+    if !change.key.starts_with(prefix) || change.key.get(prefix.len()) != Some(&0x00) {
+        return Ok(None);
+    }
+
+    let mut row = schema.new_row();
+    row.decode_key(schema, &change.key)?;
+    let op = match &change.op {
+        ChangeOp::Set(val) => {
+            row.decode_val(schema, val)?;
+            RowOp::Set(row)
+        }
+        ChangeOp::Del => RowOp::Del(row),
+    };
+    Ok(Some(RowChange { lsn: change.lsn, ts: change.ts, op }))
+}
+
+// changes of one table, other keys are skipped
+pub struct TableChanges {
+    sub: Subscription,
+    schema: Schema,
+}
+
+impl TableChanges {
+    pub fn new(sub: Subscription, schema: Schema) -> Self {
+        TableChanges { sub, schema }
+    }
+
+    // of any key, not only this table's: store it to resume
+    pub fn last_lsn(&self) -> u64 {
+        self.sub.last_lsn()
+    }
+
+    pub fn next_change(&mut self) -> Result<Option<RowChange>, KVError> {
+        while let Some(change) = self.sub.next_change()? {
+            if let Some(row_change) = decode_change(&self.schema, &change)? {
+                return Ok(Some(row_change));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for TableChanges {
+    type Item = Result<RowChange, KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_change().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::key_value::KV;
+    use crate::model::data_types::CellType;
+    use crate::model::table_schema::Column;
+
+    fn schema() -> Schema {
+        Schema {
+            table: "user".into(),
+            cols: vec![
+                Column { name: "id".into(), data_types: CellType::I64(0) },
+                Column { name: "name".into(), data_types: CellType::Str(vec![]) },
+            ],
+            pkey: vec![0],
+        }
+    }
+
+    #[test]
+    fn rows_of_one_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kv = KV::open(dir.path().join("db.log")).unwrap();
+        let schema = schema();
+
+        let row = Row { cells: vec![CellType::I64(1), CellType::Str(b"ann".to_vec())] };
+        let key = row.encode_key(&schema).unwrap();
+        kv.set(&key, &row.encode_val(&schema).unwrap()).unwrap();
+        kv.set(b"users\0other table", b"x").unwrap();
+        kv.del(&key).unwrap();
+
+        let mut changes = TableChanges::new(kv.subscribe(0).unwrap(), schema.clone());
+        assert_eq!(changes.next_change().unwrap().unwrap().op, RowOp::Set(row));
+
+        let del = changes.next_change().unwrap().unwrap();
+        let mut pkey_only = schema.new_row();
+        pkey_only.cells[0] = CellType::I64(1);
+        assert_eq!((del.lsn, del.op), (3, RowOp::Del(pkey_only)));
+
+        assert!(changes.next_change().unwrap().is_none());
+        assert_eq!(changes.last_lsn(), 3);
+    }
+
+    #[test]
+    fn bad_row_is_an_error() {
+        let change = Change {
+            lsn: 1,
+            ts: 0,
+            key: b"user\0\x07".to_vec(),
+            op: ChangeOp::Del,
+        };
+        assert!(decode_change(&schema(), &change).is_err());
+    }
+}
@@ -1,14 +1,34 @@
+use crate::error::KVError;
 use crate::model::data_types::{CellType, DecodeError};
-use crate::model::table_schema::Schema;
+use crate::model::table_schema::{Column, Schema};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Row {
     pub cells: Vec<CellType>,
 }
 
+fn check_len(row: &Row, schema: &Schema) -> Result<(), KVError> {
+    if row.cells.len() != schema.cols.len() {
+        return Err(KVError::SchemaMismatch(format!(
+            "table {} has {} columns, row has {}",
+            schema.table,
+            schema.cols.len(),
+            row.cells.len()
+        )));
+    }
+    Ok(())
+}
+
+fn check_type(col: &Column, cell: &CellType) -> Result<(), KVError> {
+    if !col.data_types.same_type(cell) {
+        return Err(KVError::SchemaMismatch(format!("column {} type mismatch", col.name)));
+    }
+    Ok(())
+}
+
 impl Row {
-    pub fn encode_key(&self, schema: &Schema) -> Vec<u8> {
-        assert_eq!(self.cells.len(), schema.cols.len());
+    pub fn encode_key(&self, schema: &Schema) -> Result<Vec<u8>, KVError> {
+        check_len(self, schema)?;
 
         let mut key = Vec::new();
         key.extend_from_slice(schema.table.as_bytes());
@@ -18,28 +38,36 @@ impl Row {
             let col = &schema.cols[idx];
             let cell = &self.cells[idx];
 
-            assert!(
-                col.data_types.same_type(cell),
-                "column {} type mismatch",
-                col.name
-            );
+            check_type(col, cell)?;
 
             cell.encode(&mut key);
         }
 
-        key
+        Ok(key)
     }
 
     pub fn decode_key(
         &mut self,
         schema: &Schema,
         mut key: &[u8],
-    ) -> Result<(), DecodeError> {
+    ) -> Result<(), KVError> {
+        check_len(self, schema)?;
+
         let prefix_len = schema.table.len() + 1;
+        if key.len() < prefix_len {
+            return Err(DecodeError::UnexpectedEOF.into());
+        }
+        if &key[..prefix_len - 1] != schema.table.as_bytes() || key[prefix_len - 1] != 0x00 {
+            return Err(KVError::SchemaMismatch(format!(
+                "key is not from table {}",
+                schema.table
+            )));
+        }
         key = &key[prefix_len..];
 
         for &idx in &schema.pkey {
             let (cell, rest) = CellType::decode(key)?;
+            check_type(&schema.cols[idx], &cell)?;
             self.cells[idx] = cell;
             key = rest;
         }
@@ -47,8 +75,8 @@ impl Row {
         Ok(())
     }
 
-    pub fn encode_val(&self, schema: &Schema) -> Vec<u8> {
-        assert_eq!(self.cells.len(), schema.cols.len());
+    pub fn encode_val(&self, schema: &Schema) -> Result<Vec<u8>, KVError> {
+        check_len(self, schema)?;
 
         let mut val = Vec::new();
 
@@ -59,23 +87,21 @@ impl Row {
 
             let cell = &self.cells[idx];
 
-            assert!(
-                col.data_types.same_type(cell),
-                "column {} type mismatch",
-                col.name
-            );
+            check_type(col, cell)?;
 
             cell.encode(&mut val);
         }
 
-        val
+        Ok(val)
     }
 
     pub fn decode_val(
         &mut self,
         schema: &Schema,
         mut val: &[u8],
-    ) -> Result<(), DecodeError> {
+    ) -> Result<(), KVError> {
+        check_len(self, schema)?;
+
         for (idx, col) in schema.cols.iter().enumerate() {
             if schema.pkey.contains(&idx) {
                 continue;
@@ -83,11 +109,7 @@ impl Row {
 
             let (cell, rest) = CellType::decode(val)?;
 
-            assert!(
-                col.data_types.same_type(&cell),
-                "column {} type mismatch",
-                col.name
-            );
+            check_type(col, &cell)?;
 
             self.cells[idx] = cell;
             val = rest;
@@ -126,8 +148,8 @@ mod tests {
             ],
         };
 
-        let key = row.encode_key(&schema);
-        let val = row.encode_val(&schema);
+        let key = row.encode_key(&schema).unwrap();
+        let val = row.encode_val(&schema).unwrap();
 
         let mut decoded = schema.new_row();
         decoded.decode_key(&schema, &key).unwrap();
@@ -135,4 +157,48 @@ mod tests {
 
         assert_eq!(row, decoded);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn type_mismatch_is_an_error() {
+        let schema = schema();
+
+        let row = Row {
+            cells: vec![
+                CellType::Str(b"not a number".to_vec()),
+                CellType::Str(b"a".to_vec()),
+                CellType::I64(1),
+            ],
+        };
+
+        assert!(matches!(row.encode_key(&schema), Err(KVError::SchemaMismatch(_))));
+        assert!(matches!(row.encode_val(&schema), Err(KVError::SchemaMismatch(_))));
+    }
+
+    #[test]
+    fn wrong_cell_count_is_an_error() {
+        let schema = schema();
+        let row = Row { cells: vec![CellType::I64(1)] };
+
+        assert!(matches!(row.encode_key(&schema), Err(KVError::SchemaMismatch(_))));
+    }
+
+    #[test]
+    fn decode_bad_key() {
+        let schema = schema();
+        let mut row = schema.new_row();
+
+        assert!(matches!(
+            row.decode_key(&schema, b"li"),
+            Err(KVError::Decode(DecodeError::UnexpectedEOF))
+        ));
+        assert!(matches!(
+            row.decode_key(&schema, b"other\0"),
+            Err(KVError::SchemaMismatch(_))
+        ));
+        // table prefix, then a truncated cell
+        assert!(matches!(
+            row.decode_key(&schema, b"link\0\x02\x05"),
+            Err(KVError::Decode(DecodeError::UnexpectedEOF))
+        ));
+    }
+}
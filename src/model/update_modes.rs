@@ -0,0 +1,10 @@
+// what set_row does depending on whether the row is already there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateMode {
+    // insert or replace
+    Upsert,
+    // replace only, NotFound if missing
+    UpdateOnly,
+    // insert only, ConstraintViolation if present
+    InsertOnly,
+}
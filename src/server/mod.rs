@@ -0,0 +1,380 @@
+//! Network server: one KV shared by many clients over TCP or a Unix socket
+pub mod resp;
+
+use crate::core::key_value::KV;
+use crate::core::kv_store::KvStore;
+use crate::error::KVError;
+use resp::Value;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// SCAN without a count
+pub const DEFAULT_SCAN_COUNT: usize = 100;
+
+// Commands (RESP arrays or inline), names in any case:
+//   PING [msg]
+//   GET key                    -> bulk, nil if missing
+//   SET key value              -> 1 if the key existed, else 0
+//   DEL key [key ...]          -> number of keys deleted
+//   SCAN start end [count]     -> [key, value, key, value, ...] in key order,
+//                                 start <= key < end, "" end = no end;
+//                                 the next page starts after the last key
+//   MULTI                      -> then SET and DEL reply QUEUED
+//   EXEC                       -> their replies, applied as one batch
+//   DISCARD
+//   QUIT
+// Each command, and each EXEC, runs under one lock of the KV. A KVError
+// comes back as an error reply with a code, see resp::encode_error.
+#[derive(Clone)]
+pub struct Server {
+    kv: Arc<Mutex<KV>>,
+}
+
+// per connection: commands queued since MULTI
+#[derive(Default)]
+pub struct Session {
+    queued: Option<Vec<Vec<Vec<u8>>>>,
+    // a bad command was queued: EXEC runs nothing
+    aborted: bool,
+}
+
+impl Session {
+    // an error inside MULTI also fails the EXEC
+    fn abort(&mut self, msg: String) -> Value {
+        if self.queued.is_some() {
+            self.aborted = true;
+        }
+        Value::Error(msg)
+    }
+}
+
+impl Server {
+    pub fn new(kv: KV) -> Self {
+        Server { kv: Arc::new(Mutex::new(kv)) }
+    }
+
+    // a thread per connection
+    pub fn serve_tcp(&self, listener: TcpListener) -> Result<(), KVError> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            stream.set_nodelay(true)?;
+            let server = self.clone();
+            std::thread::spawn(move || server.handle(stream));
+        }
+        Ok(())
+    }
+
+    pub fn serve_unix(&self, listener: UnixListener) -> Result<(), KVError> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            std::thread::spawn(move || server.handle(stream));
+        }
+        Ok(())
+    }
+
+    // One connection until the client hangs up or sends QUIT. Replies are
+    // flushed once no more pipelined commands are buffered.
+    pub fn handle<S>(&self, stream: S) -> Result<(), KVError>
+    where
+        for<'a> &'a S: Read + Write,
+    {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let mut session = Session::default();
+        loop {
+            let args = match resp::read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(e @ KVError::Protocol(_)) => {
+                    // the stream is out of step: say why and hang up
+                    Value::Error(resp::encode_error(&e)).write_to(&mut writer)?;
+                    writer.flush()?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+
+            let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+            let reply = match quit {
+                true => Value::Simple("OK".into()),
+                false => self.execute_in(&mut session, &args),
+            };
+            reply.write_to(&mut writer)?;
+            if quit || reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn execute(&self, args: &[Vec<u8>]) -> Value {
+        self.execute_in(&mut Session::default(), args)
+    }
+
+    // a command on a connection, MULTI/EXEC included
+    pub fn execute_in(&self, session: &mut Session, args: &[Vec<u8>]) -> Value {
+        let Some(first) = args.first() else {
+            return session.abort("ERR empty command".into());
+        };
+        let name = String::from_utf8_lossy(first).to_ascii_uppercase();
+        let argc = args.len() - 1;
+        let arity_ok = match name.as_str() {
+            "PING" => argc <= 1,
+            "GET" => argc == 1,
+            "SET" => argc == 2,
+            "DEL" => argc >= 1,
+            "SCAN" => argc == 2 || argc == 3,
+            "MULTI" | "EXEC" | "DISCARD" => argc == 0,
+            _ => return session.abort(format!("ERR unknown command '{name}'")),
+        };
+        if !arity_ok {
+            return session.abort(format!("ERR wrong number of arguments for '{name}'"));
+        }
+
+        let result = match (name.as_str(), &mut session.queued) {
+            ("MULTI", Some(_)) => return Value::Error("ERR MULTI calls can not be nested".into()),
+            ("MULTI", None) => {
+                session.queued = Some(Vec::new());
+                return Value::Simple("OK".into());
+            }
+            ("EXEC" | "DISCARD", None) => return Value::Error(format!("ERR {name} without MULTI")),
+            ("DISCARD", Some(_)) => {
+                *session = Session::default();
+                return Value::Simple("OK".into());
+            }
+            ("EXEC", Some(queued)) => {
+                let queued = std::mem::take(queued);
+                let aborted = session.aborted;
+                *session = Session::default();
+                if aborted {
+                    return Value::Error("EXECABORT transaction discarded because of previous errors".into());
+                }
+                self.exec(&queued)
+            }
+            ("SET" | "DEL", Some(queued)) => {
+                queued.push(args.to_vec());
+                return Value::Simple("QUEUED".into());
+            }
+            (_, Some(_)) => return session.abort(format!("ERR {name} can not be queued")),
+            (_, None) => self.run(&name, &args[1..]),
+        };
+        match result {
+            Ok(reply) => reply,
+            Err(e) => Value::Error(resp::encode_error(&e)),
+        }
+    }
+
+    // Queued SETs and DELs through a Txn: the replies are what each
+    // command would have said, then all of it is one batch record.
+    fn exec(&self, queued: &[Vec<Vec<u8>>]) -> Result<Value, KVError> {
+        let mut kv = self.lock();
+        let mut txn = kv.begin();
+        let mut replies = Vec::with_capacity(queued.len());
+        for args in queued {
+            let n = match args[0].eq_ignore_ascii_case(b"SET") {
+                true => txn.set(&args[1], &args[2])? as i64,
+                false => {
+                    let mut deleted = 0;
+                    for key in &args[1..] {
+                        deleted += txn.del(key)? as i64;
+                    }
+                    deleted
+                }
+            };
+            replies.push(Value::Int(n));
+        }
+        txn.commit()?;
+        Ok(Value::Array(replies))
+    }
+
+    fn run(&self, name: &str, args: &[Vec<u8>]) -> Result<Value, KVError> {
+        match name {
+            "PING" => Ok(match args.first() {
+                Some(msg) => Value::Bulk(msg.clone()),
+                None => Value::Simple("PONG".into()),
+            }),
+            "GET" => Ok(self.lock().get(&args[0])?.map_or(Value::Nil, Value::Bulk)),
+            "SET" => Ok(Value::Int(self.lock().set(&args[0], &args[1])? as i64)),
+            "DEL" => {
+                let mut kv = self.lock();
+                let mut deleted = 0;
+                for key in args {
+                    deleted += kv.del(key)? as i64;
+                }
+                Ok(Value::Int(deleted))
+            }
+            "SCAN" => {
+                let count = match args.get(2) {
+                    None => DEFAULT_SCAN_COUNT,
+                    Some(n) => std::str::from_utf8(n)
+                        .ok()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| KVError::Protocol("count is not a number".into()))?,
+                };
+                let end = match args[1].is_empty() {
+                    true => Bound::Unbounded,
+                    false => Bound::Excluded(args[1].clone()),
+                };
+
+                let kv = self.lock();
+                let mut items = Vec::new();
+                for pair in kv.scan((Bound::Included(args[0].clone()), end)).take(count) {
+                    let (key, val) = pair?;
+                    items.push(Value::Bulk(key));
+                    items.push(Value::Bulk(val));
+                }
+                Ok(Value::Array(items))
+            }
+            _ => unreachable!("checked in execute_in"),
+        }
+    }
+
+    // a panic in another connection does not leave the KV half-written:
+    // every write is one log record
+    fn lock(&self) -> MutexGuard<'_, KV> {
+        self.kv.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+
+    fn start() -> std::net::SocketAddr {
+        let server = Server::new(KV::open_in_memory().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.serve_tcp(listener));
+        addr
+    }
+
+    fn call(stream: &TcpStream, args: &[&[u8]]) -> Value {
+        resp::write_command(&mut &*stream, args).unwrap();
+        resp::read_value(&mut BufReader::new(stream)).unwrap().unwrap()
+    }
+
+    fn bulk(data: &[u8]) -> Value {
+        Value::Bulk(data.to_vec())
+    }
+
+    #[test]
+    fn get_set_del_scan() {
+        let stream = TcpStream::connect(start()).unwrap();
+
+        assert_eq!(call(&stream, &[b"PING"]), Value::Simple("PONG".into()));
+        assert_eq!(call(&stream, &[b"set", b"a", b"1"]), Value::Int(0));
+        assert_eq!(call(&stream, &[b"SET", b"a", b"1"]), Value::Int(1));
+        call(&stream, &[b"SET", b"b", b"2"]);
+        call(&stream, &[b"SET", b"c", b"3"]);
+        assert_eq!(call(&stream, &[b"GET", b"a"]), bulk(b"1"));
+        assert_eq!(call(&stream, &[b"DEL", b"a", b"nope"]), Value::Int(1));
+        assert_eq!(call(&stream, &[b"GET", b"a"]), Value::Nil);
+
+        assert_eq!(
+            call(&stream, &[b"SCAN", b"", b""]),
+            Value::Array(vec![bulk(b"b"), bulk(b"2"), bulk(b"c"), bulk(b"3")])
+        );
+        assert_eq!(call(&stream, &[b"SCAN", b"b", b"c"]), Value::Array(vec![bulk(b"b"), bulk(b"2")]));
+        assert_eq!(call(&stream, &[b"SCAN", b"b", b"", b"1"]), Value::Array(vec![bulk(b"b"), bulk(b"2")]));
+        assert_eq!(call(&stream, &[b"SCAN", b"c", b"b"]), Value::Array(vec![]));
+    }
+
+    #[test]
+    fn errors_keep_the_connection() {
+        let stream = TcpStream::connect(start()).unwrap();
+
+        assert!(matches!(call(&stream, &[b"FLY"]), Value::Error(e) if e.contains("unknown command")));
+        assert!(matches!(call(&stream, &[b"GET"]), Value::Error(e) if e.contains("wrong number")));
+        let big_key = vec![b'k'; 100 << 10];
+        let reply = call(&stream, &[b"SET", &big_key, b"v"]);
+        assert!(matches!(reply, Value::Error(e) if e.starts_with("KEYTOOLARGE ")));
+        assert_eq!(call(&stream, &[b"PING", b"hi"]), bulk(b"hi"));
+
+        // an empty array is skipped, not a command
+        (&stream).write_all(b"*0\r\n").unwrap();
+        assert_eq!(call(&stream, &[b"PING"]), Value::Simple("PONG".into()));
+        assert!(matches!(Server::new(KV::open_in_memory().unwrap()).execute(&[]), Value::Error(_)));
+    }
+
+    #[test]
+    fn multi_exec_is_one_batch() {
+        let stream = TcpStream::connect(start()).unwrap();
+        call(&stream, &[b"SET", b"a", b"1"]);
+
+        assert_eq!(call(&stream, &[b"MULTI"]), Value::Simple("OK".into()));
+        assert_eq!(call(&stream, &[b"SET", b"a", b"2"]), Value::Simple("QUEUED".into()));
+        call(&stream, &[b"SET", b"b", b"3"]);
+        call(&stream, &[b"DEL", b"b", b"c"]);
+        assert_eq!(call(&stream, &[b"EXEC"]), Value::Array(vec![Value::Int(1), Value::Int(0), Value::Int(1)]));
+        assert_eq!(call(&stream, &[b"GET", b"a"]), bulk(b"2"));
+        assert_eq!(call(&stream, &[b"GET", b"b"]), Value::Nil);
+
+        // a too large value fails all of it
+        call(&stream, &[b"MULTI"]);
+        call(&stream, &[b"SET", b"a", b"3"]);
+        call(&stream, &[b"SET", &vec![b'k'; 100 << 10], b"v"]);
+        assert!(matches!(call(&stream, &[b"EXEC"]), Value::Error(e) if e.starts_with("KEYTOOLARGE ")));
+        assert_eq!(call(&stream, &[b"GET", b"a"]), bulk(b"2"));
+
+        // so does a bad command
+        call(&stream, &[b"MULTI"]);
+        call(&stream, &[b"SET", b"a", b"4"]);
+        assert!(matches!(call(&stream, &[b"GET", b"a"]), Value::Error(_)));
+        assert!(matches!(call(&stream, &[b"EXEC"]), Value::Error(e) if e.starts_with("EXECABORT")));
+
+        call(&stream, &[b"MULTI"]);
+        call(&stream, &[b"DEL", b"a"]);
+        assert_eq!(call(&stream, &[b"DISCARD"]), Value::Simple("OK".into()));
+        assert!(matches!(call(&stream, &[b"EXEC"]), Value::Error(_)));
+        assert_eq!(call(&stream, &[b"GET", b"a"]), bulk(b"2"));
+    }
+
+    #[test]
+    fn pipelined_and_inline_commands() {
+        let stream = TcpStream::connect(start()).unwrap();
+
+        (&stream).write_all(b"SET k v\r\nGET k\r\nQUIT\r\n").unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut replies = Vec::new();
+        while let Some(value) = resp::read_value(&mut reader).unwrap() {
+            replies.push(value);
+        }
+        assert_eq!(replies, [Value::Int(0), bulk(b"v"), Value::Simple("OK".into())]);
+    }
+
+    #[test]
+    fn protocol_error_closes_the_connection() {
+        let stream = TcpStream::connect(start()).unwrap();
+
+        (&stream).write_all(b"*1\r\n:5\r\n").unwrap();
+        let mut reader = BufReader::new(&stream);
+        assert!(matches!(resp::read_value(&mut reader).unwrap(), Some(Value::Error(_))));
+        assert!(reader.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unix_socket_shares_the_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::new(KV::open_in_memory().unwrap());
+        let sock = dir.path().join("db.sock");
+        let listener = UnixListener::bind(&sock).unwrap();
+        let unix = server.clone();
+        std::thread::spawn(move || unix.serve_unix(listener));
+
+        server.execute(&[b"SET".to_vec(), b"k".to_vec(), b"shared".to_vec()]);
+
+        let stream = UnixStream::connect(&sock).unwrap();
+        resp::write_command(&mut &stream, &[b"GET", b"k"]).unwrap();
+        let reply = resp::read_value(&mut BufReader::new(&stream)).unwrap().unwrap();
+        assert_eq!(reply, bulk(b"shared"));
+    }
+}
@@ -0,0 +1,316 @@
+//! RESP2, the Redis wire protocol: redis-cli and Redis clients can talk to silly-db
+use crate::error::KVError;
+use std::io::{self, BufRead, Read, Write};
+
+// longest bulk string accepted, as in Redis
+pub const MAX_BULK: usize = 512 << 20;
+// arguments in one command
+pub const MAX_ARGS: usize = 1 << 20;
+// +simple, -error, :int and length lines
+const MAX_LINE: u64 = 64 << 10;
+// arrays in arrays: replies here go one level deep
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    // $-1: a missing key
+    Nil,
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        match self {
+            Value::Simple(s) => write!(w, "+{s}\r\n"),
+            Value::Error(s) => write!(w, "-{s}\r\n"),
+            Value::Int(n) => write!(w, ":{n}\r\n"),
+            Value::Bulk(data) => {
+                write!(w, "${}\r\n", data.len())?;
+                w.write_all(data)?;
+                w.write_all(b"\r\n")
+            }
+            Value::Nil => w.write_all(b"$-1\r\n"),
+            Value::Array(items) => {
+                write!(w, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(w))
+            }
+        }
+    }
+}
+
+fn protocol(msg: impl Into<String>) -> KVError {
+    KVError::Protocol(msg.into())
+}
+
+// without the \r\n; None at a clean end of stream
+fn read_line(r: &mut impl BufRead) -> Result<Option<Vec<u8>>, KVError> {
+    let mut line = Vec::new();
+    r.take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(protocol("line without \\r\\n or too long"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(line))
+}
+
+fn parse_int(line: &[u8]) -> Result<i64, KVError> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol("bad integer"))
+}
+
+fn parse_len(line: &[u8], max: usize) -> Result<Option<usize>, KVError> {
+    match parse_int(line)? {
+        -1 => Ok(None),
+        n if n < 0 || n as u64 > max as u64 => Err(protocol(format!("bad length {n}"))),
+        n => Ok(Some(n as usize)),
+    }
+}
+
+// None at a clean end of stream
+pub fn read_value(r: &mut impl BufRead) -> Result<Option<Value>, KVError> {
+    read_nested(r, 0)
+}
+
+fn read_nested(r: &mut impl BufRead, depth: usize) -> Result<Option<Value>, KVError> {
+    if depth > MAX_DEPTH {
+        return Err(protocol("arrays nested too deep"));
+    }
+    let Some(line) = read_line(r)? else { return Ok(None) };
+    let (&tag, rest) = line.split_first().ok_or_else(|| protocol("empty line"))?;
+    let text = || String::from_utf8_lossy(rest).into_owned();
+
+    let value = match tag {
+        b'+' => Value::Simple(text()),
+        b'-' => Value::Error(text()),
+        b':' => Value::Int(parse_int(rest)?),
+        b'$' => match parse_len(rest, MAX_BULK)? {
+            None => Value::Nil,
+            Some(len) => Value::Bulk(read_bulk(r, len)?),
+        },
+        b'*' => match parse_len(rest, MAX_ARGS)? {
+            None => Value::Nil,
+            Some(count) => {
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    let item = read_nested(r, depth + 1)?;
+                    items.push(item.ok_or_else(|| protocol("array cut short"))?);
+                }
+                Value::Array(items)
+            }
+        },
+        other => return Err(protocol(format!("unknown type byte {other:#04x}"))),
+    };
+    Ok(Some(value))
+}
+
+// the length comes from the peer: memory grows as the bytes arrive
+fn read_bulk(r: &mut impl BufRead, len: usize) -> Result<Vec<u8>, KVError> {
+    let mut data = Vec::new();
+    r.take(len as u64 + 2).read_to_end(&mut data)?;
+    if data.len() != len + 2 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if !data.ends_with(b"\r\n") {
+        return Err(protocol("bulk string without \\r\\n"));
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+// A command is an array of bulk strings. A line of words also works
+// ("inline" commands, for telnet and nc). None at a clean end of stream.
+pub fn read_command(r: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>, KVError> {
+    loop {
+        let first = match r.fill_buf()?.first() {
+            None => return Ok(None),
+            Some(&b) => b,
+        };
+
+        if first != b'*' {
+            let Some(line) = read_line(r)? else { return Ok(None) };
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+
+        let Some(Value::Array(items)) = read_value(r)? else {
+            return Err(protocol("command is not an array"));
+        };
+        let args: Vec<Vec<u8>> = items
+            .into_iter()
+            .map(|item| match item {
+                Value::Bulk(arg) => Ok(arg),
+                _ => Err(protocol("command argument is not a bulk string")),
+            })
+            .collect::<Result<_, _>>()?;
+        // "*0", like an empty line
+        if args.is_empty() {
+            continue;
+        }
+        return Ok(Some(args));
+    }
+}
+
+// Error replies start with a code word, as Redis does ("-READONLY ..."),
+// so a client gets the KVError back. Errors without their own code
+// (unknown command, bad arguments) are "ERR ...", Protocol on the client.
+pub fn encode_error(e: &KVError) -> String {
+    match e {
+        KVError::Io(e) => format!("IO {e}"),
+        KVError::Corruption(msg) => format!("CORRUPTION {msg}"),
+        KVError::Tampered(msg) => format!("TAMPERED {msg}"),
+        KVError::KeyMismatch(msg) => format!("KEYMISMATCH {msg}"),
+        KVError::Unsupported(msg) => format!("UNSUPPORTED {msg}"),
+        KVError::KeyTooLarge { len, max } => format!("KEYTOOLARGE {len} {max}"),
+        KVError::ValueTooLarge { len, max } => format!("VALUETOOLARGE {len} {max}"),
+        KVError::SchemaMismatch(msg) => format!("SCHEMA {msg}"),
+        KVError::Conflict(msg) => format!("CONFLICT {msg}"),
+        KVError::NotFound => "NOTFOUND".into(),
+        KVError::ConstraintViolation(msg) => format!("CONSTRAINT {msg}"),
+        KVError::ReadOnly => "READONLY".into(),
+        KVError::Poisoned(msg) => format!("POISONED {msg}"),
+        KVError::Locked(msg) => format!("LOCKED {msg}"),
+        KVError::Replication(msg) => format!("REPLICATION {msg}"),
+        e @ (KVError::Decode(_) | KVError::Protocol(_)) => format!("ERR {e}"),
+    }
+}
+
+pub fn decode_error(reply: &str) -> KVError {
+    let (code, msg) = reply.split_once(' ').unwrap_or((reply, ""));
+    let msg = msg.to_string();
+    let sizes = || {
+        let (len, max) = msg.split_once(' ')?;
+        Some((len.parse().ok()?, max.parse().ok()?))
+    };
+    match code {
+        "IO" => KVError::Io(io::Error::other(msg)),
+        "CORRUPTION" => KVError::Corruption(msg),
+        "TAMPERED" => KVError::Tampered(msg),
+        "KEYMISMATCH" => KVError::KeyMismatch(msg),
+        "UNSUPPORTED" => KVError::Unsupported(msg),
+        "KEYTOOLARGE" | "VALUETOOLARGE" => match (code, sizes()) {
+            ("KEYTOOLARGE", Some((len, max))) => KVError::KeyTooLarge { len, max },
+            (_, Some((len, max))) => KVError::ValueTooLarge { len, max },
+            _ => KVError::Protocol(reply.to_string()),
+        },
+        "SCHEMA" => KVError::SchemaMismatch(msg),
+        "CONFLICT" => KVError::Conflict(msg),
+        "NOTFOUND" => KVError::NotFound,
+        "CONSTRAINT" => KVError::ConstraintViolation(msg),
+        "READONLY" => KVError::ReadOnly,
+        "POISONED" => KVError::Poisoned(msg),
+        "LOCKED" => KVError::Locked(msg),
+        "REPLICATION" => KVError::Replication(msg),
+        _ => KVError::Protocol(reply.to_string()),
+    }
+}
+
+pub fn write_command(w: &mut impl Write, args: &[&[u8]]) -> io::Result<()> {
+    write!(w, "*{}\r\n", args.len())?;
+    for arg in args {
+        write!(w, "${}\r\n", arg.len())?;
+        w.write_all(arg)?;
+        w.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        let value = Value::Array(vec![
+            Value::Simple("OK".into()),
+            Value::Error("ERR bad".into()),
+            Value::Int(-42),
+            Value::Bulk(b"bin\r\nary".to_vec()),
+            Value::Nil,
+            Value::Array(vec![]),
+        ]);
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+
+        let mut r = buf.as_slice();
+        assert_eq!(read_value(&mut r).unwrap(), Some(value));
+        assert_eq!(read_value(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn commands_array_and_inline() {
+        let mut buf = Vec::new();
+        write_command(&mut buf, &[b"SET", b"k", b"two words"]).unwrap();
+        buf.extend_from_slice(b"\r\nGET  k\r\n");
+
+        let mut r = buf.as_slice();
+        let args = read_command(&mut r).unwrap().unwrap();
+        assert_eq!(args, [&b"SET"[..], b"k", b"two words"]);
+        assert_eq!(read_command(&mut r).unwrap().unwrap(), [&b"GET"[..], b"k"]);
+        assert_eq!(read_command(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn empty_commands_are_skipped() {
+        let mut r = &b"*0\r\n\r\n*0\r\nPING\r\n*0\r\n"[..];
+        assert_eq!(read_command(&mut r).unwrap().unwrap(), [&b"PING"[..]]);
+        assert_eq!(read_command(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn bad_input_is_a_protocol_error() {
+        for input in [
+            &b"*1\r\n:1\r\n"[..],
+            b"*x\r\n",
+            b"*1\r\n$3\r\nabcd\r\n",
+            b"*1\r\n$-5\r\n",
+        ] {
+            let err = read_command(&mut &input[..]).unwrap_err();
+            assert!(matches!(err, KVError::Protocol(_)), "{input:?}: {err}");
+        }
+
+        assert!(matches!(read_value(&mut &b"?\r\n"[..]), Err(KVError::Protocol(_))));
+        let err = read_value(&mut &b"$10\r\nshort\r\n"[..]).unwrap_err();
+        assert!(err.is_eof());
+
+        let too_long = format!("${}\r\n", MAX_BULK + 1);
+        assert!(read_value(&mut too_long.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn errors_keep_their_kind() {
+        let errors = [
+            KVError::KeyTooLarge { len: 10, max: 4 },
+            KVError::ValueTooLarge { len: 7, max: 6 },
+            KVError::ReadOnly,
+            KVError::Poisoned("fsync: EIO".into()),
+            KVError::NotFound,
+            KVError::Corruption("bad checksum".into()),
+            KVError::Conflict("key a".into()),
+        ];
+        for e in errors {
+            let back = decode_error(&encode_error(&e));
+            assert_eq!(back.to_string(), e.to_string());
+        }
+
+        let io = decode_error(&encode_error(&KVError::Io(io::Error::other("disk full"))));
+        assert!(matches!(io, KVError::Io(e) if e.to_string() == "disk full"));
+        assert!(matches!(decode_error("ERR unknown command 'FLY'"), KVError::Protocol(_)));
+        assert!(matches!(decode_error("KEYTOOLARGE many"), KVError::Protocol(_)));
+    }
+}